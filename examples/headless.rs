@@ -0,0 +1,48 @@
+// Runs a test rom with the core alone: no sdl, no files, the rom is built in.
+// It has to build without the default features too:
+//
+//     cargo run --example headless --no-default-features
+
+extern crate gameman;
+
+use gameman::cartridge::load_rom_bytes;
+use gameman::cpu::CPU;
+use gameman::gpu::GPU;
+use gameman::mem::MMU;
+
+use std::process;
+
+const ROM: &[u8] = include_bytes!("../tests/cpu_instrs/01-special.gb");
+const MAX_FRAMES: u32 = 60 * 60;
+const CLOCKS_IN_A_FRAME: u32 = 70224;
+
+fn main() {
+    let cartridge = load_rom_bytes(ROM.to_vec()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let mut cpu = CPU::new(MMU::new(GPU::new(), cartridge));
+    cpu.reset();
+
+    for _ in 0..MAX_FRAMES {
+        // the memory runs the gpu and the sound along with the cpu
+        let mut frame_clocks = 0;
+        while frame_clocks < CLOCKS_IN_A_FRAME {
+            let (_, t) = cpu.step();
+            frame_clocks += t as u32;
+        }
+
+        let output = cpu.mmu.link.get_output();
+        if output.contains("Passed") {
+            println!("{}", output);
+            return;
+        }
+        if output.contains("Failed") {
+            println!("{}", output);
+            process::exit(1);
+        }
+    }
+
+    eprintln!("no result after {} frames", MAX_FRAMES);
+    process::exit(1);
+}
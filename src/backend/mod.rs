@@ -0,0 +1,54 @@
+use keypad::Button;
+use sound::{AudioOutType, AUDIO_BUFFER_SIZE, SAMPLE_RATE};
+
+#[cfg(feature = "sdl")]
+mod sdl;
+
+#[cfg(feature = "sdl")]
+pub use self::sdl::{controller_button_to_gb, KeyBindings, SdlBackend};
+
+// what the user asked the emulator to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Quit,
+    TogglePause,
+    ToggleTurbo,
+    SpeedUp,  // doubles the speed
+    SlowDown, // halves the speed
+    Rewind,
+    StepFrame, // runs a single frame, even while paused
+    Press(Button),
+    Release(Button),
+}
+
+// draws the screen
+pub trait Renderer {
+    // rgb is the screen, 3 bytes per pixel, row after row
+    fn present(&mut self, rgb: &[u8]);
+}
+
+// collects what the user did since the last poll
+pub trait Input {
+    fn poll_events(&mut self) -> Vec<InputEvent>;
+}
+
+// plays the sound
+pub trait AudioOutput {
+    // queues a buffer of samples. It's fine to block until there's room for them
+    fn play(&mut self, samples: &[AudioOutType]);
+    // drops the samples queued but not played yet
+    fn clear(&mut self);
+    // samples per second the host plays
+    fn sample_rate(&self) -> usize {
+        SAMPLE_RATE
+    }
+    // samples played at once, less means less latency
+    fn audio_buffer_size(&self) -> usize {
+        AUDIO_BUFFER_SIZE
+    }
+}
+
+// everything a frontend provides to run the emulator
+pub trait Backend: Renderer + Input + AudioOutput {}
+
+impl<T: Renderer + Input + AudioOutput> Backend for T {}
@@ -0,0 +1,367 @@
+extern crate sdl2;
+
+use backend::{AudioOutput, Input, InputEvent, Renderer};
+use keypad::Button;
+use sound::{AudioOutType, AUDIO_BUFFER_SIZE, SAMPLE_RATE};
+
+use self::sdl2::audio::{AudioQueue, AudioSpecDesired};
+use self::sdl2::controller::{Button as ControllerButton, GameController};
+use self::sdl2::event::Event;
+use self::sdl2::keyboard::Keycode;
+use self::sdl2::pixels::PixelFormatEnum;
+use self::sdl2::rect::Rect;
+use self::sdl2::render::{Canvas, TextureCreator};
+use self::sdl2::video::{Window, WindowContext};
+use self::sdl2::{EventPump, GameControllerSubsystem};
+use std::collections::HashMap;
+use std::{thread, time};
+
+const SCREEN_SIZE_MULTIPLIER: u32 = 3;
+const SCREEN_WIDTH: u32 = 160 * SCREEN_SIZE_MULTIPLIER;
+const SCREEN_HEIGHT: u32 = 144 * SCREEN_SIZE_MULTIPLIER;
+
+// the gameboy button a controller button presses by default: the face buttons are A/B,
+// back/start are select/start and the d-pad is the d-pad
+pub fn controller_button_to_gb(btn: ControllerButton) -> Option<Button> {
+    match btn {
+        ControllerButton::A => Some(Button::A),
+        ControllerButton::B => Some(Button::B),
+        ControllerButton::Back => Some(Button::SELECT),
+        ControllerButton::Start => Some(Button::START),
+        ControllerButton::DPadUp => Some(Button::UP),
+        ControllerButton::DPadDown => Some(Button::DOWN),
+        ControllerButton::DPadLeft => Some(Button::LEFT),
+        ControllerButton::DPadRight => Some(Button::RIGHT),
+        _ => None,
+    }
+}
+
+// which keyboard key or controller button presses which gameboy button
+pub struct KeyBindings {
+    keys: HashMap<Keycode, Button>,
+    // on top of controller_button_to_gb
+    controller_buttons: HashMap<ControllerButton, Button>,
+}
+
+impl KeyBindings {
+    // Z/X are A/B, A/S are select/start, arrows for the d-pad
+    pub fn new() -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(Keycode::Z, Button::A);
+        keys.insert(Keycode::X, Button::B);
+        keys.insert(Keycode::A, Button::SELECT);
+        keys.insert(Keycode::S, Button::START);
+        keys.insert(Keycode::Down, Button::DOWN);
+        keys.insert(Keycode::Up, Button::UP);
+        keys.insert(Keycode::Left, Button::LEFT);
+        keys.insert(Keycode::Right, Button::RIGHT);
+        KeyBindings {
+            keys,
+            controller_buttons: HashMap::new(),
+        }
+    }
+
+    // binds key to button, replacing what key was bound to.
+    // Escape, Q, Space, N, Tab, Backspace, = and - are taken by the emulator controls
+    pub fn set_binding(&mut self, key: Keycode, button: Button) {
+        self.keys.insert(key, button);
+    }
+
+    pub fn button(&self, key: Keycode) -> Option<Button> {
+        self.keys.get(&key).cloned()
+    }
+
+    // binds a controller button to button, replacing its default
+    pub fn set_controller_binding(&mut self, controller_button: ControllerButton, button: Button) {
+        self.controller_buttons.insert(controller_button, button);
+    }
+
+    pub fn controller_button(&self, controller_button: ControllerButton) -> Option<Button> {
+        self.controller_buttons
+            .get(&controller_button)
+            .cloned()
+            .or_else(|| controller_button_to_gb(controller_button))
+    }
+
+    // the emulator input for an sdl event, if any
+    fn input_event(&self, event: &Event) -> Option<InputEvent> {
+        match *event {
+            Event::Quit { .. } => Some(InputEvent::Quit),
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => match keycode {
+                Keycode::Q | Keycode::Escape => Some(InputEvent::Quit),
+                Keycode::Space => Some(InputEvent::TogglePause),
+                Keycode::Tab => Some(InputEvent::ToggleTurbo),
+                Keycode::Equals | Keycode::KpPlus => Some(InputEvent::SpeedUp),
+                Keycode::Minus | Keycode::KpMinus => Some(InputEvent::SlowDown),
+                Keycode::Backspace => Some(InputEvent::Rewind),
+                Keycode::N => Some(InputEvent::StepFrame),
+                _ => self.button(keycode).map(InputEvent::Press),
+            },
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => self.button(keycode).map(InputEvent::Release),
+            Event::ControllerButtonDown { button, .. } => {
+                self.controller_button(button).map(InputEvent::Press)
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                self.controller_button(button).map(InputEvent::Release)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings::new()
+    }
+}
+
+// a window, the keyboard, the controllers and an audio queue, through SDL
+pub struct SdlBackend {
+    canvas: Canvas<Window>,
+    texture_creator: TextureCreator<WindowContext>,
+    event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    // the controllers only send events while open
+    controllers: Vec<GameController>,
+    audio_queue: AudioQueue<AudioOutType>,
+    audio_buffer_size: usize, // samples queued at once
+    bindings: KeyBindings,
+}
+
+impl SdlBackend {
+    pub fn new() -> Result<SdlBackend, String> {
+        SdlBackend::with_audio_buffer_size(AUDIO_BUFFER_SIZE)
+    }
+
+    // like new, playing the audio audio_buffer_size samples at a time.
+    // Smaller buffers lower the latency, but might not keep the device fed
+    pub fn with_audio_buffer_size(audio_buffer_size: usize) -> Result<SdlBackend, String> {
+        let sdl = sdl2::init()?;
+        let video_subsystem = sdl.video()?;
+        let audio_subsystem = sdl.audio()?;
+        let controller_subsystem = sdl.game_controller()?;
+
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(audio_buffer_size as u16),
+        };
+
+        let audio_queue = audio_subsystem.open_queue::<AudioOutType, _>(None, &desired_spec)?;
+
+        let window = video_subsystem
+            .window("gameman", SCREEN_WIDTH, SCREEN_HEIGHT)
+            .position_centered()
+            .opengl()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let texture_creator = canvas.texture_creator();
+
+        let event_pump = sdl.event_pump()?;
+
+        Ok(SdlBackend {
+            canvas,
+            texture_creator,
+            event_pump,
+            controller_subsystem,
+            controllers: Vec::new(),
+            audio_queue,
+            audio_buffer_size,
+            bindings: KeyBindings::new(),
+        })
+    }
+
+    pub fn set_binding(&mut self, key: Keycode, button: Button) {
+        self.bindings.set_binding(key, button);
+    }
+
+    pub fn set_controller_binding(&mut self, controller_button: ControllerButton, button: Button) {
+        self.bindings
+            .set_controller_binding(controller_button, button);
+    }
+
+    // opens the controllers plugged in, and closes the unplugged ones.
+    // The controllers already plugged in at startup get an added event too
+    fn handle_controller_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.controller_subsystem.open(which) {
+                    Ok(controller) => self.controllers.push(controller),
+                    Err(e) => println!("Error opening controller {}: {}", which, e),
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers
+                    .retain(|controller| controller.instance_id() != which);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Renderer for SdlBackend {
+    fn present(&mut self, rgb: &[u8]) {
+        // the texture borrows its creator, so it can't live next to it in the backend
+        let mut texture = self
+            .texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+            .unwrap();
+
+        texture
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                for (y, row) in rgb.chunks(160 * 3).enumerate() {
+                    buffer[y * pitch..y * pitch + row.len()].copy_from_slice(row);
+                }
+            })
+            .unwrap();
+
+        self.canvas.clear();
+        self.canvas
+            .copy(
+                &texture,
+                None,
+                Some(Rect::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)),
+            )
+            .unwrap();
+        self.canvas.present();
+    }
+}
+
+impl Input for SdlBackend {
+    fn poll_events(&mut self) -> Vec<InputEvent> {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+
+        let mut input_events = Vec::new();
+        for event in events {
+            self.handle_controller_event(&event);
+            if let Some(input_event) = self.bindings.input_event(&event) {
+                input_events.push(input_event);
+            }
+        }
+        input_events
+    }
+}
+
+impl AudioOutput for SdlBackend {
+    fn play(&mut self, samples: &[AudioOutType]) {
+        // wait for device queue to drain audio buffer
+        while self.audio_queue.size() > self.audio_buffer_size as u32 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+
+        self.audio_queue.queue(samples);
+        self.audio_queue.resume();
+    }
+
+    fn clear(&mut self) {
+        self.audio_queue.clear();
+    }
+
+    // the device might not open at the rate asked for
+    fn sample_rate(&self) -> usize {
+        self.audio_queue.spec().freq as usize
+    }
+
+    fn audio_buffer_size(&self) -> usize {
+        self.audio_buffer_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_binding() {
+        let mut bindings = KeyBindings::new();
+
+        assert_eq!(bindings.button(Keycode::Z), Some(Button::A));
+        assert_eq!(bindings.button(Keycode::J), None);
+
+        bindings.set_binding(Keycode::J, Button::START);
+        bindings.set_binding(Keycode::Z, Button::B);
+
+        assert_eq!(bindings.button(Keycode::J), Some(Button::START));
+        assert_eq!(bindings.button(Keycode::Z), Some(Button::B));
+        assert_eq!(bindings.button(Keycode::S), Some(Button::START));
+    }
+
+    #[test]
+    fn test_controller_button_to_gb() {
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::A),
+            Some(Button::A)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::B),
+            Some(Button::B)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::Back),
+            Some(Button::SELECT)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::Start),
+            Some(Button::START)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::DPadUp),
+            Some(Button::UP)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::DPadDown),
+            Some(Button::DOWN)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::DPadLeft),
+            Some(Button::LEFT)
+        );
+        assert_eq!(
+            controller_button_to_gb(ControllerButton::DPadRight),
+            Some(Button::RIGHT)
+        );
+
+        assert_eq!(controller_button_to_gb(ControllerButton::X), None);
+        assert_eq!(controller_button_to_gb(ControllerButton::Guide), None);
+    }
+
+    #[test]
+    fn test_set_controller_binding() {
+        let mut bindings = KeyBindings::new();
+
+        bindings.set_controller_binding(ControllerButton::X, Button::B);
+        bindings.set_controller_binding(ControllerButton::A, Button::START);
+
+        assert_eq!(
+            bindings.controller_button(ControllerButton::X),
+            Some(Button::B)
+        );
+        assert_eq!(
+            bindings.controller_button(ControllerButton::A),
+            Some(Button::START)
+        );
+        assert_eq!(
+            bindings.controller_button(ControllerButton::B),
+            Some(Button::B)
+        );
+        assert_eq!(bindings.controller_button(ControllerButton::Y), None);
+
+        let event = Event::ControllerButtonDown {
+            timestamp: 0,
+            which: 0,
+            button: ControllerButton::DPadLeft,
+        };
+        assert_eq!(
+            bindings.input_event(&event),
+            Some(InputEvent::Press(Button::LEFT))
+        );
+    }
+}
@@ -1,11 +1,34 @@
 extern crate gameman;
 
+use gameman::backend::SdlBackend;
 use gameman::emu::Emulator;
 
 fn main() {
     let rom_path = std::env::args()
         .nth(1)
-        .expect("no gb rom file given. Usage: cargo run <rom file>");
-    let mut emulator = Emulator::new(rom_path.as_str());
-    emulator.run();
+        .expect("no gb rom file given. Usage: cargo run <rom file> [--debug] [--no-save]");
+    let flags: Vec<String> = std::env::args().skip(2).collect();
+    let debug = flags.iter().any(|arg| arg == "--debug");
+    let no_save = flags.iter().any(|arg| arg == "--no-save");
+
+    let mut emulator = match Emulator::try_new(rom_path.as_str(), no_save) {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if debug {
+        emulator.debug_repl();
+        return;
+    }
+
+    let mut backend = match SdlBackend::new() {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    emulator.run(&mut backend);
 }
@@ -1,4 +1,4 @@
-use cartridge::{Cartridge, CartridgeAccess};
+use cartridge::{Cartridge, CartridgeAccess, RAM_BANK_SIZE, ROM_BANK_SIZE};
 
 pub struct CartridgeMBC1 {
     cart: Cartridge,
@@ -18,6 +18,39 @@ impl CartridgeAccess for CartridgeMBC1 {
         &mut self.cart
     }
 
+    // the ram bank is only selected in mode 1, wrapped around the ram size
+    fn ram_offset(&self) -> usize {
+        let cartridge = self.cartridge();
+        if cartridge.mode == 0 || cartridge.ram.is_empty() {
+            return 0;
+        }
+        (cartridge.ram_bank as usize * RAM_BANK_SIZE) % cartridge.ram.len()
+    }
+
+    // the upper 2 bits of the rom bank always apply to 0x4000-0x7FFF,
+    // and in mode 1 to 0x0000-0x3FFF as well. Banks past the rom size wrap around
+    fn read_rom(&self, addr: u16) -> u8 {
+        let cartridge = self.cartridge();
+        if cartridge.rom.is_empty() {
+            return 0xFF;
+        }
+
+        let bank = match addr & 0xF000 {
+            0x0000 | 0x1000 | 0x2000 | 0x3000 => {
+                if cartridge.mode == 1 {
+                    cartridge.rom_bank & 0x60
+                } else {
+                    0
+                }
+            }
+            0x4000 | 0x5000 | 0x6000 | 0x7000 => cartridge.rom_bank,
+            _ => panic!("Unhandled ROM MBC read at addr {:x}", addr),
+        };
+
+        let abs_addr = bank as usize * ROM_BANK_SIZE + (addr & 0x3FFF) as usize;
+        cartridge.rom[abs_addr % cartridge.rom.len()]
+    }
+
     fn write_rom(&mut self, addr: u16, byte: u8) {
         let cartridge = self.cartridge_mut();
 
@@ -36,17 +69,93 @@ impl CartridgeAccess for CartridgeMBC1 {
                 cartridge.rom_bank = (cartridge.rom_bank & 0x60) + val as u16;
             }
             0x4000 | 0x5000 => {
-                // change rom bank or ram bank
-                if cartridge.mode == 1 {
-                    cartridge.ram_bank = byte & 3;
-                } else {
-                    cartridge.rom_bank = (cartridge.rom_bank & 0x1F) + ((byte & 3) << 5) as u16;
-                }
+                // upper bits of the rom bank, and the ram bank. The mode picks which one is used
+                cartridge.rom_bank = (cartridge.rom_bank & 0x1F) + ((byte & 3) << 5) as u16;
+                cartridge.ram_bank = byte & 3;
             }
             0x6000 | 0x7000 => {
-                panic!("rom mode change not implemented")
-            } // change rom mode
+                // change banking mode
+                cartridge.mode = byte & 1;
+            }
             _ => panic!("Unhandled rom write at addr 0x{:x}", addr),
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 2MB rom, every bank starting with its number
+    fn large_rom() -> Vec<u8> {
+        let mut rom = vec![0; 128 * ROM_BANK_SIZE];
+        for bank in 0..128 {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_rom_banking_mode_0() {
+        let mut mbc = CartridgeMBC1::new(Cartridge::without_save(large_rom(), 0));
+
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x25);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+
+        // bank 0 can't be selected in the lower bits
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+    }
+
+    #[test]
+    fn test_rom_banking_mode_1() {
+        let mut mbc = CartridgeMBC1::new(Cartridge::without_save(large_rom(), 0));
+
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_rom(0x6000, 0x01);
+
+        // the upper bits now remap the first area too
+        assert_eq!(mbc.read_rom(0x0000), 0x40);
+        assert_eq!(mbc.read_rom(0x4000), 0x45);
+
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_rom_bank_wraps() {
+        // 256KB, 16 banks
+        let mut rom = large_rom();
+        rom.truncate(16 * ROM_BANK_SIZE);
+        let mut mbc = CartridgeMBC1::new(Cartridge::without_save(rom, 0));
+
+        mbc.write_rom(0x2000, 0x13);
+        assert_eq!(mbc.read_rom(0x4000), 0x03);
+
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_ram_banking() {
+        let mut mbc = CartridgeMBC1::new(Cartridge::without_save(vec![0; 0x8000], 0x8000));
+        mbc.write_rom(0x0000, 0x0A);
+
+        // mode 0 always uses the first ram bank
+        mbc.write_rom(0x4000, 0x02);
+        mbc.write_ram(0x0000, 0x11);
+        assert_eq!(mbc.cart.ram[0], 0x11);
+
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_ram(0x0000, 0x22);
+        assert_eq!(mbc.cart.ram[2 * RAM_BANK_SIZE], 0x22);
+        assert_eq!(mbc.read_ram(0x0000), 0x22);
+
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0x11);
+    }
+}
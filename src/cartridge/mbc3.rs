@@ -1,4 +1,7 @@
 use cartridge::{Cartridge, CartridgeAccess};
+use state::{SaveState, StateReader, StateWriter};
+
+use std::io;
 
 pub struct CartridgeMBC3 {
     cart: Cartridge,
@@ -52,6 +55,11 @@ impl CartridgeAccess for CartridgeMBC3 {
         };
     }
 
+    fn reset(&mut self) {
+        self.cart.reset();
+        self.ram_and_timer_enabled = false;
+    }
+
     fn read_ram(&self, addr: u16) -> u8 {
         let cartridge = self.cartridge();
 
@@ -61,9 +69,9 @@ impl CartridgeAccess for CartridgeMBC3 {
             return 0x0;
         }
         if cartridge.ram.is_empty() || !self.ram_and_timer_enabled {
-            0xFF
+            cartridge.disabled_ram_read(addr)
         } else {
-            cartridge.ram[self.ram_offset() + addr as usize]
+            cartridge.read_ram_at(self.ram_offset() + addr as usize)
         }
     }
 
@@ -80,6 +88,17 @@ impl CartridgeAccess for CartridgeMBC3 {
         if cartridge.ram.is_empty() || !ram_and_timer_enabled {
             return;
         }
-        cartridge.ram[ram_offset + addr as usize] = byte;
+        cartridge.write_ram_at(ram_offset + addr as usize, byte);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cart.save_state(state);
+        state.write_bool(self.ram_and_timer_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cart.load_state(state)?;
+        self.ram_and_timer_enabled = state.read_bool()?;
+        Ok(())
     }
 }
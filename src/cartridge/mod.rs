@@ -8,14 +8,32 @@ use cartridge::mbc3::CartridgeMBC3;
 use cartridge::mbc5::CartridgeMBC5;
 use cartridge::nombc::CartridgeNoMBC;
 
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
+use std::cell::Cell;
+#[cfg(feature = "std")]
 use std::fs::{File, OpenOptions};
 use std::io;
+#[cfg(feature = "std")]
 use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
 use std::path::PathBuf;
 
 pub const ROM_BANK_SIZE: usize = 0x4000;
 pub const RAM_BANK_SIZE: usize = 0x2000;
 
+// what the rom header says about the game
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    pub title: String,       // up to 16 ascii characters, cgb games have less
+    pub cgb_flag: u8,        // 0x80 works on the dmg too, 0xC0 is cgb only
+    pub sgb_flag: u8,        // 0x03 has sgb functions
+    pub cartridge_type: u8,  // the mbc and what's in the cartridge with it
+    pub region: u8,          // 0x00 japan, 0x01 everywhere else
+    pub licensee: u8,        // the old licensee code, 0x33 means the new one is used
+    pub header_checksum: u8, // of the bytes 0x134 to 0x14C, checked by the boot rom
+}
+
 pub struct Cartridge {
     pub rom: Vec<u8>,
     pub ram: Vec<u8>,
@@ -26,50 +44,209 @@ pub struct Cartridge {
     ram_bank: u8,
     mode: u8,
 
-    path: PathBuf,
+    // reads from disabled or absent ram return the low byte of the address instead of 0xFF
+    open_bus: bool,
+
+    #[cfg(feature = "std")]
+    path: PathBuf, // empty for roms loaded from memory
+    #[cfg(feature = "std")]
+    save_path: Option<PathBuf>, // where to keep the save file, instead of next to the rom
+    #[cfg(feature = "std")]
     save_file: Option<File>,
+    dirty: bool, // ram changed since it was last saved
+
+    ram_out_of_bounds_logged: Cell<bool>, // only the first access past the ram is reported
 }
 
 impl Cartridge {
+    #[cfg(feature = "std")]
     pub fn new(path: PathBuf, rom: Vec<u8>, ram_size: usize) -> Self {
-        let mut cart = Self {
+        Cartridge::with_save_path(path, rom, ram_size, None, false)
+    }
+
+    // read_only loads the save file if there is one, but never creates nor writes it:
+    // what the game saves is lost with the cartridge
+    #[cfg(feature = "std")]
+    pub fn with_save_path(
+        path: PathBuf,
+        rom: Vec<u8>,
+        ram_size: usize,
+        save_path: Option<PathBuf>,
+        read_only: bool,
+    ) -> Self {
+        // the ram is read from the save file
+        let mut cart = Cartridge::with_ram(rom, Vec::new());
+        cart.ram_size = ram_size;
+        cart.path = path;
+        cart.save_path = save_path;
+
+        if ram_size > 0 {
+            match cart.try_load_save_file(read_only) {
+                Ok(file) => cart.save_file = file,
+                Err(e) => {
+                    println!("Unable to load/create save file: {}", e)
+                }
+            }
+        }
+
+        cart
+    }
+
+    // a cartridge whose ram lives only in memory, no save file is read or written
+    pub fn without_save(rom: Vec<u8>, ram_size: usize) -> Self {
+        Cartridge::with_ram(rom, vec![0; ram_size])
+    }
+
+    // a cartridge starting from the given ram, like a battery save kept by the embedder.
+    // No save file is read or written
+    pub fn with_ram(rom: Vec<u8>, ram: Vec<u8>) -> Self {
+        Self {
             rom,
-            ram: Vec::new(),
-            ram_size,
+            ram_size: ram.len(),
+            ram,
             ram_enabled: false,
             rom_bank: 1,
             ram_bank: 0,
             mode: 0,
-            path,
+            open_bus: false,
+            #[cfg(feature = "std")]
+            path: PathBuf::new(),
+            #[cfg(feature = "std")]
+            save_path: None,
+            #[cfg(feature = "std")]
             save_file: None,
+            dirty: false,
+            ram_out_of_bounds_logged: Cell::new(false),
+        }
+    }
+
+    // parses the rom header. Bytes past the end of a rom too small for it read 0
+    pub fn header(&self) -> CartridgeHeader {
+        let byte = |addr: usize| self.rom.get(addr).cloned().unwrap_or(0);
+
+        // the title ends at the first 0, the cgb flag took its last byte
+        let title_end = if byte(0x143) & 0x80 != 0 {
+            0x143
+        } else {
+            0x144
         };
+        let title = (0x134..title_end)
+            .map(byte)
+            .take_while(|&c| c != 0)
+            .map(|c| c as char)
+            .collect();
+
+        CartridgeHeader {
+            title,
+            cgb_flag: byte(0x143),
+            sgb_flag: byte(0x146),
+            cartridge_type: byte(0x147),
+            region: byte(0x14A),
+            licensee: byte(0x14B),
+            header_checksum: byte(0x14D),
+        }
+    }
 
-        if ram_size > 0 {
-            match cart.try_load_save_file() {
-                Ok(file) => cart.save_file = Some(file),
-                Err(e) => {
-                    println!("Unable to load/create save file: {}", e)
-                }
+    // the bank registers go back to their power on values, the ram is kept
+    pub fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+        self.mode = 0;
+    }
+
+    pub fn set_open_bus(&mut self, enabled: bool) {
+        self.open_bus = enabled;
+    }
+
+    // the value read from disabled or absent ram.
+    // The low byte of the address approximates what's left on the bus
+    pub fn disabled_ram_read(&self, addr: u16) -> u8 {
+        if self.open_bus {
+            (addr & 0xFF) as u8
+        } else {
+            0xFF
+        }
+    }
+
+    // the ram byte at index, 0xFF past the end of the ram
+    pub fn read_ram_at(&self, index: usize) -> u8 {
+        match self.ram.get(index) {
+            Some(byte) => *byte,
+            None => {
+                self.log_ram_out_of_bounds(index);
+                0xFF
             }
         }
+    }
 
-        cart
+    // writes the ram byte at index, ignored past the end of the ram
+    pub fn write_ram_at(&mut self, index: usize, byte: u8) {
+        match self.ram.get_mut(index) {
+            Some(ram_byte) => {
+                *ram_byte = byte;
+                self.dirty = true;
+            }
+            None => self.log_ram_out_of_bounds(index),
+        }
+    }
+
+    // a copy of the battery ram, for frontends keeping the saves themselves
+    pub fn export_ram(&self) -> Vec<u8> {
+        self.ram.clone()
     }
 
+    // replaces the battery ram with a save exported before, as big as the cartridge ram
+    pub fn import_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.ram_size {
+            return Err(format!(
+                "the save is 0x{:x} bytes, the cartridge ram is 0x{:x}",
+                data.len(),
+                self.ram_size
+            ));
+        }
+
+        self.ram = data.to_vec();
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn log_ram_out_of_bounds(&self, index: usize) {
+        if !self.ram_out_of_bounds_logged.replace(true) {
+            println!(
+                "External ram access at 0x{:x} is past the 0x{:x} bytes of ram, ignored",
+                index,
+                self.ram.len()
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
     // the path for the save file
     fn save_file_path(&self) -> PathBuf {
+        if let Some(save_path) = self.save_path.as_ref() {
+            return save_path.clone();
+        }
+
         let mut save_file = self.path.clone();
         save_file.set_extension("sav");
         save_file
     }
 
-    // attemps to load/create a save file
-    fn try_load_save_file(&mut self) -> io::Result<File> {
+    #[cfg(feature = "std")]
+    // attemps to load/create a save file. Read only, it's not created nor kept open
+    fn try_load_save_file(&mut self, read_only: bool) -> io::Result<Option<File>> {
+        let save_file_path = self.save_file_path();
+        if read_only && !save_file_path.exists() {
+            self.ram = vec![0; self.ram_size];
+            return Ok(None);
+        }
+
         let mut file = OpenOptions::new()
             .read(true)
-            .write(true)
-            .create(true)
-            .open(self.save_file_path())?;
+            .write(!read_only)
+            .create(!read_only)
+            .open(save_file_path)?;
 
         let file_size = file.metadata()?.len();
         let expected_file_size = self.ram_size as u64;
@@ -79,15 +256,34 @@ impl Cartridge {
             self.ram = vec![0; self.ram_size];
             self.save()?
         } else if file_size != expected_file_size {
-            panic!("Save file has unexpected size");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "save file has unexpected size {}, expected {}",
+                    file_size, expected_file_size
+                ),
+            ));
         } else {
             println!("Loading save file");
             file.read_to_end(&mut self.ram)?;
         };
 
-        Ok(file)
+        Ok(if read_only { None } else { Some(file) })
+    }
+
+    #[cfg(feature = "std")]
+    // writes the ram to the save file, if it changed since the last time
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.save()?;
+        self.dirty = false;
+        Ok(())
     }
 
+    #[cfg(feature = "std")]
     fn save(&mut self) -> io::Result<()> {
         if let Some(file) = self.save_file.as_mut() {
             println!("Saving game");
@@ -98,10 +294,37 @@ impl Cartridge {
     }
 }
 
+// the rom is not saved, states can only be loaded back in the same cartridge
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_u8(self.mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let ram = state.read_vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(invalid_state(
+                "save state ram size doesnt match the cartridge",
+            ));
+        }
+        self.ram = ram;
+        self.dirty = true;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.mode = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
 impl Drop for Cartridge {
     fn drop(&mut self) {
-        // TODO: dont save when closing
-        match self.save() {
+        match self.flush_save() {
             Ok(()) => {}
             Err(e) => {
                 println!("Error updating save file: {}", e)
@@ -141,13 +364,22 @@ pub trait CartridgeAccess {
 
     fn write_rom(&mut self, addr: u16, byte: u8);
 
+    // like the cartridge was just plugged in, its ram is kept
+    fn reset(&mut self) {
+        self.cartridge_mut().reset();
+    }
+
+    fn set_open_bus(&mut self, enabled: bool) {
+        self.cartridge_mut().set_open_bus(enabled);
+    }
+
     fn read_ram(&self, addr: u16) -> u8 {
         let cartridge = self.cartridge();
 
         if cartridge.ram.is_empty() || !cartridge.ram_enabled {
-            0xFF
+            cartridge.disabled_ram_read(addr)
         } else {
-            cartridge.ram[self.ram_offset() + addr as usize]
+            cartridge.read_ram_at(self.ram_offset() + addr as usize)
         }
     }
 
@@ -159,46 +391,546 @@ pub trait CartridgeAccess {
         if cartridge.ram.is_empty() || !cartridge.ram_enabled {
             return;
         }
-        cartridge.ram[ram_offset + addr as usize] = byte;
+        cartridge.write_ram_at(ram_offset + addr as usize, byte);
+    }
+
+    #[cfg(feature = "std")]
+    fn flush_save(&mut self) -> io::Result<()> {
+        self.cartridge_mut().flush_save()
     }
+
+    // mbcs with registers of their own save them after the cartridge ones
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cartridge().save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cartridge_mut().load_state(state)
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn load_rom(path: &str) -> Result<Box<dyn CartridgeAccess>, String> {
+    load_rom_with_save_path(path, None, false)
 }
 
-pub fn load_rom(path: &str) -> Box<dyn CartridgeAccess> {
+#[cfg(feature = "std")]
+// like load_rom, keeping the save file at save_path if given.
+// Read only, the save file is never created nor written
+pub fn load_rom_with_save_path(
+    path: &str,
+    save_path: Option<PathBuf>,
+    read_only: bool,
+) -> Result<Box<dyn CartridgeAccess>, String> {
     let mut rom: Vec<u8> = Vec::new();
 
-    match File::open(path) {
-        Ok(mut file) => {
-            match file.read_to_end(&mut rom) {
-                Ok(_) => {}
-                Err(_) => panic!("couldnt read the rom into the buffer!"),
-            };
-        }
-        Err(_) => panic!("couldnt open the rom file"),
+    let mut file = File::open(path).map_err(|e| format!("couldnt open the rom file: {}", e))?;
+    file.read_to_end(&mut rom)
+        .map_err(|e| format!("couldnt read the rom into the buffer: {}", e))?;
+
+    let (cart_type, ram_size) = parse_rom(&rom)?;
+    pad_rom(&mut rom);
+    let cart = Cartridge::with_save_path(PathBuf::from(path), rom, ram_size, save_path, read_only);
+
+    Ok(cartridge_controller(cart_type, cart))
+}
+
+// loads a rom already in memory, without touching the filesystem.
+// The cartridge ram is not saved anywhere
+pub fn load_rom_bytes(mut rom: Vec<u8>) -> Result<Box<dyn CartridgeAccess>, String> {
+    let (cart_type, ram_size) = parse_rom(&rom)?;
+    pad_rom(&mut rom);
+    let cart = Cartridge::without_save(rom, ram_size);
+
+    Ok(cartridge_controller(cart_type, cart))
+}
+
+// like load_rom_bytes, starting from a battery save kept somewhere else.
+// The ram has to be as big as the rom header says
+pub fn load_rom_bytes_with_ram(
+    mut rom: Vec<u8>,
+    ram: Vec<u8>,
+) -> Result<Box<dyn CartridgeAccess>, String> {
+    let (cart_type, ram_size) = parse_rom(&rom)?;
+    pad_rom(&mut rom);
+    if ram.len() != ram_size {
+        return Err(format!(
+            "the ram is 0x{:x} bytes, the cartridge has 0x{:x}",
+            ram.len(),
+            ram_size
+        ));
     }
+    let cart = Cartridge::with_ram(rom, ram);
 
-    let ram_size = match rom[0x149] {
-        0x00 => 0,
-        0x01 => 2,
-        0x02 => 8,
-        0x03 => 32,
-        0x04 => 128,
-        0x05 => 64,
-        _ => panic!("Unrecognized cartridge ram size"),
-    } * 1024;
+    Ok(cartridge_controller(cart_type, cart))
+}
+
+// checks the rom header, returning the cartridge type and its external ram size
+fn parse_rom(rom: &[u8]) -> Result<(u8, usize), String> {
+    if rom.len() < 0x150 {
+        return Err(String::from("rom is too small to contain a header"));
+    }
 
-    let cart_type = rom[0x147] as usize;
+    let cart_type = rom[0x147];
+    if !matches!(cart_type, 0 | 1 | 2 | 3 | 0x13 | 0x19 | 0x1b) {
+        return Err(format!("Cartridge type 0x{:x} not implemented", cart_type));
+    }
+
+    let ram_size = cartridge_ram_size(cart_type, rom[0x149])?;
+
+    let (header_ok, global_ok) = verify_checksums(rom);
+    if !header_ok {
+        println!("The header checksum doesn't match, a real boot rom would lock up");
+    }
+    if !global_ok {
+        println!("The global checksum doesn't match");
+    }
 
     println!("rom size = 0x{:x}", rom.len());
     println!("rom type = 0x{:x}", cart_type);
     println!("ram size = 0x{:x}", ram_size);
 
-    let cart = Cartridge::new(PathBuf::from(path), rom, ram_size);
+    Ok((cart_type, ram_size))
+}
+
+// checks the header checksum, over 0x134-0x14C, and the global one, the sum of every
+// other byte of the rom. Only the first is checked by the boot rom
+pub fn verify_checksums(rom: &[u8]) -> (bool, bool) {
+    if rom.len() < 0x150 {
+        return (false, false);
+    }
+
+    let header_checksum = rom[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+
+    let global_checksum = rom
+        .iter()
+        .enumerate()
+        .filter(|(addr, _)| *addr != 0x14E && *addr != 0x14F)
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+    let declared_global_checksum = (rom[0x14E] as u16) << 8 | rom[0x14F] as u16;
+
+    (
+        header_checksum == rom[0x14D],
+        global_checksum == declared_global_checksum,
+    )
+}
+
+// the rom size declared by the size code in the header, if it's a known one
+fn declared_rom_size(size_code: u8) -> Option<usize> {
+    match size_code {
+        0x00..=0x08 => Some(0x8000 << size_code),
+        0x52 => Some(72 * ROM_BANK_SIZE),
+        0x53 => Some(80 * ROM_BANK_SIZE),
+        0x54 => Some(96 * ROM_BANK_SIZE),
+        _ => None,
+    }
+}
+
+// warns if the rom isn't as big as its header says. A short rom is padded with 0xFF
+// up to the declared size, so that the banks past its end read like an open bus
+fn pad_rom(rom: &mut Vec<u8>) {
+    let declared_size = match declared_rom_size(rom[0x148]) {
+        Some(size) => size,
+        None => {
+            println!("Unrecognized rom size code 0x{:x}", rom[0x148]);
+            return;
+        }
+    };
+
+    if rom.len() != declared_size {
+        println!(
+            "The rom is 0x{:x} bytes, its header says 0x{:x}",
+            rom.len(),
+            declared_size
+        );
+    }
+    if rom.len() < declared_size {
+        rom.resize(declared_size, 0xFF);
+    }
+}
 
+// wraps the cartridge in the memory bank controller of its type, checked by parse_rom
+fn cartridge_controller(cart_type: u8, cart: Cartridge) -> Box<dyn CartridgeAccess> {
     match cart_type {
         0 => Box::new(CartridgeNoMBC::new(cart)),
         1 | 2 | 3 => Box::new(CartridgeMBC1::new(cart)),
         0x13 => Box::new(CartridgeMBC3::new(cart)),
         0x19 | 0x1b => Box::new(CartridgeMBC5::new(cart)),
-        _ => panic!("Cartridge type {:x} not implemented", cart_type),
+        _ => unreachable!("Cartridge type 0x{:x} not implemented", cart_type),
+    }
+}
+
+// the external ram size of a cartridge, given its type and the ram size code in its header.
+// The header is ignored for cartridges that can't have external ram
+fn cartridge_ram_size(cart_type: u8, ram_code: u8) -> Result<usize, String> {
+    match cart_type {
+        // the mbc2 has 512 half bytes of ram built in
+        0x05 | 0x06 => return Ok(512),
+        // no external ram
+        0x00 | 0x01 | 0x0B | 0x0F | 0x11 | 0x15 | 0x19 | 0x1C | 0x20 | 0x22 => return Ok(0),
+        _ => {}
+    }
+
+    let kilobytes = match ram_code {
+        0x00 => 0,
+        0x01 => 2,
+        0x02 => 8,
+        0x03 => 32,
+        0x04 => 128,
+        0x05 => 64,
+        _ => {
+            return Err(format!(
+                "Unrecognized cartridge ram size code 0x{:x}",
+                ram_code
+            ))
+        }
+    };
+
+    Ok(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_ram_open_bus() {
+        let cart = Cartridge::without_save(vec![0; 0x8000], 0);
+        let mut mbc: Box<dyn CartridgeAccess> = Box::new(CartridgeMBC1::new(cart));
+
+        assert_eq!(mbc.read_ram(0x0123), 0xFF);
+
+        mbc.set_open_bus(true);
+        assert_eq!(mbc.read_ram(0x0123), 0x23);
+        assert_eq!(mbc.read_ram(0x1FFE), 0xFE);
+
+        mbc.set_open_bus(false);
+        assert_eq!(mbc.read_ram(0x0123), 0xFF);
+    }
+
+    // a bank past the end of the ram reads 0xFF and drops the writes
+    #[test]
+    fn test_ram_out_of_bounds() {
+        let cart = Cartridge::without_save(vec![0; 0x8000], RAM_BANK_SIZE);
+        let mut mbc: Box<dyn CartridgeAccess> = Box::new(CartridgeMBC5::new(cart));
+        mbc.write_rom(0x0000, 0x0A);
+
+        mbc.write_ram(0x0010, 0x42);
+        assert_eq!(mbc.read_ram(0x0010), 0x42);
+
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_ram(0x0010), 0xFF);
+        mbc.write_ram(0x0010, 0x24);
+        assert_eq!(mbc.read_ram(0x0010), 0xFF);
+
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0010), 0x42);
+        assert_eq!(mbc.cartridge().ram.len(), RAM_BANK_SIZE);
+    }
+
+    #[test]
+    fn test_export_import_ram() {
+        let cart = Cartridge::without_save(vec![0; 0x8000], 4 * RAM_BANK_SIZE);
+        let mut mbc: Box<dyn CartridgeAccess> = Box::new(CartridgeMBC5::new(cart));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x42);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0x1FFF, 0x24);
+
+        let save = mbc.cartridge().export_ram();
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE);
+
+        mbc.cartridge_mut()
+            .import_ram(&[0; 4 * RAM_BANK_SIZE])
+            .unwrap();
+        assert_eq!(mbc.read_ram(0x1FFF), 0x00);
+
+        mbc.cartridge_mut().import_ram(&save).unwrap();
+        assert_eq!(mbc.read_ram(0x1FFF), 0x24);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0x42);
+
+        // the wrong size leaves the ram alone
+        assert!(mbc.cartridge_mut().import_ram(&[0; RAM_BANK_SIZE]).is_err());
+        assert!(mbc.cartridge_mut().import_ram(&[]).is_err());
+        assert_eq!(mbc.cartridge().export_ram(), save);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_custom_save_path() {
+        let save_path = std::env::temp_dir().join("gameman_test_custom_save_path.sav");
+        let _ = std::fs::remove_file(&save_path);
+
+        let cart = Cartridge::with_save_path(
+            PathBuf::from("test.gb"),
+            vec![0; 0x8000],
+            RAM_BANK_SIZE,
+            Some(save_path.clone()),
+            false,
+        );
+        let mut mbc = CartridgeMBC1::new(cart);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x42);
+        mbc.write_ram(RAM_BANK_SIZE as u16 - 1, 0x24);
+        drop(mbc);
+
+        assert_eq!(
+            std::fs::metadata(&save_path).unwrap().len(),
+            RAM_BANK_SIZE as u64
+        );
+
+        let cart = Cartridge::with_save_path(
+            PathBuf::from("test.gb"),
+            vec![0; 0x8000],
+            RAM_BANK_SIZE,
+            Some(save_path.clone()),
+            false,
+        );
+        assert_eq!(cart.ram[0], 0x42);
+        assert_eq!(cart.ram[RAM_BANK_SIZE - 1], 0x24);
+        drop(cart);
+
+        std::fs::remove_file(&save_path).unwrap();
+    }
+
+    // a save file of the wrong size is reported, and left as it is
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_save_file_wrong_size() {
+        let save_path = std::env::temp_dir().join("gameman_test_save_file_wrong_size.sav");
+        std::fs::write(&save_path, vec![0x42; 100]).unwrap();
+
+        let mut cart = Cartridge::with_save_path(
+            PathBuf::from("test.gb"),
+            vec![0; 0x8000],
+            RAM_BANK_SIZE,
+            Some(save_path.clone()),
+            false,
+        );
+        assert!(cart.save_file.is_none());
+
+        let error = cart.try_load_save_file(false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        drop(cart);
+
+        assert_eq!(std::fs::read(&save_path).unwrap(), vec![0x42; 100]);
+        std::fs::remove_file(&save_path).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_flush_save() {
+        let save_path = std::env::temp_dir().join("gameman_test_flush_save.sav");
+        let _ = std::fs::remove_file(&save_path);
+
+        let cart = Cartridge::with_save_path(
+            PathBuf::from("test.gb"),
+            vec![0; 0x8000],
+            RAM_BANK_SIZE,
+            Some(save_path.clone()),
+            false,
+        );
+        let mut mbc: Box<dyn CartridgeAccess> = Box::new(CartridgeMBC1::new(cart));
+
+        // ram disabled, nothing written
+        mbc.write_ram(0x0010, 0x42);
+        assert!(!mbc.cartridge().dirty);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0010, 0x42);
+        assert!(mbc.cartridge().dirty);
+
+        mbc.flush_save().unwrap();
+        assert!(!mbc.cartridge().dirty);
+
+        let mut saved = Vec::new();
+        File::open(&save_path)
+            .unwrap()
+            .read_to_end(&mut saved)
+            .unwrap();
+        assert_eq!(saved[0x0010], 0x42);
+
+        drop(mbc);
+        std::fs::remove_file(&save_path).unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_read_only() {
+        let rom_path = std::env::temp_dir().join("gameman_test_read_only.gb");
+        let save_path = rom_path.with_extension("sav");
+        let _ = std::fs::remove_file(&save_path);
+
+        let cart =
+            Cartridge::with_save_path(rom_path.clone(), vec![0; 0x8000], RAM_BANK_SIZE, None, true);
+        let mut mbc = CartridgeMBC1::new(cart);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x42);
+        assert_eq!(mbc.read_ram(0x0000), 0x42);
+        drop(mbc);
+
+        assert!(!save_path.exists());
+
+        // an existing save is loaded, and left as it was
+        std::fs::write(&save_path, vec![0x24; RAM_BANK_SIZE]).unwrap();
+        let cart =
+            Cartridge::with_save_path(rom_path.clone(), vec![0; 0x8000], RAM_BANK_SIZE, None, true);
+        let mut mbc = CartridgeMBC1::new(cart);
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0x0000), 0x24);
+        mbc.write_ram(0x0000, 0x42);
+        drop(mbc);
+
+        assert_eq!(
+            std::fs::read(&save_path).unwrap(),
+            vec![0x24; RAM_BANK_SIZE]
+        );
+        std::fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn test_header() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"POKEMON RED");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x13;
+        rom[0x14A] = 0x01;
+        rom[0x14B] = 0x33;
+        rom[0x14D] = 0x20;
+
+        let header = Cartridge::without_save(rom.clone(), 0).header();
+        assert_eq!(header.title, "POKEMON RED");
+        assert_eq!(header.cgb_flag, 0x00);
+        assert_eq!(header.sgb_flag, 0x03);
+        assert_eq!(header.cartridge_type, 0x13);
+        assert_eq!(header.region, 0x01);
+        assert_eq!(header.licensee, 0x33);
+        assert_eq!(header.header_checksum, 0x20);
+
+        // a 16 characters title, unless the cgb flag takes the last one
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN LETTERS!");
+        assert_eq!(
+            Cartridge::without_save(rom.clone(), 0).header().title,
+            "SIXTEEN LETTERS!"
+        );
+        rom[0x143] = 0xC0;
+        let header = Cartridge::without_save(rom, 0).header();
+        assert_eq!(header.title, "SIXTEEN LETTERS");
+        assert_eq!(header.cgb_flag, 0xC0);
+
+        // nothing to parse, nothing to panic about
+        assert_eq!(Cartridge::without_save(vec![], 0).header().title, "");
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"CHECKSUMMED");
+        rom[0x147] = 0x01;
+        rom[0x4000] = 0xAB;
+
+        let header_sum: u32 = rom[0x134..=0x14C].iter().map(|byte| *byte as u32).sum();
+        rom[0x14D] = (0u32.wrapping_sub(header_sum + 0x19) & 0xFF) as u8;
+        let global_sum: u32 = rom.iter().map(|byte| *byte as u32).sum();
+        rom[0x14E] = (global_sum >> 8) as u8;
+        rom[0x14F] = global_sum as u8;
+
+        assert_eq!(verify_checksums(&rom), (true, true));
+
+        // the header checksum is part of the global one
+        rom[0x13F] = b'!';
+        assert_eq!(verify_checksums(&rom), (false, false));
+        rom[0x13F] = 0;
+
+        // the rest of the rom only matters to the global checksum
+        rom[0x4000] = 0xBA;
+        assert_eq!(verify_checksums(&rom), (true, false));
+
+        assert_eq!(verify_checksums(&rom[..0x100]), (false, false));
+        assert!(verify_checksums(&std::fs::read("tests/cpu_instrs/01-special.gb").unwrap()).0);
+    }
+
+    #[test]
+    fn test_cartridge_ram_size() {
+        // MBC1+RAM+BATTERY with 32KB
+        assert_eq!(cartridge_ram_size(0x03, 0x03), Ok(32 * 1024));
+        // MBC5+RAM+BATTERY with 128KB
+        assert_eq!(cartridge_ram_size(0x1B, 0x04), Ok(128 * 1024));
+        // ROM ONLY, whatever the header says
+        assert_eq!(cartridge_ram_size(0x00, 0x02), Ok(0));
+
+        assert!(cartridge_ram_size(0x03, 0x06).is_err());
+    }
+
+    #[test]
+    fn test_mbc2_ram_size() {
+        // the header of an mbc2 cartridge declares no ram, it's built into the mbc
+        assert_eq!(cartridge_ram_size(0x06, 0x00), Ok(512));
+        assert_eq!(cartridge_ram_size(0x05, 0x03), Ok(512));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_load_rom() {
+        assert!(load_rom("tests/cpu_instrs/01-special.gb").is_ok());
+        assert!(load_rom("tests/not_a_rom.gb").is_err());
+    }
+
+    #[test]
+    fn test_load_rom_bytes() {
+        // an mbc1 with 8KB of ram
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+
+        let mut mbc = load_rom_bytes(rom).unwrap();
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0010, 0x42);
+        assert_eq!(mbc.read_ram(0x0010), 0x42);
+
+        // nothing to save to
+        #[cfg(feature = "std")]
+        assert!(mbc.flush_save().is_ok());
+
+        assert!(load_rom_bytes(vec![0; 0x100]).is_err());
+    }
+
+    #[test]
+    fn test_short_rom_padded() {
+        // an mbc1 declaring 64KB, only 32KB long
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x4000] = 0x42;
+
+        let mut mbc = load_rom_bytes(rom).unwrap();
+        assert_eq!(mbc.cartridge().rom.len(), 0x10000);
+        assert_eq!(mbc.read_rom(0x4000), 0x42);
+
+        // bank 2 and 3 are past the end of the file
+        mbc.write_rom(0x2000, 0x02);
+        assert_eq!(mbc.read_rom(0x4000), 0xFF);
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mbc.read_rom(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn test_load_rom_bytes_with_ram() {
+        // an mbc1 with 8KB of ram
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+
+        let mut ram = vec![0; RAM_BANK_SIZE];
+        ram[0x0010] = 0x42;
+
+        let mut mbc = load_rom_bytes_with_ram(rom.clone(), ram).unwrap();
+        mbc.write_rom(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0x0010), 0x42);
+
+        assert!(load_rom_bytes_with_ram(rom, vec![0; 0x10]).is_err());
     }
 }
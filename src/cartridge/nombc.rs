@@ -21,8 +21,8 @@ impl CartridgeAccess for CartridgeNoMBC {
         self.cart.rom[addr as usize]
     }
     fn write_rom(&mut self, _addr: u16, _byte: u8) {}
-    fn read_ram(&self, _addr: u16) -> u8 {
-        0xFF
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.cart.disabled_ram_read(addr)
     }
     fn write_ram(&mut self, _addr: u16, _byte: u8) {}
 }
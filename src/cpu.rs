@@ -1,6 +1,8 @@
 #![allow(non_snake_case)]
 
+use crate::disassembler::disassemble_operation;
 use crate::mem::Memory;
+use crate::state::{SaveState, StateReader, StateWriter};
 use crate::utils::add_bytes;
 use crate::utils::add_word_with_signed;
 use crate::utils::add_words;
@@ -10,6 +12,9 @@ use crate::utils::set_bit;
 use crate::utils::sub_bytes;
 use crate::utils::swap_nibbles;
 
+use std::collections::HashSet;
+use std::io;
+
 pub const CPU_FREQ: usize = 4194304; // cpu frequency, in hz
 
 // Flags bit poisition in the F register
@@ -37,6 +42,34 @@ const REG_CPC: u16 = 11;
 const REG_M: u16 = 12;
 const REG_T: u16 = 13;
 
+// The t cycles taken by every operation, when conditional ones take the branch.
+// Unused opcodes take 4, like the NOP they run as. The CB prefix is 0,
+// its operations are in CB_OPERATION_CYCLES
+const OPERATION_CYCLES: [u8; 256] = [
+    4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4, 4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4,
+    8, 4, 12, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4, 12, 12, 8, 8, 12, 12, 12, 4, 12, 8, 8,
+    8, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4,
+    4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4,
+    4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4,
+    4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4,
+    4, 4, 4, 8, 4, 20, 12, 16, 16, 24, 16, 8, 16, 20, 16, 16, 0, 24, 24, 8, 16, 20, 12, 16, 4, 24,
+    16, 8, 16, 20, 16, 16, 4, 24, 4, 8, 16, 12, 12, 8, 4, 4, 16, 8, 16, 16, 4, 16, 4, 4, 4, 8, 16,
+    12, 12, 8, 4, 4, 16, 8, 16, 12, 8, 16, 4, 4, 4, 8, 16,
+];
+
+// The t cycles taken by every CB prefixed operation, prefix included
+const CB_OPERATION_CYCLES: [u8; 256] = [
+    8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8,
+    16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8,
+    8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8, 8, 8,
+    8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 12, 8,
+    8, 8, 8, 8, 8, 8, 12, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8,
+    16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8,
+    8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8,
+    8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+    8, 8, 8, 8, 8, 8, 16, 8, 8, 8, 8, 8, 8, 8, 16, 8,
+];
+
 pub struct Clocks {
     // todo: remove pub
     m: u32,
@@ -77,6 +110,68 @@ impl Regs {
     }
 }
 
+// the registers a debugger can look at, 8 or 16 bits wide
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub const ALL: [Register; 14] = [
+        Register::A,
+        Register::F,
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::H,
+        Register::L,
+        Register::AF,
+        Register::BC,
+        Register::DE,
+        Register::HL,
+        Register::SP,
+        Register::PC,
+    ];
+
+    // the name get_registry_value knows it by
+    fn name(self) -> &'static str {
+        match self {
+            Register::A => "A",
+            Register::F => "F",
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::AF => "AF",
+            Register::BC => "BC",
+            Register::DE => "DE",
+            Register::HL => "HL",
+            Register::SP => "SP",
+            Register::PC => "PC",
+        }
+    }
+
+    pub fn is_word(self) -> bool {
+        self.name().len() == 2
+    }
+}
+
 pub fn is_bit_set(pos: u8, value: u16) -> bool {
     value & (1u16 << pos) != 0
 }
@@ -94,6 +189,7 @@ impl Memory for Regs {
         // The F register lower nibble is always 0, you cant overwrite it.
         self.regs[addr as usize] = if addr != REG_F { byte } else { byte & 0xF0 };
     }
+    // pairs are big endian: the high register comes first, B of BC is at REG_B
     fn read_word(&mut self, addr: u16) -> u16 {
         (self.read_byte(addr + 1) as u16) | ((self.read_byte(addr) as u16) << 8)
     }
@@ -103,6 +199,20 @@ impl Memory for Regs {
     }
 }
 
+// an operation about to be executed, with the registers before it
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u8, // the byte after 0xCB for prefixed operations
+    pub prefixed: bool,
+    pub mnemonic: String,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+}
+
 pub struct CPU<M: Memory> {
     pub clks: Clocks,
     regs: Regs,
@@ -111,6 +221,37 @@ pub struct CPU<M: Memory> {
     schedule_interrupt_enable: bool, // if set to true, next step interrupt_master_enable will be set to 1
     stopped: bool,
     halted: bool, // used for HALT
+    pub breakpoints: HashSet<u16>,
+    breakpoint_reported: bool, // set after step_checked stops on a breakpoint, so the next call executes it
+    trace_hook: Option<Box<dyn FnMut(TraceEntry)>>, // called before every operation
+    illegal_opcode_hit: bool,  // the last step ran one of the unused opcodes
+    interrupt_counts: [u64; 5], // interrupts served, for profiling. Not part of the state
+}
+
+// breakpoints are debugger settings, not cpu state, so they are left alone
+impl<M: Memory + SaveState> SaveState for CPU<M> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.clks.m);
+        state.write_u32(self.clks.t);
+        state.write_bytes(&self.regs.regs);
+        state.write_bool(self.interrupt_master_enable);
+        state.write_bool(self.schedule_interrupt_enable);
+        state.write_bool(self.stopped);
+        state.write_bool(self.halted);
+        self.mmu.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.clks.m = state.read_u32()?;
+        self.clks.t = state.read_u32()?;
+        state.read_bytes(&mut self.regs.regs)?;
+        self.interrupt_master_enable = state.read_bool()?;
+        self.schedule_interrupt_enable = state.read_bool()?;
+        self.stopped = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.breakpoint_reported = false;
+        self.mmu.load_state(state)
+    }
 }
 
 impl<M: Memory> ByteStream for CPU<M> {
@@ -132,17 +273,38 @@ impl<M: Memory> CPU<M> {
             schedule_interrupt_enable: false,
             stopped: false,
             halted: false,
+            breakpoints: HashSet::new(),
+            breakpoint_reported: false,
+            trace_hook: None,
+            illegal_opcode_hit: false,
+            interrupt_counts: [0; 5],
         };
         cpu.reset();
         cpu
     }
 
-    // initalize
-    fn reset(&mut self) {
-        self.set_registry_value("SP", 0xFFFE);
-        self.set_registry_value("PC", 0x100);
+    // the registers and the io as the DMG boot rom leaves them, ready to run the cartridge
+    pub fn reset(&mut self) {
+        self.reset_registers();
+        self.mmu.post_boot();
+    }
+
+    // the registers as the DMG boot rom leaves them. The memory is left alone
+    pub fn reset_registers(&mut self) {
+        self.clks = Clocks::new();
+        self.regs = Regs::new();
+        self.set_reg(Register::AF, 0x01B0);
+        self.set_reg(Register::BC, 0x0013);
+        self.set_reg(Register::DE, 0x00D8);
+        self.set_reg(Register::HL, 0x014D);
+        self.set_reg(Register::SP, 0xFFFE);
+        self.set_reg(Register::PC, 0x0100);
+
         self.interrupt_master_enable = true;
-        //TODO: set all registry to zero. RAM as well
+        self.schedule_interrupt_enable = false;
+        self.stopped = false;
+        self.halted = false;
+        self.breakpoint_reported = false;
     }
 
     // fetches the next byte from the ram
@@ -169,6 +331,11 @@ impl<M: Memory> CPU<M> {
         let mut cycles_this_step: u8 = 0;
 
         if !self.halted {
+            if self.trace_hook.is_some() {
+                self.trace();
+            }
+            self.illegal_opcode_hit = false;
+
             let mut prefixed = false;
             let mut byte = self.read_byte();
 
@@ -200,6 +367,137 @@ impl<M: Memory> CPU<M> {
         (line_number, cycles_this_step)
     }
 
+    // calls hook with every operation before it's executed. None removes it
+    pub fn set_trace_hook(&mut self, hook: Option<Box<dyn FnMut(TraceEntry)>>) {
+        self.trace_hook = hook;
+    }
+
+    // hands the operation at PC to the trace hook
+    fn trace(&mut self) {
+        let pc = self.reg(Register::PC);
+        let (prefixed, opcode) = match self.mmu.peek_byte(pc) {
+            0xCB => (true, self.mmu.peek_byte(pc.wrapping_add(1))),
+            opcode => (false, opcode),
+        };
+
+        let entry = TraceEntry {
+            pc,
+            opcode,
+            prefixed,
+            mnemonic: disassemble_operation(&self.mmu, pc).0,
+            af: self.reg(Register::AF),
+            bc: self.reg(Register::BC),
+            de: self.reg(Register::DE),
+            hl: self.reg(Register::HL),
+            sp: self.reg(Register::SP),
+        };
+
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(entry);
+        }
+    }
+
+    // true if the last step ran an unused opcode, which doesn't exist on the real cpu
+    pub fn illegal_opcode_hit(&self) -> bool {
+        self.illegal_opcode_hit
+    }
+
+    // the unused opcodes lock up the real cpu. Here they do nothing but get reported
+    fn illegal_opcode(&mut self) {
+        self.illegal_opcode_hit = true;
+        self.regs.write_byte(REG_T, 4);
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.mmu.is_double_speed()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    // like step, but stops before executing an instruction that sits on a breakpoint.
+    // In that case no cycles pass and the returned flag is set; calling it again
+    // executes the instruction and carries on
+    pub fn step_checked(&mut self) -> (u16, u8, bool) {
+        let pc = self.get_registry_value("PC");
+
+        if !self.halted && !self.breakpoint_reported && self.breakpoints.contains(&pc) {
+            self.breakpoint_reported = true;
+            return (pc, 0, true);
+        }
+
+        self.breakpoint_reported = false;
+        let (line_number, cycles) = self.step();
+        (line_number, cycles, false)
+    }
+
+    // the state of the cpu before executing the next instruction, in the Gameboy Doctor log format
+    // eg: A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    pub fn trace_state(&mut self) -> String {
+        let pc = self.get_registry_value("PC");
+        let pcmem: Vec<String> = (0..4)
+            .map(|i| format!("{:02X}", self.mmu.peek_byte(pc.wrapping_add(i))))
+            .collect();
+
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            self.get_registry_value("A"),
+            self.get_registry_value("F"),
+            self.get_registry_value("B"),
+            self.get_registry_value("C"),
+            self.get_registry_value("D"),
+            self.get_registry_value("E"),
+            self.get_registry_value("H"),
+            self.get_registry_value("L"),
+            self.get_registry_value("SP"),
+            pc,
+            pcmem.join(",")
+        )
+    }
+
+    // decodes count operations starting from start, without executing them.
+    // Returns the address of every operation along with its text
+    pub fn disassemble(&self, start: u16, count: usize) -> Vec<(u16, String)> {
+        let mut operations = Vec::with_capacity(count);
+        let mut addr = start;
+
+        for _ in 0..count {
+            let (text, length) = disassemble_operation(&self.mmu, addr);
+            operations.push((addr, text));
+            addr = addr.wrapping_add(length);
+        }
+
+        operations
+    }
+
+    // the most t cycles the next step can take: the next operation taking its branch,
+    // followed by an interrupt being handled
+    pub fn next_step_max_cycles(&mut self) -> u8 {
+        let pc = self.get_registry_value("PC");
+
+        let operation_cycles = if self.halted {
+            4
+        } else {
+            match self.mmu.peek_byte(pc) {
+                0xCB => CB_OPERATION_CYCLES[self.mmu.peek_byte(pc.wrapping_add(1)) as usize],
+                opcode => OPERATION_CYCLES[opcode as usize],
+            }
+        };
+
+        let interrupt_cycles = if self.interrupt_master_enable || self.schedule_interrupt_enable {
+            12
+        } else {
+            0
+        };
+
+        operation_cycles + interrupt_cycles
+    }
+
     fn registry_name_to_index(&mut self, registry: &str) -> u16 {
         match registry {
             "A" | "AF" => 0,
@@ -238,8 +536,16 @@ impl<M: Memory> CPU<M> {
         }
     }
 
+    pub fn reg(&mut self, register: Register) -> u16 {
+        self.get_registry_value(register.name())
+    }
+
+    // 8 bit registers take the low byte of value. The low nibble of F is always 0
+    pub fn set_reg(&mut self, register: Register, value: u16) {
+        self.set_registry_value(register.name(), value)
+    }
+
     pub fn store_result(&mut self, into: &str, value: u16, is_byte: bool) {
-        info!("Storing into {} value 0x{:x}", into, value);
         let addr: u16 = match into {
             "BC" | "DE" | "HL" | "PC" | "SP" | "AF" | "A" | "B" | "C" | "D" | "E" | "H" | "L" => {
                 return self.set_registry_value(into, value);
@@ -276,7 +582,6 @@ impl<M: Memory> CPU<M> {
             "(a8)" => {
                 let addr = 0xFF00 + u16::from(self.fetch_next_byte());
                 u16::from(self.mmu.read_byte(addr))
-                //                info!("Reading input from 0x{:x} --> 0b{:b}", addr, res);
             }
             "(C)" => {
                 let addr = 0xFF00 + self.get_registry_value("C");
@@ -298,14 +603,14 @@ impl<M: Memory> CPU<M> {
 
     pub fn push(&mut self, value: u16) {
         let sp = self.get_registry_value("SP");
-        self.set_registry_value("SP", sp - 2);
+        self.set_registry_value("SP", sp.wrapping_sub(2));
         self.store_result("(SP)", value, false);
     }
 
     pub fn pop(&mut self) -> u16 {
         let sp = self.get_registry_value("SP");
         let value = self.mmu.read_word(sp);
-        self.set_registry_value("SP", sp + 2);
+        self.set_registry_value("SP", sp.wrapping_add(2));
         value
     }
 
@@ -317,11 +622,20 @@ impl<M: Memory> CPU<M> {
         self.mmu.tick(cycles);
     }
 
-    // return IE & IF
+    // return IE & IF, the 5 interrupt bits only
     fn interrupts_to_handle(&mut self) -> u8 {
         let interrupt_enable = self.mmu.read_byte(0xFFFF);
         let interrupt_flags = self.mmu.read_byte(0xFF0F);
-        interrupt_enable & interrupt_flags
+        interrupt_enable & interrupt_flags & 0x1F
+    }
+
+    // how many times each interrupt was served: vblank, stat, timer, serial and joypad
+    pub fn interrupt_counts(&self) -> [u64; 5] {
+        self.interrupt_counts
+    }
+
+    pub fn reset_interrupt_counts(&mut self) {
+        self.interrupt_counts = [0; 5];
     }
 
     fn handle_interrupts(&mut self) {
@@ -344,51 +658,15 @@ impl<M: Memory> CPU<M> {
 
             interrupt_cycles_t = 12;
 
+            // the lowest bit has priority, vblank first and joypad last.
+            // Turn its flag off cause we are handling it now
+            let interrupt = interrupts.trailing_zeros() as u8;
             let interrupt_flags = self.mmu.read_byte(0xFF0F);
+            self.mmu
+                .write_byte(0xFF0F, reset_bit(interrupt, interrupt_flags) as u8);
+            self.interrupt_counts[interrupt as usize] += 1;
 
-            // vblank
-            if (interrupts & 0x1) != 0 {
-                // turn interrupt flag off cause we are handling it now
-                self.mmu
-                    .write_byte(0xFF0F, reset_bit(0, interrupt_flags) as u8);
-
-                self.set_registry_value("PC", 0x0040);
-            }
-            // lcd status triggers
-            else if (interrupts & 0x2) != 0 {
-                self.mmu
-                    .write_byte(0xFF0F, reset_bit(1, interrupt_flags) as u8);
-
-                self.set_registry_value("PC", 0x0048);
-            }
-
-            // timer
-            if (interrupts & 0x4) != 0 {
-                println!("Handling timer");
-
-                self.mmu
-                    .write_byte(0xFF0F, reset_bit(2, interrupt_flags) as u8);
-
-                self.set_registry_value("PC", 0x0050);
-            }
-            // serial
-            else if (interrupts & 0b1000) != 0 {
-                println!("Handling serial");
-
-                self.mmu
-                    .write_byte(0xFF0F, reset_bit(3, interrupt_flags) as u8);
-
-                self.set_registry_value("PC", 0x0058);
-            }
-            // joypad
-            else if (interrupts & 0b10000) != 0 {
-                println!("Handling joypad");
-
-                self.mmu
-                    .write_byte(0xFF0F, reset_bit(4, interrupt_flags) as u8);
-
-                self.set_registry_value("PC", 0x0060);
-            }
+            self.set_registry_value("PC", 0x0040 + 8 * interrupt as u16);
         }
 
         // todo: on button press resume from stop
@@ -1081,7 +1359,10 @@ impl<M: Memory> CPU<M> {
     }
 
     fn x10(&mut self) {
-        self.stopped = true;
+        // a prepared speed switch happens instead of stopping
+        if !self.mmu.switch_speed() {
+            self.stopped = true;
+        }
 
         self.regs.write_byte(REG_T, 4);
     }
@@ -2967,7 +3248,9 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 16);
     }
 
-    fn xD3(&mut self) {}
+    fn xD3(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xD4(&mut self) {
         let op1 = self.get_operand_value("a16");
@@ -3042,7 +3325,9 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 16);
     }
 
-    fn xDB(&mut self) {}
+    fn xDB(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xDC(&mut self) {
         let op1 = self.get_operand_value("a16");
@@ -3060,7 +3345,9 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 24);
     }
 
-    fn xDD(&mut self) {}
+    fn xDD(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xDE(&mut self) {
         let op1 = self.get_operand_value("A");
@@ -3099,9 +3386,13 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 8);
     }
 
-    fn xE3(&mut self) {}
+    fn xE3(&mut self) {
+        self.illegal_opcode();
+    }
 
-    fn xE4(&mut self) {}
+    fn xE4(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xE5(&mut self) {
         let op1 = self.get_operand_value("HL");
@@ -3152,11 +3443,17 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 16);
     }
 
-    fn xEB(&mut self) {}
+    fn xEB(&mut self) {
+        self.illegal_opcode();
+    }
 
-    fn xEC(&mut self) {}
+    fn xEC(&mut self) {
+        self.illegal_opcode();
+    }
 
-    fn xED(&mut self) {}
+    fn xED(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xEE(&mut self) {
         let op1 = self.get_operand_value("A");
@@ -3201,7 +3498,9 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 4);
     }
 
-    fn xF4(&mut self) {}
+    fn xF4(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xF5(&mut self) {
         let op1 = self.get_operand_value("AF");
@@ -3259,9 +3558,13 @@ impl<M: Memory> CPU<M> {
         self.regs.write_byte(REG_T, 4);
     }
 
-    fn xFC(&mut self) {}
+    fn xFC(&mut self) {
+        self.illegal_opcode();
+    }
 
-    fn xFD(&mut self) {}
+    fn xFD(&mut self) {
+        self.illegal_opcode();
+    }
 
     fn xFE(&mut self) {
         let op1 = self.get_operand_value("A");
@@ -5676,6 +5979,8 @@ impl<M: Memory> CPU<M> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     struct DummyMMU {
         values: [u8; 65536],
@@ -5694,6 +5999,9 @@ mod tests {
         fn read_byte(&mut self, addr: u16) -> u8 {
             self.values[addr as usize]
         }
+        fn peek_byte(&self, addr: u16) -> u8 {
+            self.values[addr as usize]
+        }
         fn write_byte(&mut self, addr: u16, byte: u8) {
             self.values[addr as usize] = byte;
         }
@@ -5706,14 +6014,15 @@ mod tests {
         assert_eq!(clks.m, 0);
         assert_eq!(clks.t, 0);
 
-        assert_eq!(regs.read_byte(REG_A), 0);
-        assert_eq!(regs.read_byte(REG_B), 0);
-        assert_eq!(regs.read_byte(REG_C), 0);
-        assert_eq!(regs.read_byte(REG_D), 0);
-        assert_eq!(regs.read_byte(REG_E), 0);
-        assert_eq!(regs.read_byte(REG_H), 0);
-        assert_eq!(regs.read_byte(REG_L), 0);
-        assert_eq!(regs.read_byte(REG_F), 0);
+        // as the boot rom leaves them
+        assert_eq!(regs.read_byte(REG_A), 0x01);
+        assert_eq!(regs.read_byte(REG_B), 0x00);
+        assert_eq!(regs.read_byte(REG_C), 0x13);
+        assert_eq!(regs.read_byte(REG_D), 0x00);
+        assert_eq!(regs.read_byte(REG_E), 0xD8);
+        assert_eq!(regs.read_byte(REG_H), 0x01);
+        assert_eq!(regs.read_byte(REG_L), 0x4D);
+        assert_eq!(regs.read_byte(REG_F), 0xB0);
         assert_eq!(regs.read_word(REG_PC), 0x100);
         assert_eq!(regs.read_word(REG_SP), 0xFFFE);
         assert_eq!(regs.read_byte(REG_M), 0);
@@ -5733,6 +6042,257 @@ mod tests {
         assert!(!c);
     }
 
+    #[test]
+    fn test_trace_state() {
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        cpu.set_registry_value("AF", 0x01B0);
+        cpu.set_registry_value("BC", 0x0013);
+        cpu.set_registry_value("HL", 0x014D);
+        cpu.mmu.values[0x100] = 0x00;
+        cpu.mmu.values[0x101] = 0xC3;
+        cpu.mmu.values[0x102] = 0x13;
+        cpu.mmu.values[0x103] = 0x02;
+
+        assert_eq!(
+            cpu.trace_state(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        let program = [
+            0x3E, 0x42, // LD A,d8
+            0xCB, 0x37, // SWAP A
+            0x00, // NOP
+        ];
+        cpu.mmu.values[0x100..0x105].copy_from_slice(&program);
+
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let hook_entries = entries.clone();
+        cpu.set_trace_hook(Some(Box::new(move |entry| {
+            hook_entries.borrow_mut().push(entry)
+        })));
+
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        let entries = entries.borrow();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].pc, 0x100);
+        assert_eq!(entries[0].opcode, 0x3E);
+        assert_eq!(entries[0].mnemonic, "LD A,$42");
+        assert_eq!(entries[0].af, 0x01B0);
+        assert_eq!(entries[1].pc, 0x102);
+        assert!(entries[1].prefixed);
+        assert_eq!(entries[1].opcode, 0x37);
+        assert_eq!(entries[1].af >> 8, 0x42);
+        assert_eq!(entries[2].af >> 8, 0x24);
+
+        // without a hook nothing is collected
+        cpu.set_trace_hook(None);
+        cpu.step();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        let program = [
+            0x01, 0x34, 0x12, // LD BC,d16
+            0x3E, 0x42, // LD A,d8
+            0x20, 0xFE, // JR NZ,r8
+            0xCB, 0x7C, // BIT 7,H
+            0xE0, 0x44, // LDH (a8),A
+            0xF8, 0x05, // LD HL,SP+r8
+            0xD3, // unused
+            0x00, // NOP
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mmu.values[0x200 + i] = *byte;
+        }
+
+        let disassembled = cpu.disassemble(0x200, 8);
+
+        assert_eq!(
+            disassembled,
+            vec![
+                (0x200, String::from("LD BC,$1234")),
+                (0x203, String::from("LD A,$42")),
+                (0x205, String::from("JR NZ,-$02")),
+                (0x207, String::from("BIT 7,H")),
+                (0x209, String::from("LDH ($FF44),A")),
+                (0x20B, String::from("LD HL,SP+$05")),
+                (0x20D, String::from("DB $D3")),
+                (0x20E, String::from("NOP")),
+            ]
+        );
+
+        // cpu state is untouched
+        assert_eq!(cpu.get_registry_value("PC"), 0x100);
+    }
+
+    // the byte after STOP isn't an operation
+    #[test]
+    fn test_disassemble_stop() {
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        let program = [
+            0x10, 0x00, // STOP 0
+            0x3E, 0x42, // LD A,d8
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            cpu.mmu.values[0x200 + i] = *byte;
+        }
+
+        assert_eq!(
+            cpu.disassemble(0x200, 2),
+            vec![
+                (0x200, String::from("STOP 0")),
+                (0x202, String::from("LD A,$42")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // memory is all NOPs
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        cpu.add_breakpoint(0x102);
+
+        assert_eq!(cpu.step_checked(), (0x100, 4, false));
+        assert_eq!(cpu.step_checked(), (0x101, 4, false));
+        assert_eq!(cpu.step_checked(), (0x102, 0, true));
+        assert_eq!(cpu.get_registry_value("PC"), 0x102);
+
+        // resuming executes the instruction under the breakpoint
+        assert_eq!(cpu.step_checked(), (0x102, 4, false));
+        assert_eq!(cpu.step_checked(), (0x103, 4, false));
+
+        cpu.remove_breakpoint(0x102);
+        cpu.set_registry_value("PC", 0x102);
+        assert_eq!(cpu.step_checked(), (0x102, 4, false));
+    }
+
+    #[test]
+    fn test_daa() {
+        // (A, N, H, C) before, (A, Z, C) after. N is kept, H always cleared
+        let cases = [
+            ((0x00, false, false, false), (0x00, true, false)),
+            ((0x0A, false, false, false), (0x10, false, false)),
+            ((0x3F, false, false, false), (0x45, false, false)),
+            ((0x99, false, false, false), (0x99, false, false)),
+            ((0x9A, false, false, false), (0x00, true, true)),
+            ((0xA0, false, false, false), (0x00, true, true)),
+            ((0x15, false, true, false), (0x1B, false, false)),
+            ((0x25, false, false, true), (0x85, false, true)),
+            ((0x9A, false, true, true), (0x00, true, true)),
+            ((0x45, true, false, false), (0x45, false, false)),
+            ((0x10, true, true, false), (0x0A, false, false)),
+            ((0x90, true, false, true), (0x30, false, true)),
+            ((0x00, true, true, true), (0x9A, false, true)),
+            ((0x66, true, true, true), (0x00, true, true)),
+        ];
+
+        for &((a, n, h, c), (expected_a, expected_z, expected_c)) in cases.iter() {
+            let mut cpu = CPU::new(DummyMMU::new());
+
+            cpu.set_registry_value("PC", 0x200);
+            cpu.mmu.values[0x200] = 0x27;
+            cpu.set_registry_value("A", a);
+            cpu.regs.set_flags(false, n, h, c);
+
+            cpu.step();
+
+            assert_eq!(
+                cpu.get_registry_value("A"),
+                expected_a,
+                "A after DAA of {:02X} n={} h={} c={}",
+                a,
+                n,
+                h,
+                c
+            );
+            assert_eq!(
+                cpu.regs.get_flags(),
+                (expected_z, n, false, expected_c),
+                "flags after DAA of {:02X} n={} h={} c={}",
+                a,
+                n,
+                h,
+                c
+            );
+        }
+    }
+
+    // runs opcode with SP=sp and operand r8, returns the result register and the flags
+    fn run_sp_plus_r8(
+        opcode: u8,
+        result: &str,
+        sp: u16,
+        r8: u8,
+    ) -> (u16, (bool, bool, bool, bool)) {
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        cpu.set_registry_value("PC", 0x200);
+        cpu.mmu.values[0x200] = opcode;
+        cpu.mmu.values[0x201] = r8;
+        cpu.set_registry_value("SP", sp);
+        cpu.regs.set_flags(true, true, false, false);
+
+        cpu.step();
+
+        (cpu.get_registry_value(result), cpu.regs.get_flags())
+    }
+
+    #[test]
+    fn test_add_sp_r8_flags() {
+        // half carry only, from bit 3 of the low byte
+        assert_eq!(
+            run_sp_plus_r8(0xE8, "SP", 0x120F, 0x01),
+            (0x1210, (false, false, true, false))
+        );
+        // carry only, from bit 7 of the low byte
+        assert_eq!(
+            run_sp_plus_r8(0xE8, "SP", 0x12F0, 0x10),
+            (0x1300, (false, false, false, true))
+        );
+        // negative operands are added as unsigned bytes for the flags
+        assert_eq!(
+            run_sp_plus_r8(0xE8, "SP", 0x0001, 0xFF),
+            (0x0000, (false, false, true, true))
+        );
+        assert_eq!(
+            run_sp_plus_r8(0xE8, "SP", 0x1200, 0xFF),
+            (0x11FF, (false, false, false, false))
+        );
+    }
+
+    #[test]
+    fn test_ld_hl_sp_r8_flags() {
+        assert_eq!(
+            run_sp_plus_r8(0xF8, "HL", 0x120F, 0x01),
+            (0x1210, (false, false, true, false))
+        );
+        assert_eq!(
+            run_sp_plus_r8(0xF8, "HL", 0x12F0, 0x10),
+            (0x1300, (false, false, false, true))
+        );
+        assert_eq!(
+            run_sp_plus_r8(0xF8, "HL", 0x0001, 0xFF),
+            (0x0000, (false, false, true, true))
+        );
+        assert_eq!(
+            run_sp_plus_r8(0xF8, "HL", 0x1200, 0xFF),
+            (0x11FF, (false, false, false, false))
+        );
+    }
+
     #[test]
     fn test_jr_positive() {
         let mut cpu = CPU::new(DummyMMU::new());
@@ -5772,6 +6332,100 @@ mod tests {
         assert_eq!(cpu.pop(), 0xF000);
     }
 
+    // the stack is little endian like the rest of memory, the register pairs aren't
+    #[test]
+    fn test_push_byte_order() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.set_registry_value("SP", 0xD000);
+
+        cpu.push(0x1234);
+        assert_eq!(cpu.get_registry_value("SP"), 0xCFFE);
+        assert_eq!(cpu.mmu.read_byte(0xCFFE), 0x34);
+        assert_eq!(cpu.mmu.read_byte(0xCFFF), 0x12);
+        assert_eq!(cpu.pop(), 0x1234);
+        assert_eq!(cpu.get_registry_value("SP"), 0xD000);
+
+        cpu.set_registry_value("BC", 0x1234);
+        assert_eq!(cpu.regs.read_byte(REG_B), 0x12);
+        assert_eq!(cpu.regs.read_byte(REG_C), 0x34);
+
+        // across the end of the address space
+        cpu.set_registry_value("SP", 0x0001);
+        cpu.push(0xABCD);
+        assert_eq!(cpu.mmu.read_byte(0xFFFF), 0xCD);
+        assert_eq!(cpu.mmu.read_byte(0x0000), 0xAB);
+        assert_eq!(cpu.pop(), 0xABCD);
+        assert_eq!(cpu.get_registry_value("SP"), 0x0001);
+    }
+
+    // EI enables the interrupts only after the next instruction
+    #[test]
+    fn test_ei_delay() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.interrupt_master_enable = false;
+        cpu.set_registry_value("SP", 0xD000);
+
+        // EI, NOP, NOP with a vblank interrupt pending
+        cpu.mmu.write_byte(0x100, 0xFB);
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+
+        cpu.step();
+        assert_eq!(cpu.get_registry_value("PC"), 0x101);
+
+        // the NOP runs, then the interrupt is taken
+        cpu.step();
+        assert_eq!(cpu.get_registry_value("PC"), 0x40);
+        assert_eq!(cpu.pop(), 0x102);
+        assert_eq!(cpu.mmu.read_byte(0xFF0F), 0x00);
+    }
+
+    // RETI enables the interrupts right away
+    #[test]
+    fn test_reti_no_delay() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.interrupt_master_enable = false;
+        cpu.set_registry_value("SP", 0xD000);
+        cpu.push(0x200);
+
+        cpu.mmu.write_byte(0x100, 0xD9);
+        cpu.mmu.write_byte(0xFFFF, 0x01);
+        cpu.mmu.write_byte(0xFF0F, 0x01);
+
+        cpu.step();
+        assert_eq!(cpu.get_registry_value("PC"), 0x40);
+        assert_eq!(cpu.pop(), 0x200);
+    }
+
+    #[test]
+    fn test_interrupt_counts() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.set_registry_value("SP", 0xD000);
+
+        // the timer handler only returns
+        cpu.mmu.write_byte(0x50, 0xD9);
+        cpu.mmu.write_byte(0xFFFF, 0x1F);
+
+        for _ in 0..3 {
+            cpu.mmu.write_byte(0xFF0F, 0x04);
+            cpu.step();
+            assert_eq!(cpu.get_registry_value("PC"), 0x50);
+            cpu.step();
+        }
+        assert_eq!(cpu.interrupt_counts(), [0, 0, 3, 0, 0]);
+
+        // vblank goes first, the timer waits for its turn
+        cpu.interrupt_master_enable = true;
+        cpu.mmu.write_byte(0xFF0F, 0x05);
+        cpu.step();
+        assert_eq!(cpu.get_registry_value("PC"), 0x40);
+        assert_eq!(cpu.mmu.read_byte(0xFF0F), 0x04);
+        assert_eq!(cpu.interrupt_counts(), [1, 0, 3, 0, 0]);
+
+        cpu.reset_interrupt_counts();
+        assert_eq!(cpu.interrupt_counts(), [0; 5]);
+    }
+
     #[test]
     fn test_pop_af() {
         let mut cpu = CPU::new(DummyMMU::new());
@@ -5789,4 +6443,171 @@ mod tests {
         // lower nibble of F must be untouched
         assert_eq!(cpu.get_registry_value("F"), 0xF0)
     }
+
+    // the t cycles taken by one step running opcode (CB prefixed if cb), with the given flags
+    fn opcode_cycles(opcode: u8, cb: bool, flags: u8) -> u8 {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.regs.write_byte(REG_F, flags);
+        // room to both push and pop
+        cpu.set_registry_value("SP", 0xD000);
+
+        if cb {
+            cpu.mmu.write_byte(0x100, 0xCB);
+            cpu.mmu.write_byte(0x101, opcode);
+        } else {
+            cpu.mmu.write_byte(0x100, opcode);
+        }
+
+        cpu.step().1
+    }
+
+    // the t cycles of conditional operations not taking the branch
+    fn not_taken_cycles(opcode: u8) -> Option<u8> {
+        match opcode {
+            0x20 | 0x28 | 0x30 | 0x38 => Some(8),  // JR cc
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(8),  // RET cc
+            0xC2 | 0xCA | 0xD2 | 0xDA => Some(12), // JP cc
+            0xC4 | 0xCC | 0xD4 | 0xDC => Some(12), // CALL cc
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_operation_cycles() {
+        for opcode in 0..=0xFFu8 {
+            let expected = OPERATION_CYCLES[opcode as usize];
+            // the prefix
+            if opcode == 0xCB {
+                continue;
+            }
+
+            // one of the two flag settings takes any branch, the other doesn't
+            let all_clear = opcode_cycles(opcode, false, 0x00);
+            let all_set = opcode_cycles(opcode, false, 0xF0);
+            let taken = all_clear.max(all_set);
+            let not_taken = all_clear.min(all_set);
+
+            assert_eq!(taken, expected, "opcode {:02X}", opcode);
+            assert_eq!(
+                not_taken,
+                not_taken_cycles(opcode).unwrap_or(expected),
+                "opcode {:02X} not taken",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_cb_operation_cycles() {
+        for opcode in 0..=0xFFu8 {
+            assert_eq!(
+                opcode_cycles(opcode, true, 0x00),
+                CB_OPERATION_CYCLES[opcode as usize],
+                "opcode CB {:02X}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut cpu = CPU::new(DummyMMU::new());
+        cpu.mmu.write_byte(0x100, 0xD3);
+
+        assert_eq!(cpu.step(), (0x100, 4));
+        assert!(cpu.illegal_opcode_hit());
+        assert_eq!(cpu.reg(Register::PC), 0x101);
+
+        // a NOP clears it
+        cpu.step();
+        assert!(!cpu.illegal_opcode_hit());
+    }
+
+    const UNUSED_OPCODES: [u8; 11] = [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ];
+
+    // the unused opcodes are exactly the ones reported, the cb table has none
+    #[test]
+    fn test_opcode_coverage() {
+        for opcode in 0..=0xFFu8 {
+            if opcode == 0xCB {
+                continue;
+            }
+
+            let mut cpu = CPU::new(DummyMMU::new());
+            cpu.set_registry_value("SP", 0xD000);
+            cpu.mmu.write_byte(0x100, opcode);
+            cpu.step();
+            assert_eq!(
+                cpu.illegal_opcode_hit(),
+                UNUSED_OPCODES.contains(&opcode),
+                "opcode {:02X}",
+                opcode
+            );
+
+            let mut cpu = CPU::new(DummyMMU::new());
+            cpu.mmu.write_byte(0x100, 0xCB);
+            cpu.mmu.write_byte(0x101, opcode);
+            cpu.step();
+            assert!(!cpu.illegal_opcode_hit(), "opcode CB {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_cb_hl_cycles() {
+        // operations on (HL) pay for the memory access, BIT only reads it
+        assert_eq!(opcode_cycles(0x36, true, 0x00), 16); // SWAP (HL)
+        assert_eq!(opcode_cycles(0x46, true, 0x00), 12); // BIT 0,(HL)
+        assert_eq!(opcode_cycles(0x30, true, 0x00), 8); // SWAP B
+    }
+
+    #[test]
+    fn test_jr_nz_cycles() {
+        assert_eq!(opcode_cycles(0x20, false, 0x00), 12);
+        assert_eq!(opcode_cycles(0x20, false, 0x80), 8);
+    }
+
+    #[test]
+    fn test_call_z_cycles() {
+        assert_eq!(opcode_cycles(0xCC, false, 0x80), 24);
+        assert_eq!(opcode_cycles(0xCC, false, 0x00), 12);
+    }
+
+    #[test]
+    fn test_ret_nc_cycles() {
+        assert_eq!(opcode_cycles(0xD0, false, 0x00), 20);
+        assert_eq!(opcode_cycles(0xD0, false, 0x10), 8);
+    }
+
+    #[test]
+    fn test_register_api() {
+        let mut cpu = CPU::new(DummyMMU::new());
+
+        for (i, &register) in Register::ALL.iter().enumerate() {
+            // F keeps the value, its low nibble is 0 already
+            let value = if register.is_word() {
+                0x1230 + (i as u16) * 0x1010
+            } else {
+                0x10 * i as u16
+            };
+            cpu.set_reg(register, value);
+            assert_eq!(cpu.reg(register), value, "{:?}", register);
+        }
+
+        // the halves of a pair
+        cpu.set_reg(Register::HL, 0xABCD);
+        assert_eq!(cpu.reg(Register::H), 0xAB);
+        assert_eq!(cpu.reg(Register::L), 0xCD);
+
+        // the low nibble of F can't be set
+        cpu.set_reg(Register::F, 0xFF);
+        assert_eq!(cpu.reg(Register::F), 0xF0);
+        cpu.set_reg(Register::AF, 0x12FF);
+        assert_eq!(cpu.reg(Register::AF), 0x12F0);
+
+        // 8 bit registers only take the low byte
+        cpu.set_reg(Register::B, 0x1234);
+        assert_eq!(cpu.reg(Register::B), 0x34);
+    }
 }
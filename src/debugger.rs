@@ -0,0 +1,70 @@
+// what the user can ask the debugger to do, one command per line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugCommand {
+    Step,            // s: runs an instruction
+    Continue,        // c: runs until a breakpoint
+    Breakpoint(u16), // b <addr>: stops before running the instruction at addr
+    Registers,       // r: shows the registers
+    Memory(u16),     // m <addr>: shows the memory from addr on
+    Quit,            // q
+}
+
+// reads a command, addresses are in hex with or without the 0x
+pub fn parse_command(input: &str) -> Result<DebugCommand, String> {
+    let mut words = input.split_whitespace();
+    let command = words.next().ok_or("no command given")?;
+    let argument = words.next();
+
+    if words.next().is_some() {
+        return Err(format!("too many arguments for {}", command));
+    }
+
+    match (command, argument) {
+        ("s", None) => Ok(DebugCommand::Step),
+        ("c", None) => Ok(DebugCommand::Continue),
+        ("r", None) => Ok(DebugCommand::Registers),
+        ("q", None) => Ok(DebugCommand::Quit),
+        ("b", Some(addr)) => Ok(DebugCommand::Breakpoint(parse_address(addr)?)),
+        ("m", Some(addr)) => Ok(DebugCommand::Memory(parse_address(addr)?)),
+        ("b", None) | ("m", None) => Err(format!("{} needs an address", command)),
+        ("s", Some(_)) | ("c", Some(_)) | ("r", Some(_)) | ("q", Some(_)) => {
+            Err(format!("{} takes no arguments", command))
+        }
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not an address", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("s"), Ok(DebugCommand::Step));
+        assert_eq!(parse_command("c\n"), Ok(DebugCommand::Continue));
+        assert_eq!(parse_command(" r "), Ok(DebugCommand::Registers));
+        assert_eq!(parse_command("q"), Ok(DebugCommand::Quit));
+        assert_eq!(
+            parse_command("b 0150"),
+            Ok(DebugCommand::Breakpoint(0x0150))
+        );
+        assert_eq!(
+            parse_command("b 0xC000"),
+            Ok(DebugCommand::Breakpoint(0xC000))
+        );
+        assert_eq!(parse_command("m ff44"), Ok(DebugCommand::Memory(0xFF44)));
+
+        assert!(parse_command("").is_err());
+        assert!(parse_command("x").is_err());
+        assert!(parse_command("b").is_err());
+        assert!(parse_command("m 10000").is_err());
+        assert!(parse_command("m zz").is_err());
+        assert!(parse_command("s 10").is_err());
+        assert!(parse_command("b 10 20").is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use crate::mem::Memory;
+
+// Mnemonics of the unprefixed operations, operands are named like in the cpu:
+// d8/d16 immediate data, a8/a16 addresses, r8 signed offset. "-" is an unused opcode
+const OPERATIONS: [&str; 256] = [
+    "NOP",
+    "LD BC,d16",
+    "LD (BC),A",
+    "INC BC",
+    "INC B",
+    "DEC B",
+    "LD B,d8",
+    "RLCA",
+    "LD (a16),SP",
+    "ADD HL,BC",
+    "LD A,(BC)",
+    "DEC BC",
+    "INC C",
+    "DEC C",
+    "LD C,d8",
+    "RRCA",
+    "STOP 0",
+    "LD DE,d16",
+    "LD (DE),A",
+    "INC DE",
+    "INC D",
+    "DEC D",
+    "LD D,d8",
+    "RLA",
+    "JR r8",
+    "ADD HL,DE",
+    "LD A,(DE)",
+    "DEC DE",
+    "INC E",
+    "DEC E",
+    "LD E,d8",
+    "RRA",
+    "JR NZ,r8",
+    "LD HL,d16",
+    "LD (HL+),A",
+    "INC HL",
+    "INC H",
+    "DEC H",
+    "LD H,d8",
+    "DAA",
+    "JR Z,r8",
+    "ADD HL,HL",
+    "LD A,(HL+)",
+    "DEC HL",
+    "INC L",
+    "DEC L",
+    "LD L,d8",
+    "CPL",
+    "JR NC,r8",
+    "LD SP,d16",
+    "LD (HL-),A",
+    "INC SP",
+    "INC (HL)",
+    "DEC (HL)",
+    "LD (HL),d8",
+    "SCF",
+    "JR C,r8",
+    "ADD HL,SP",
+    "LD A,(HL-)",
+    "DEC SP",
+    "INC A",
+    "DEC A",
+    "LD A,d8",
+    "CCF",
+    "LD B,B",
+    "LD B,C",
+    "LD B,D",
+    "LD B,E",
+    "LD B,H",
+    "LD B,L",
+    "LD B,(HL)",
+    "LD B,A",
+    "LD C,B",
+    "LD C,C",
+    "LD C,D",
+    "LD C,E",
+    "LD C,H",
+    "LD C,L",
+    "LD C,(HL)",
+    "LD C,A",
+    "LD D,B",
+    "LD D,C",
+    "LD D,D",
+    "LD D,E",
+    "LD D,H",
+    "LD D,L",
+    "LD D,(HL)",
+    "LD D,A",
+    "LD E,B",
+    "LD E,C",
+    "LD E,D",
+    "LD E,E",
+    "LD E,H",
+    "LD E,L",
+    "LD E,(HL)",
+    "LD E,A",
+    "LD H,B",
+    "LD H,C",
+    "LD H,D",
+    "LD H,E",
+    "LD H,H",
+    "LD H,L",
+    "LD H,(HL)",
+    "LD H,A",
+    "LD L,B",
+    "LD L,C",
+    "LD L,D",
+    "LD L,E",
+    "LD L,H",
+    "LD L,L",
+    "LD L,(HL)",
+    "LD L,A",
+    "LD (HL),B",
+    "LD (HL),C",
+    "LD (HL),D",
+    "LD (HL),E",
+    "LD (HL),H",
+    "LD (HL),L",
+    "HALT",
+    "LD (HL),A",
+    "LD A,B",
+    "LD A,C",
+    "LD A,D",
+    "LD A,E",
+    "LD A,H",
+    "LD A,L",
+    "LD A,(HL)",
+    "LD A,A",
+    "ADD A,B",
+    "ADD A,C",
+    "ADD A,D",
+    "ADD A,E",
+    "ADD A,H",
+    "ADD A,L",
+    "ADD A,(HL)",
+    "ADD A,A",
+    "ADC A,B",
+    "ADC A,C",
+    "ADC A,D",
+    "ADC A,E",
+    "ADC A,H",
+    "ADC A,L",
+    "ADC A,(HL)",
+    "ADC A,A",
+    "SUB B",
+    "SUB C",
+    "SUB D",
+    "SUB E",
+    "SUB H",
+    "SUB L",
+    "SUB (HL)",
+    "SUB A",
+    "SBC A,B",
+    "SBC A,C",
+    "SBC A,D",
+    "SBC A,E",
+    "SBC A,H",
+    "SBC A,L",
+    "SBC A,(HL)",
+    "SBC A,A",
+    "AND B",
+    "AND C",
+    "AND D",
+    "AND E",
+    "AND H",
+    "AND L",
+    "AND (HL)",
+    "AND A",
+    "XOR B",
+    "XOR C",
+    "XOR D",
+    "XOR E",
+    "XOR H",
+    "XOR L",
+    "XOR (HL)",
+    "XOR A",
+    "OR B",
+    "OR C",
+    "OR D",
+    "OR E",
+    "OR H",
+    "OR L",
+    "OR (HL)",
+    "OR A",
+    "CP B",
+    "CP C",
+    "CP D",
+    "CP E",
+    "CP H",
+    "CP L",
+    "CP (HL)",
+    "CP A",
+    "RET NZ",
+    "POP BC",
+    "JP NZ,a16",
+    "JP a16",
+    "CALL NZ,a16",
+    "PUSH BC",
+    "ADD A,d8",
+    "RST 00H",
+    "RET Z",
+    "RET",
+    "JP Z,a16",
+    "PREFIX CB",
+    "CALL Z,a16",
+    "CALL a16",
+    "ADC A,d8",
+    "RST 08H",
+    "RET NC",
+    "POP DE",
+    "JP NC,a16",
+    "-",
+    "CALL NC,a16",
+    "PUSH DE",
+    "SUB d8",
+    "RST 10H",
+    "RET C",
+    "RETI",
+    "JP C,a16",
+    "-",
+    "CALL C,a16",
+    "-",
+    "SBC A,d8",
+    "RST 18H",
+    "LDH (a8),A",
+    "POP HL",
+    "LD (C),A",
+    "-",
+    "-",
+    "PUSH HL",
+    "AND d8",
+    "RST 20H",
+    "ADD SP,r8",
+    "JP (HL)",
+    "LD (a16),A",
+    "-",
+    "-",
+    "-",
+    "XOR d8",
+    "RST 28H",
+    "LDH A,(a8)",
+    "POP AF",
+    "LD A,(C)",
+    "DI",
+    "-",
+    "PUSH AF",
+    "OR d8",
+    "RST 30H",
+    "LD HL,SP+r8",
+    "LD SP,HL",
+    "LD A,(a16)",
+    "EI",
+    "-",
+    "-",
+    "CP d8",
+    "RST 38H",
+];
+
+const CB_OPERATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const CB_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+// the mnemonic of a CB prefixed operation
+fn cb_mnemonic(opcode: u8) -> String {
+    let register = CB_REGISTERS[(opcode & 0b111) as usize];
+    let bit = (opcode >> 3) & 0b111;
+
+    match opcode >> 6 {
+        0 => format!("{} {}", CB_OPERATIONS[bit as usize], register),
+        1 => format!("BIT {},{}", bit, register),
+        2 => format!("RES {},{}", bit, register),
+        _ => format!("SET {},{}", bit, register),
+    }
+}
+
+// decodes the operation at addr, without executing it.
+// Returns the mnemonic with the immediate operands resolved, and the length in bytes
+pub fn disassemble_operation<M: Memory>(mmu: &M, addr: u16) -> (String, u16) {
+    let opcode = mmu.peek_byte(addr);
+
+    if opcode == 0xCB {
+        return (cb_mnemonic(mmu.peek_byte(addr.wrapping_add(1))), 2);
+    }
+
+    let mnemonic = OPERATIONS[opcode as usize];
+
+    if mnemonic == "-" {
+        return (format!("DB ${:02X}", opcode), 1);
+    }
+
+    // STOP is followed by a byte that's always 0
+    if opcode == 0x10 {
+        return (mnemonic.to_string(), 2);
+    }
+
+    let byte = mmu.peek_byte(addr.wrapping_add(1));
+    let word = (byte as u16) | ((mmu.peek_byte(addr.wrapping_add(2)) as u16) << 8);
+
+    if mnemonic.contains("d16") {
+        (mnemonic.replace("d16", &format!("${:04X}", word)), 3)
+    } else if mnemonic.contains("a16") {
+        (mnemonic.replace("a16", &format!("${:04X}", word)), 3)
+    } else if mnemonic.contains("a8") {
+        (mnemonic.replace("a8", &format!("$FF{:02X}", byte)), 2)
+    } else if mnemonic.contains("d8") {
+        (mnemonic.replace("d8", &format!("${:02X}", byte)), 2)
+    } else if mnemonic.contains("r8") {
+        let offset = byte as i8;
+        let sign = if offset < 0 { "-" } else { "+" };
+        let operand = format!("{}${:02X}", sign, (offset as i16).abs());
+        (mnemonic.replace("+r8", &operand).replace("r8", &operand), 2)
+    } else {
+        (mnemonic.to_string(), 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cb_mnemonic() {
+        assert_eq!(cb_mnemonic(0x00), "RLC B");
+        assert_eq!(cb_mnemonic(0x37), "SWAP A");
+        assert_eq!(cb_mnemonic(0x7C), "BIT 7,H");
+        assert_eq!(cb_mnemonic(0x86), "RES 0,(HL)");
+        assert_eq!(cb_mnemonic(0xFF), "SET 7,A");
+    }
+
+    #[test]
+    fn test_operations_table() {
+        assert_eq!(OPERATIONS[0x41], "LD B,C");
+        assert_eq!(OPERATIONS[0x76], "HALT");
+        assert_eq!(OPERATIONS[0x9E], "SBC A,(HL)");
+        assert_eq!(OPERATIONS[0xBF], "CP A");
+    }
+}
@@ -1,274 +1,678 @@
 #![allow(unused_must_use)]
 
-extern crate sdl2;
-
-use keypad::Button;
-
-use crate::cartridge::load_rom;
+use crate::backend::{Backend, InputEvent};
+use crate::cartridge::{load_rom_bytes, load_rom_with_save_path, CartridgeAccess, CartridgeHeader};
 use crate::cpu::CPU;
-use crate::gpu::GPU;
-use crate::mem::{Memory, MMU};
-use crate::sound::AUDIO_BUFFER_SIZE;
-
-use self::sdl2::audio::AudioSpecDesired;
-use self::sdl2::event::Event;
-use self::sdl2::keyboard::Keycode;
-use self::sdl2::pixels::PixelFormatEnum;
-use self::sdl2::rect::Rect;
+use crate::debugger::{parse_command, DebugCommand};
+use crate::gpu::{RenderMode, GPU};
+use crate::keypad::ButtonState;
+use crate::link::SerialPeer;
+use crate::mem::{Interrupt, Memory, CGB_BOOT_ROM_SIZE, DMG_BOOT_ROM_SIZE, MMU};
+use crate::sound::AudioOutType;
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
+
 use crate::utils::load_boot_rom;
-use sound::SAMPLE_RATE;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
 use std::{thread, time};
 
-const SCREEN_SIZE_MULTIPLIER: u32 = 3;
-const SCREEN_WIDTH: u32 = 160 * SCREEN_SIZE_MULTIPLIER;
-const SCREEN_HEIGHT: u32 = 144 * SCREEN_SIZE_MULTIPLIER;
 const FPS: u32 = 60;
 const CLOCKS_IN_A_FRAME: u32 = 70224;
 const DELAY_EVERY_FRAME: u32 = 1000 / FPS;
+const TURBO_RENDER_EVERY: u32 = 4; // in turbo mode, only one frame out of these is drawn
+const MIN_SPEED_MULTIPLIER: f32 = 0.25;
+const MAX_SPEED_MULTIPLIER: f32 = 4.0; // at this speed there's no waiting between frames at all
+const REWIND_EVERY: u32 = 5; // frames between two snapshots in the rewind buffer
+const DEFAULT_REWIND_CAPACITY: usize = 120; // 10 seconds of snapshots
+const FLUSH_SAVE_EVERY: u32 = 5 * FPS; // frames between two writes of the battery save
+
+// save state files start with these, then the rom header they belong to
+const STATE_FILE_MAGIC: &[u8; 4] = b"GMST";
+const STATE_FILE_VERSION: u16 = 5;
+const ROM_HEADER_START: usize = 0x134; // title to global checksum
+const ROM_HEADER_END: usize = 0x14E;
+
+// The RGB colours the 4 shades of the DMG are displayed with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorPalette {
+    colors: [(u8, u8, u8); 4],
+}
+
+impl ColorPalette {
+    pub const GREEN: ColorPalette = ColorPalette {
+        colors: [
+            (0xc4, 0xf0, 0xc2),
+            (0x5a, 0xb9, 0xa8),
+            (0x1e, 0x60, 0x6e),
+            (0x2d, 0x1b, 0x00),
+        ],
+    };
+
+    pub const CLASSIC: ColorPalette = ColorPalette {
+        colors: [
+            (0x9b, 0xbc, 0x0f),
+            (0x8b, 0xac, 0x0f),
+            (0x30, 0x62, 0x30),
+            (0x0f, 0x38, 0x0f),
+        ],
+    };
+
+    pub const GRAYSCALE: ColorPalette = ColorPalette {
+        colors: [
+            (0xff, 0xff, 0xff),
+            (0xaa, 0xaa, 0xaa),
+            (0x55, 0x55, 0x55),
+            (0x00, 0x00, 0x00),
+        ],
+    };
+
+    pub fn new(colors: [(u8, u8, u8); 4]) -> Self {
+        ColorPalette { colors }
+    }
+
+    pub fn colors(&self) -> [(u8, u8, u8); 4] {
+        self.colors
+    }
+
+    // the RGB colour of a 2-bit pixel
+    pub fn get(&self, pixel: u8) -> (u8, u8, u8) {
+        match pixel {
+            0b00..=0b11 => self.colors[pixel as usize],
+            _ => panic!("unexpected pixel color"),
+        }
+    }
+}
+
+impl Default for ColorPalette {
+    fn default() -> Self {
+        ColorPalette::GREEN
+    }
+}
+
+// what the gameboy produced in a frame
+pub struct FrameOutput {
+    pub video: Vec<u8>,           // RGB, 3 bytes a pixel, row after row
+    pub audio: Vec<AudioOutType>, // every sample played during the frame
+}
 
 pub struct Emulator {
     cpu: CPU<MMU<GPU>>,
+    palette: ColorPalette,
+    turbo: bool, // run unthrottled
+    speed_multiplier: f32,
+    frame_clocks: u32, // clocks run towards the current frame, the last frame overshoot included
+    total_clocks: u64,
+    frame_ready: bool, // the last instruction brought the gpu into vblank
+    rewind_buffer: VecDeque<Vec<u8>>, // snapshots, the most recent at the back
+    rewind_capacity: usize,
+}
+
+impl SaveState for Emulator {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.frame_clocks);
+        state.write_u64(self.total_clocks);
+        self.cpu.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.frame_clocks = state.read_u32()?;
+        self.total_clocks = state.read_u64()?;
+        self.cpu.load_state(state)
+    }
 }
 
 impl Emulator {
-    pub fn new(path: &str) -> Emulator {
-        let cartridge = load_rom(path);
+    // panics if the rom can't be loaded
+    pub fn new(path: &str, read_only: bool) -> Emulator {
+        Emulator::try_new(path, read_only).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // read_only loads the save file, but the game can't create or change it
+    pub fn try_new(path: &str, read_only: bool) -> Result<Emulator, String> {
+        Ok(Emulator::with_cartridge(load_rom_with_save_path(
+            path, None, read_only,
+        )?))
+    }
+
+    // an emulator for a rom already in memory, the filesystem isn't touched.
+    // Its battery save is kept only in memory
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Emulator, String> {
+        Ok(Emulator::with_cartridge(load_rom_bytes(rom)?))
+    }
+
+    fn with_cartridge(cartridge: Box<dyn CartridgeAccess>) -> Emulator {
         let mmu = MMU::new(GPU::new(), cartridge);
         let cpu = CPU::new(mmu);
 
-        Emulator { cpu }
+        Emulator {
+            cpu,
+            palette: ColorPalette::default(),
+            turbo: false,
+            speed_multiplier: 1.0,
+            frame_clocks: 0,
+            total_clocks: 0,
+            frame_ready: false,
+            rewind_buffer: VecDeque::new(),
+            rewind_capacity: DEFAULT_REWIND_CAPACITY,
+        }
+    }
+
+    pub fn set_palette(&mut self, colors: [(u8, u8, u8); 4]) {
+        self.palette = ColorPalette::new(colors);
+    }
+
+    pub fn get_palette(&self) -> ColorPalette {
+        self.palette
+    }
+
+    // restarts the game without loading the rom again: the cartridge keeps its ram,
+    // everything else is like the gameboy was just switched on, past the boot rom
+    pub fn reset(&mut self) {
+        self.power_on();
+        self.cpu.mmu.post_boot();
+    }
+
+    // everything back to the power on state the boot rom starts from, the cartridge keeping its ram
+    fn power_on(&mut self) {
+        self.cpu.mmu.reset();
+        self.cpu.reset_registers();
+
+        self.frame_clocks = 0;
+        self.total_clocks = 0;
+        self.frame_ready = false;
+        self.rewind_buffer.clear();
     }
 
     pub fn load_bios(&mut self) {
-        self.cpu.mmu.set_bios(load_boot_rom());
+        self.boot_from(load_boot_rom().to_vec());
+    }
+
+    // boots from a DMG (256 bytes) or CGB (2304 bytes) boot rom read from path
+    pub fn load_bios_from(&mut self, path: &str) -> io::Result<()> {
+        let bios = fs::read(path)?;
+        if bios.len() != DMG_BOOT_ROM_SIZE && bios.len() != CGB_BOOT_ROM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "boot rom {} is {} bytes long, expected {} or {}",
+                    path,
+                    bios.len(),
+                    DMG_BOOT_ROM_SIZE,
+                    CGB_BOOT_ROM_SIZE
+                ),
+            ));
+        }
+
+        self.boot_from(bios);
+        Ok(())
+    }
+
+    // the boot rom starts from the power on state, with the lcd off,
+    // and sets up the io itself
+    fn boot_from(&mut self, bios: Vec<u8>) {
+        self.power_on();
+        self.cpu.mmu.set_bios(bios);
         self.cpu.set_registry_value("PC", 0);
     }
 
-    fn step(&mut self) {
-        let mut clocks_this_frame = 0u32;
+    // the whole state of the emulated gameboy
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // goes back to a snapshot taken with the same cartridge.
+    // If it fails, the emulator is left in an inconsistent state
+    pub fn load_snapshot(&mut self, snapshot: &[u8]) -> io::Result<()> {
+        let mut state = StateReader::new(snapshot);
+        self.load_whole_state(&mut state)
+    }
+
+    // loads the rest of state, which has to end with the emulator
+    fn load_whole_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.load_state(state)?;
+
+        if !state.is_empty() {
+            return Err(invalid_state("save state is longer than expected"));
+        }
+        Ok(())
+    }
+
+    // the title, type and region of the loaded game
+    pub fn cartridge_header(&self) -> CartridgeHeader {
+        self.cpu.mmu.cartridge.cartridge().header()
+    }
+
+    // a copy of the cartridge battery ram, to keep the save somewhere else than a file
+    pub fn export_ram(&self) -> Vec<u8> {
+        self.cpu.mmu.cartridge.cartridge().export_ram()
+    }
+
+    // loads a save exported before, it has to be as big as the cartridge ram
+    pub fn import_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        self.cpu.mmu.cartridge.cartridge_mut().import_ram(data)
+    }
+
+    // the bytes identifying the loaded rom: title, licensee, sizes and checksums
+    fn rom_header(&self) -> &[u8] {
+        &self.cpu.mmu.cartridge.cartridge().rom[ROM_HEADER_START..ROM_HEADER_END]
+    }
+
+    // writes a snapshot to a file, along with the rom it belongs to
+    pub fn save_to_file(&self, path: &str) -> io::Result<()> {
+        let mut state = StateWriter::new();
+        state.write_bytes(STATE_FILE_MAGIC);
+        state.write_u16(STATE_FILE_VERSION);
+        state.write_bytes(self.rom_header());
+        self.save_state(&mut state);
+
+        fs::write(path, state.into_bytes())
+    }
+
+    // loads a file written by save_to_file. States of other roms or versions are refused
+    // and leave the emulator untouched
+    pub fn load_from_file(&mut self, path: &str) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let mut state = StateReader::new(&data);
+
+        let mut magic = [0; 4];
+        state.read_bytes(&mut magic)?;
+        if &magic != STATE_FILE_MAGIC {
+            return Err(invalid_state("not a save state file"));
+        }
+
+        if state.read_u16()? != STATE_FILE_VERSION {
+            return Err(invalid_state("save state version is not supported"));
+        }
+
+        let mut rom_header = [0; ROM_HEADER_END - ROM_HEADER_START];
+        state.read_bytes(&mut rom_header)?;
+        if rom_header != self.rom_header() {
+            return Err(invalid_state("save state belongs to a different rom"));
+        }
+
+        self.load_whole_state(&mut state)
+    }
+
+    // how many snapshots the rewind buffer keeps, dropping the oldest ones if needed
+    pub fn set_rewind_capacity(&mut self, capacity: usize) {
+        self.rewind_capacity = capacity;
+        while self.rewind_buffer.len() > capacity {
+            self.rewind_buffer.pop_front();
+        }
+    }
+
+    // saves a snapshot to rewind to later
+    fn push_rewind_state(&mut self) {
+        if self.rewind_capacity == 0 {
+            return;
+        }
+        if self.rewind_buffer.len() == self.rewind_capacity {
+            self.rewind_buffer.pop_front();
+        }
+
+        let snapshot = self.snapshot();
+        self.rewind_buffer.push_back(snapshot);
+    }
+
+    // goes back to the most recent snapshot in the rewind buffer, if any
+    pub fn rewind(&mut self) {
+        if let Some(snapshot) = self.rewind_buffer.pop_back() {
+            self.load_snapshot(&snapshot)
+                .expect("rewind buffer holds an invalid snapshot");
+        }
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo
+    }
+
+    // drawing the whole frame at once is faster, but loses the raster effects
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.cpu.mmu.gpu.set_render_mode(render_mode);
+    }
+
+    // how fast the emulator runs compared to the gameboy, 2.0 is twice as fast.
+    // Kept between 0.25 and 4.0, the top speed doesn't wait between frames
+    pub fn set_speed_multiplier(&mut self, speed_multiplier: f32) {
+        self.speed_multiplier = speed_multiplier.clamp(MIN_SPEED_MULTIPLIER, MAX_SPEED_MULTIPLIER);
+    }
+
+    pub fn get_speed_multiplier(&self) -> f32 {
+        self.speed_multiplier
+    }
+
+    // true when the emulator runs faster than the gameboy
+    fn audio_outpaced(&self) -> bool {
+        self.turbo || self.speed_multiplier > 1.0
+    }
+
+    // how long to wait before the next frame, if time_passed ms went by since the last one
+    fn frame_delay(&self, time_passed: u32) -> Option<time::Duration> {
+        if self.turbo || self.speed_multiplier >= MAX_SPEED_MULTIPLIER {
+            return None;
+        }
 
+        let delay_every_frame = (DELAY_EVERY_FRAME as f32 / self.speed_multiplier) as u32;
+        if time_passed >= delay_every_frame {
+            return None;
+        }
+
+        Some(time::Duration::from_millis(
+            (delay_every_frame - time_passed) as u64,
+        ))
+    }
+
+    // whether frame number frame has to be drawn on screen
+    fn should_render(&self, frame: u32) -> bool {
+        !self.turbo || frame.is_multiple_of(TURBO_RENDER_EVERY)
+    }
+
+    // steps a frame forward. Returns true if it stopped early on a breakpoint.
+    // The cycles run past the end of the frame count towards the next one
+    fn step(&mut self) -> bool {
         // step a frame forward!
+        while self.frame_clocks < CLOCKS_IN_A_FRAME {
+            match self.step_instruction() {
+                Some(t) => self.frame_clocks += t as u32,
+                None => return true,
+            }
+        }
+
+        self.frame_clocks -= CLOCKS_IN_A_FRAME;
+        false
+    }
+
+    // clocks already run towards the next frame
+    pub fn get_frame_clocks(&self) -> u32 {
+        self.frame_clocks
+    }
+
+    // clocks run since the emulator started
+    pub fn get_total_clocks(&self) -> u64 {
+        self.total_clocks
+    }
+
+    // executes an instruction, the memory brings gpu and sound along cycle for cycle.
+    // Returns the t cycles passed at normal speed, or None if a breakpoint was hit before executing it
+    fn step_instruction(&mut self) -> Option<u8> {
+        let (_line, cpu_t, breakpoint_hit) = self.cpu.step_checked();
+        if breakpoint_hit {
+            return None;
+        }
+
+        // in double speed the rest of the gameboy sees half the cpu cycles
+        let t = if self.cpu.is_double_speed() {
+            cpu_t / 2
+        } else {
+            cpu_t
+        };
+
+        self.frame_ready = self.cpu.mmu.take_frame_completed();
+
+        self.total_clocks += t as u64;
+        Some(t)
+    }
+
+    // runs whole instructions for at most budget t cycles, for running in lockstep
+    // with an external clock. Stops before an instruction that might not fit in what's left,
+    // or on a breakpoint. Returns the t cycles actually run
+    pub fn advance(&mut self, budget: u32) -> u32 {
+        let mut cycles = 0u32;
+
         loop {
-            let (_line, t) = self.cpu.step();
+            let mut max_cycles = self.cpu.next_step_max_cycles() as u32;
+            if self.cpu.is_double_speed() {
+                max_cycles /= 2;
+            }
+            if cycles + max_cycles > budget {
+                break;
+            }
 
-            clocks_this_frame += t as u32;
+            match self.step_instruction() {
+                Some(t) => cycles += t as u32,
+                None => break,
+            }
+        }
+
+        cycles
+    }
+
+    // runs whole instructions until at least target t cycles went by, overshooting
+    // by less than an instruction. Stops early on a breakpoint. Returns the t cycles actually run
+    pub fn run_cycles(&mut self, target: u32) -> u32 {
+        let mut cycles = 0u32;
 
-            let (vblank_interrupt, stat_interrupt) = self.cpu.mmu.gpu.step(t);
-            if vblank_interrupt {
-                self.request_vblank_interrupt();
+        while cycles < target {
+            match self.step_instruction() {
+                Some(t) => cycles += t as u32,
+                None => break,
             }
-            if stat_interrupt {
-                self.request_stat_interrupt();
+        }
+
+        cycles
+    }
+
+    // runs whole instructions until the gpu completes a frame, entering vblank.
+    // Stops early on a breakpoint. Returns the t cycles actually run
+    pub fn run_until_frame(&mut self) -> u32 {
+        let mut cycles = 0u32;
+
+        while let Some(t) = self.step_instruction() {
+            cycles += t as u32;
+            if self.frame_ready {
+                break;
             }
-            self.cpu.mmu.sound.tick(t);
+        }
+
+        cycles
+    }
 
-            if clocks_this_frame >= CLOCKS_IN_A_FRAME {
+    // runs until the gpu completes a frame, returning its picture and the audio played
+    // meanwhile. With an audio sink set, all the audio goes to the sink instead.
+    // Stops early on a breakpoint, with what was produced until then
+    pub fn step_frame(&mut self) -> FrameOutput {
+        let mut audio = Vec::new();
+
+        while self.step_instruction().is_some() {
+            if let Some(audio_buffer) = self.cpu.mmu.sound.get_audio_buffer() {
+                audio.extend_from_slice(&audio_buffer[0..]);
+            }
+            if self.frame_ready {
                 break;
             }
         }
+        audio.extend(self.cpu.mmu.sound.take_pending_samples());
+
+        FrameOutput {
+            video: self.cpu.mmu.gpu.get_rgb_buffer(&self.palette.colors()),
+            audio,
+        }
     }
 
-    pub fn passes_test_rom(&mut self) -> bool {
+    // true if the last instruction run completed a frame, ready to be presented
+    pub fn is_frame_ready(&self) -> bool {
+        self.frame_ready
+    }
+
+    // sets all the buttons at once, for frontends polling the whole pad every frame
+    pub fn set_buttons(&mut self, state: ButtonState) {
+        self.cpu.mmu.set_buttons(state);
+    }
+
+    // reads debugger commands from stdin until the user quits or stdin is closed
+    pub fn debug_repl(&mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+
         loop {
-            self.step();
+            print!("> ");
+            io::stdout().flush();
 
-            let outbuffer = self.cpu.mmu.link.get_buffer();
-            if outbuffer[0] != ' ' {
-                let result: String = outbuffer.iter().collect();
-                let passed: bool = result.contains("Passed");
-                let failed: bool = result.contains("Failed");
-                if passed {
-                    return passed;
-                }
-                if failed {
-                    return false;
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => return,
+            };
+
+            match parse_command(&line) {
+                Ok(DebugCommand::Quit) => return,
+                Ok(command) => self.debug_command(command),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    fn debug_command(&mut self, command: DebugCommand) {
+        match command {
+            DebugCommand::Step => {
+                let pc = self.cpu.get_registry_value("PC");
+                let (_, text) = self.cpu.disassemble(pc, 1).remove(0);
+
+                // sitting on a breakpoint, the first try only reports it
+                if self.step_instruction().is_none() {
+                    self.step_instruction();
                 }
+
+                let flags = self.cpu.get_registry_value("F");
+                println!(
+                    "{:04X}: {:<16} Z:{} N:{} H:{} C:{}",
+                    pc,
+                    text,
+                    (flags >> 7) & 1,
+                    (flags >> 6) & 1,
+                    (flags >> 5) & 1,
+                    (flags >> 4) & 1
+                );
             }
+            DebugCommand::Continue => {
+                while self.step_instruction().is_some() {}
+                println!(
+                    "breakpoint hit at {:04X}",
+                    self.cpu.get_registry_value("PC")
+                );
+            }
+            DebugCommand::Breakpoint(addr) => {
+                self.add_breakpoint(addr);
+            }
+            DebugCommand::Registers => {
+                println!("{}", self.cpu.trace_state());
+            }
+            DebugCommand::Memory(addr) => {
+                let bytes: Vec<String> = (0..16)
+                    .map(|i| format!("{:02X}", self.cpu.mmu.peek_byte(addr.wrapping_add(i))))
+                    .collect();
+                println!("{:04X}: {}", addr, bytes.join(" "));
+            }
+            DebugCommand::Quit => {}
         }
     }
 
-    // TODO: move it away from here!
-    fn request_keypad_interrupt(&mut self) {
-        let interrupt_flags = self.cpu.mmu.read_byte(0xFF0F) | 0b10000;
-        self.cpu.mmu.write_byte(0xFF0F, interrupt_flags);
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.cpu.add_breakpoint(address);
     }
 
-    // TODO: move it away from here!
-    fn request_vblank_interrupt(&mut self) {
-        let interrupt_flags = self.cpu.mmu.read_byte(0xFF0F) | 1;
-        self.cpu.mmu.write_byte(0xFF0F, interrupt_flags);
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.cpu.remove_breakpoint(address);
     }
 
-    // TODO: move it away from here!
-    fn request_stat_interrupt(&mut self) {
-        let interrupt_flags = self.cpu.mmu.read_byte(0xFF0F) | 2;
-        self.cpu.mmu.write_byte(0xFF0F, interrupt_flags);
+    // plugs something into the link cable port
+    pub fn connect_peer(&mut self, peer: Box<dyn SerialPeer>) {
+        self.cpu.mmu.link.connect_peer(peer);
     }
 
-    pub fn run(&mut self) {
-        let sdl = sdl2::init().unwrap();
-        let video_subsystem = sdl.video().unwrap();
-        let audio_subsystem = sdl.audio().unwrap();
+    pub fn passes_test_rom(&mut self) -> bool {
+        loop {
+            self.step();
 
-        let desired_spec = AudioSpecDesired {
-            freq: Some(SAMPLE_RATE as i32),
-            channels: Some(1),
-            samples: Some(AUDIO_BUFFER_SIZE as u16), // default sample size
-        };
+            let result = self.cpu.mmu.link.get_output();
+            if result.contains("Passed") {
+                return true;
+            }
+            if result.contains("Failed") {
+                return false;
+            }
+        }
+    }
 
-        let device = audio_subsystem
-            .open_queue::<i16, _>(None, &desired_spec)
-            .unwrap();
+    // the text sent through the serial port since the start
+    pub fn serial_output(&self) -> String {
+        self.cpu.mmu.link.get_output().to_string()
+    }
+
+    // how many times each interrupt was served: vblank, stat, timer, serial and joypad
+    pub fn interrupt_counts(&self) -> [u64; 5] {
+        self.cpu.interrupt_counts()
+    }
 
-        let window = video_subsystem
-            .window("gameman", SCREEN_WIDTH, SCREEN_HEIGHT)
-            .position_centered()
-            .opengl()
-            .build()
-            .unwrap();
+    pub fn reset_interrupt_counts(&mut self) {
+        self.cpu.reset_interrupt_counts();
+    }
 
-        let mut canvas = window.into_canvas().build().unwrap();
-        // canvas.set_scale(2f32, 2f32);
-        let texture_creator = canvas.texture_creator();
+    // the super game boy packets the game sent, only recorded
+    pub fn sgb_packets(&self) -> &[[u8; 16]] {
+        self.cpu.mmu.sgb_packets()
+    }
 
-        let mut texture2 = texture_creator
-            .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
-            .unwrap();
+    pub fn get_lyc(&self) -> u8 {
+        self.cpu.mmu.gpu.get_compare_line()
+    }
 
+    // sets the compare line (LYC), just like a game writing 0xFF45 would
+    pub fn set_lyc(&mut self, lyc: u8) {
+        if self.cpu.mmu.gpu.set_compare_line(lyc) {
+            self.cpu.mmu.request_interrupt(Interrupt::Stat);
+        }
+    }
+
+    // runs until the user quits, presenting the frames and playing the sound through backend
+    pub fn run<B: Backend>(&mut self, backend: &mut B) {
         let mut last_ticks = time::Instant::now();
         let mut pause = false;
+        let mut frame: u32 = 0;
 
-        let mut event_pump = sdl.event_pump().unwrap();
+        self.cpu.mmu.sound.set_sample_rate(backend.sample_rate());
+        self.cpu
+            .mmu
+            .sound
+            .set_audio_buffer_size(backend.audio_buffer_size());
 
         'running: loop {
-            for event in event_pump.poll_iter() {
+            for event in backend.poll_events() {
                 match event {
-                    Event::Quit { .. }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Q),
-                        ..
-                    }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => break 'running,
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Space),
-                        ..
-                    } => {
+                    InputEvent::Quit => break 'running,
+                    InputEvent::TogglePause => {
                         pause ^= true;
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::N),
-                        ..
-                    } => {
-                        self.step();
-                    }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Z),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::A);
-                        self.request_keypad_interrupt();
+                    InputEvent::ToggleTurbo => {
+                        self.turbo ^= true;
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::X),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::B);
-                        self.request_keypad_interrupt();
+                    InputEvent::SpeedUp => {
+                        let speed_multiplier = self.speed_multiplier * 2.0;
+                        self.set_speed_multiplier(speed_multiplier);
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::A),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::SELECT);
-                        self.request_keypad_interrupt();
+                    InputEvent::SlowDown => {
+                        let speed_multiplier = self.speed_multiplier / 2.0;
+                        self.set_speed_multiplier(speed_multiplier);
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::S),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::START);
-                        self.request_keypad_interrupt();
+                    InputEvent::Rewind => {
+                        self.rewind();
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Down),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::DOWN);
-                        self.request_keypad_interrupt();
+                    InputEvent::StepFrame => {
+                        pause |= self.step();
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Up),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::UP);
-                        self.request_keypad_interrupt();
+                    InputEvent::Press(button) => {
+                        self.cpu.mmu.press_button(button);
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Left),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::LEFT);
-                        self.request_keypad_interrupt();
+                    InputEvent::Release(button) => {
+                        self.cpu.mmu.release_button(button);
                     }
-                    Event::KeyDown {
-                        keycode: Some(Keycode::Right),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.press(Button::RIGHT);
-                        self.request_keypad_interrupt();
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::Z),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::A);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::X),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::B);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::A),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::SELECT);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::S),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::START);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::Down),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::DOWN);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::Up),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::UP);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::Left),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::LEFT);
-                    }
-                    Event::KeyUp {
-                        keycode: Some(Keycode::Right),
-                        ..
-                    } => {
-                        self.cpu.mmu.key.release(Button::RIGHT);
-                    }
-                    _ => {}
                 }
             }
 
@@ -276,68 +680,654 @@ impl Emulator {
                 continue;
             }
 
-            self.step();
-
-            canvas.clear();
-
-            texture2
-                .with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                    let gpu_buffer = self.cpu.mmu.gpu.get_buffer();
+            pause |= self.step();
 
-                    for y in 0..144 {
-                        for x in 0..160 {
-                            let pixel = gpu_buffer[x + y * 160];
-
-                            let paletted_color: (u8, u8, u8) = match pixel {
-                                0b00 => (0xc4, 0xf0, 0xc2),
-                                0b01 => (0x5a, 0xb9, 0xa8),
-                                0b10 => (0x1e, 0x60, 0x6e),
-                                0b11 => (0x2d, 0x1b, 0x00),
-                                _ => panic!("unexpected pixel color"),
-                            };
-
-                            let x_out = x * 3;
-                            let y_out = y * pitch;
-
-                            buffer[x_out + y_out] = paletted_color.0;
-                            buffer[x_out + y_out + 1] = paletted_color.1;
-                            buffer[x_out + y_out + 2] = paletted_color.2;
-                        }
-                    }
-                })
-                .unwrap();
-            canvas
-                .copy(
-                    &texture2,
-                    None,
-                    Some(Rect::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)),
-                )
-                .unwrap();
-
-            canvas.present();
+            frame = frame.wrapping_add(1);
+            if frame.is_multiple_of(REWIND_EVERY) {
+                self.push_rewind_state();
+            }
+            if frame.is_multiple_of(FLUSH_SAVE_EVERY) {
+                if let Err(e) = self.cpu.mmu.cartridge.flush_save() {
+                    println!("Error updating save file: {}", e);
+                }
+            }
+            if self.should_render(frame) {
+                backend.present(&self.cpu.mmu.gpu.get_rgb_buffer(&self.palette.colors()));
+            }
 
             // audio
+            let audio_outpaced = self.audio_outpaced();
             if let Some(audio_buffer) = self.cpu.mmu.sound.get_audio_buffer() {
-                // wait for device queue to drain audio buffer
-                while device.size() > AUDIO_BUFFER_SIZE as u32 {
-                    thread::sleep(time::Duration::from_millis(1));
+                if audio_outpaced {
+                    // samples come faster than they are played, drop what's queued
+                    // instead of waiting for it
+                    backend.clear();
                 }
 
-                device.queue(&audio_buffer[0..]);
-
-                device.resume();
+                backend.play(&audio_buffer[0..]);
             }
 
             let ticks = time::Instant::now();
             let time_passed = (ticks - last_ticks).as_millis() as u32;
 
-            if time_passed < DELAY_EVERY_FRAME {
-                thread::sleep(time::Duration::from_millis(
-                    (DELAY_EVERY_FRAME - time_passed) as u64,
-                ));
+            if let Some(delay) = self.frame_delay(time_passed) {
+                thread::sleep(delay);
             }
 
             last_ticks = ticks;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{AudioOutput, Input, Renderer};
+    use cpu::Register;
+    use sound::{AudioSink, AUDIO_BUFFER_SIZE, SAMPLE_RATE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_set_palette() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        assert_eq!(emulator.get_palette(), ColorPalette::GREEN);
+
+        let colors = [(1, 2, 3), (4, 5, 6), (7, 8, 9), (10, 11, 12)];
+        emulator.set_palette(colors);
+
+        assert_eq!(emulator.get_palette().colors(), colors);
+        assert_eq!(emulator.get_palette().get(0b10), (7, 8, 9));
+
+        emulator.set_palette(ColorPalette::GRAYSCALE.colors());
+        assert_eq!(emulator.get_palette(), ColorPalette::GRAYSCALE);
+    }
+
+    #[test]
+    fn test_frame_overshoot_carried() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        let frames = 10;
+        for _ in 0..frames {
+            emulator.step();
+
+            // at most an instruction and an interrupt past the end of the frame
+            assert!(emulator.get_frame_clocks() < 24 + 12);
+        }
+
+        assert_eq!(
+            emulator.get_total_clocks(),
+            frames * CLOCKS_IN_A_FRAME as u64 + emulator.get_frame_clocks() as u64
+        );
+    }
+
+    // quits after polling frames times, counting what it's given
+    struct MockBackend {
+        frames: usize,
+        polls: usize,
+        presents: usize,
+        samples: usize,
+        plays: usize,
+        clears: usize,
+    }
+
+    impl Renderer for MockBackend {
+        fn present(&mut self, rgb: &[u8]) {
+            assert_eq!(rgb.len(), 160 * 144 * 3);
+            self.presents += 1;
+        }
+    }
+
+    impl Input for MockBackend {
+        fn poll_events(&mut self) -> Vec<InputEvent> {
+            self.polls += 1;
+            if self.polls > self.frames {
+                vec![InputEvent::Quit]
+            } else {
+                vec![]
+            }
+        }
+    }
+
+    impl AudioOutput for MockBackend {
+        fn play(&mut self, samples: &[AudioOutType]) {
+            self.samples += samples.len();
+            self.plays += 1;
+        }
+
+        fn clear(&mut self) {
+            self.clears += 1;
+        }
+    }
+
+    #[test]
+    fn test_run_backend() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        let mut backend = MockBackend {
+            frames: 5,
+            polls: 0,
+            presents: 0,
+            samples: 0,
+            plays: 0,
+            clears: 0,
+        };
+
+        emulator.run(&mut backend);
+
+        assert_eq!(backend.presents, 5);
+        assert!(backend.samples > 0);
+        assert_eq!(backend.clears, 0);
+    }
+
+    // faster than the gameboy, the queued audio is dropped instead of waited for
+    #[test]
+    fn test_run_backend_fast() {
+        for &(speed_multiplier, turbo) in [(2.0, false), (1.0, true)].iter() {
+            let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+            emulator.set_speed_multiplier(speed_multiplier);
+            emulator.set_turbo(turbo);
+            let mut backend = MockBackend {
+                frames: 10,
+                polls: 0,
+                presents: 0,
+                samples: 0,
+                plays: 0,
+                clears: 0,
+            };
+
+            emulator.run(&mut backend);
+
+            assert!(backend.plays > 0);
+            assert_eq!(backend.clears, backend.plays);
+        }
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        assert_eq!(
+            emulator.frame_delay(10),
+            Some(time::Duration::from_millis((DELAY_EVERY_FRAME - 10) as u64))
+        );
+        assert_eq!(emulator.frame_delay(DELAY_EVERY_FRAME), None);
+        assert!((0..TURBO_RENDER_EVERY).all(|frame| emulator.should_render(frame)));
+
+        emulator.set_turbo(true);
+
+        assert!(emulator.is_turbo());
+        assert_eq!(emulator.frame_delay(0), None);
+        assert_eq!(
+            (0..TURBO_RENDER_EVERY)
+                .filter(|frame| emulator.should_render(*frame))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_speed_multiplier() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        assert_eq!(emulator.get_speed_multiplier(), 1.0);
+
+        // half speed, twice the wait
+        emulator.set_speed_multiplier(0.5);
+        assert_eq!(
+            emulator.frame_delay(10),
+            Some(time::Duration::from_millis(
+                (DELAY_EVERY_FRAME * 2 - 10) as u64
+            ))
+        );
+        assert_eq!(emulator.frame_delay(DELAY_EVERY_FRAME * 2), None);
+
+        emulator.set_speed_multiplier(2.0);
+        assert_eq!(
+            emulator.frame_delay(0),
+            Some(time::Duration::from_millis((DELAY_EVERY_FRAME / 2) as u64))
+        );
+        assert_eq!(emulator.frame_delay(DELAY_EVERY_FRAME / 2), None);
+
+        // clamped, the top speed doesn't wait at all
+        emulator.set_speed_multiplier(0.01);
+        assert_eq!(emulator.get_speed_multiplier(), 0.25);
+        emulator.set_speed_multiplier(100.0);
+        assert_eq!(emulator.get_speed_multiplier(), 4.0);
+        assert_eq!(emulator.frame_delay(0), None);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        for _ in 0..3 {
+            emulator.step();
+        }
+        emulator.push_rewind_state();
+        let buffer = emulator.cpu.mmu.gpu.get_buffer().to_vec();
+        let pc = emulator.cpu.get_registry_value("PC");
+        let total_clocks = emulator.get_total_clocks();
+
+        for _ in 0..5 {
+            emulator.step();
+        }
+        assert_ne!(emulator.get_total_clocks(), total_clocks);
+
+        emulator.rewind();
+
+        assert_eq!(emulator.cpu.mmu.gpu.get_buffer().to_vec(), buffer);
+        assert_eq!(emulator.cpu.get_registry_value("PC"), pc);
+        assert_eq!(emulator.get_total_clocks(), total_clocks);
+
+        // nothing left to rewind to
+        assert!(emulator.rewind_buffer.is_empty());
+        emulator.rewind();
+        assert_eq!(emulator.get_total_clocks(), total_clocks);
+    }
+
+    #[test]
+    fn test_rewind_capacity() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        emulator.set_rewind_capacity(2);
+
+        let mut snapshots = Vec::new();
+        for _ in 0..3 {
+            emulator.step();
+            emulator.push_rewind_state();
+            snapshots.push(emulator.snapshot());
+        }
+
+        // the oldest one was dropped
+        assert_eq!(emulator.rewind_buffer.len(), 2);
+        assert_eq!(emulator.rewind_buffer[0], snapshots[1]);
+
+        emulator.set_rewind_capacity(1);
+        assert_eq!(emulator.rewind_buffer.len(), 1);
+        assert_eq!(emulator.rewind_buffer[0], snapshots[2]);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        assert_eq!(emulator.cpu.mmu.read_byte(0x0100), 0x00);
+        assert_eq!(emulator.cpu.mmu.read_byte(0x0101), 0xC3);
+
+        emulator.step();
+        assert_ne!(emulator.get_total_clocks(), 0);
+
+        assert!(Emulator::from_bytes(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_cartridge_header() {
+        let emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        let header = emulator.cartridge_header();
+
+        // blargg's roms have no title, they're mbc1 and work on both dmg and cgb
+        assert_eq!(header.title, "");
+        assert_eq!(header.cgb_flag, 0x80);
+        assert_eq!(header.cartridge_type, 0x01);
+        assert_eq!(header.region, 0x00);
+        assert_eq!(header.header_checksum, 0x66);
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join("gameman_test_state_file.state");
+        let path = path.to_str().unwrap();
+
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        for _ in 0..10 {
+            emulator.step();
+        }
+        let saved = emulator.snapshot();
+        emulator.save_to_file(path).unwrap();
+
+        for _ in 0..10 {
+            emulator.step();
+        }
+        assert_ne!(emulator.snapshot(), saved);
+
+        emulator.load_from_file(path).unwrap();
+        assert_eq!(emulator.snapshot(), saved);
+
+        // another rom refuses it, untouched
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        rom[0x134..0x13A].copy_from_slice(b"OTHER!");
+        let mut other = Emulator::from_bytes(rom).unwrap();
+        let other_state = other.snapshot();
+        assert_eq!(
+            other.load_from_file(path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(other.snapshot(), other_state);
+
+        // so does something that isn't a state
+        fs::write(path, b"not a state, just some bytes").unwrap();
+        assert!(emulator.load_from_file(path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    // a dmg has no KEY1, STOP just stops
+    #[test]
+    fn test_no_double_speed_on_dmg() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        emulator.cpu.mmu.write_byte(0xFFFF, 0);
+
+        emulator.cpu.mmu.write_byte(0xFF4D, 1);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF4D), 0xFF);
+        emulator.cpu.mmu.write_byte(0xC000, 0x10);
+        emulator.cpu.mmu.write_byte(0xC001, 0x00);
+        emulator.cpu.set_registry_value("PC", 0xC000);
+
+        emulator.step_instruction();
+        assert!(!emulator.cpu.is_double_speed());
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn test_double_speed() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        emulator.cpu.mmu.set_cgb_mode(true);
+        emulator.cpu.mmu.write_byte(0xFFFF, 0);
+
+        // prepare the switch, then STOP from work ram
+        emulator.cpu.mmu.write_byte(0xFF4D, 1);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF4D), 0x7F);
+        emulator.cpu.mmu.write_byte(0xC000, 0x10);
+        emulator.cpu.mmu.write_byte(0xC001, 0x00);
+        emulator.cpu.set_registry_value("PC", 0xC000);
+
+        emulator.step_instruction();
+        assert!(emulator.cpu.is_double_speed());
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF4D), 0xFE);
+
+        // NOPs: the timers see every cpu cycle, everything else half of them
+        for address in 0xC000..0xC200 {
+            emulator.cpu.mmu.write_byte(address, 0x00);
+        }
+        emulator.cpu.set_registry_value("PC", 0xC000);
+        emulator.cpu.mmu.write_byte(0xFF04, 0);
+        let total_clocks = emulator.get_total_clocks();
+
+        for _ in 0..256 {
+            assert_eq!(emulator.step_instruction(), Some(2));
+        }
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF04), 4);
+        assert_eq!(emulator.get_total_clocks() - total_clocks, 512);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        // the first instruction is a NOP, 4 cycles plus a possible interrupt
+        assert_eq!(emulator.cpu.next_step_max_cycles(), 16);
+
+        assert_eq!(emulator.advance(15), 0);
+        assert_eq!(emulator.cpu.get_registry_value("PC"), 0x100);
+
+        // then a JP a16 which doesn't fit anymore
+        assert_eq!(emulator.advance(16), 4);
+        assert_eq!(emulator.cpu.get_registry_value("PC"), 0x101);
+
+        assert_eq!(emulator.advance(27), 0);
+        assert_eq!(emulator.advance(28), 16);
+        assert_eq!(emulator.cpu.get_registry_value("PC"), 0x213);
+
+        // never overshoots, and stops only when the next step might not fit
+        for budget in [100, 1000, 70224].iter() {
+            let cycles = emulator.advance(*budget);
+            assert!(cycles <= *budget);
+            assert!(cycles + emulator.cpu.next_step_max_cycles() as u32 > *budget);
+        }
+    }
+
+    // an unused opcode isn't free, it only runs when its 4 cycles fit
+    #[test]
+    fn test_advance_illegal_opcode() {
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        rom[0x100] = 0xD3;
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        for budget in 0..4 {
+            assert_eq!(emulator.advance(budget), 0);
+            assert_eq!(emulator.cpu.get_registry_value("PC"), 0x100);
+        }
+
+        let max_cycles = emulator.cpu.next_step_max_cycles() as u32;
+        assert_eq!(emulator.advance(max_cycles), 4);
+        assert_eq!(emulator.cpu.get_registry_value("PC"), 0x101);
+    }
+
+    // without the bios, the emulator starts where the boot rom would have left it
+    #[test]
+    fn test_post_boot_state() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        assert_eq!(emulator.cpu.reg(Register::A), 0x01);
+        assert_eq!(emulator.cpu.reg(Register::F), 0xB0);
+        assert_eq!(emulator.cpu.reg(Register::BC), 0x0013);
+        assert_eq!(emulator.cpu.reg(Register::DE), 0x00D8);
+        assert_eq!(emulator.cpu.reg(Register::HL), 0x014D);
+
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF40), 0x91);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF47), 0xFC);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF04), 0xAB);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF0F), 0xE1);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF24), 0x77);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF26), 0xF1);
+    }
+
+    #[test]
+    fn test_load_bios_from() {
+        let path = std::env::temp_dir().join("gameman_test_boot_rom.bin");
+        let path = path.to_str().unwrap();
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        // somewhere in the middle of a frame
+        emulator.run_cycles(CLOCKS_IN_A_FRAME / 2);
+        assert_ne!(emulator.cpu.mmu.gpu.status().line, 0);
+
+        fs::write(path, vec![0x42; 0x100]).unwrap();
+        emulator.load_bios_from(path).unwrap();
+        assert!(emulator.cpu.mmu.is_bios_mapped());
+        assert_eq!(emulator.cpu.reg(Register::PC), 0);
+        assert_eq!(emulator.cpu.mmu.read_byte(0x00FF), 0x42);
+
+        // the lcd is off, the boot rom turns it on after uploading the logo
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF40), 0x00);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF44), 0);
+        assert_eq!(emulator.cpu.mmu.gpu.status().mode, 2);
+        assert_eq!(emulator.cpu.mmu.gpu.status().modeclock, 0);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF26), 0x70);
+
+        fs::write(path, vec![0x42; 0x200]).unwrap();
+        let error = emulator.load_bios_from(path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(path).unwrap();
+        assert!(emulator.load_bios_from(path).is_err());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        for _ in 0..10 {
+            emulator.step();
+        }
+        emulator.cpu.mmu.write_byte(0xC000, 0x42);
+        emulator.cpu.mmu.write_byte(0xFF80, 0x42);
+
+        emulator.reset();
+
+        let expected = [
+            (Register::AF, 0x01B0),
+            (Register::BC, 0x0013),
+            (Register::DE, 0x00D8),
+            (Register::HL, 0x014D),
+            (Register::SP, 0xFFFE),
+            (Register::PC, 0x0100),
+        ];
+        for (register, value) in expected.iter() {
+            assert_eq!(emulator.cpu.reg(*register), *value);
+        }
+
+        assert_eq!(emulator.cpu.mmu.read_byte(0xC000), 0);
+        assert_eq!(emulator.cpu.mmu.read_byte(0xFF80), 0);
+        assert_eq!(emulator.get_total_clocks(), 0);
+
+        // and it runs the game again from the start
+        let fresh = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+        assert_eq!(emulator.snapshot(), fresh.snapshot());
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        assert_eq!(emulator.run_cycles(0), 0);
+
+        // at most the longest operation plus an interrupt past the target
+        for target in [1, 1000, 70224].iter() {
+            let clocks = emulator.get_total_clocks();
+            let cycles = emulator.run_cycles(*target);
+            assert!(cycles >= *target);
+            assert!(cycles < *target + 24 + 12);
+            assert_eq!(emulator.get_total_clocks() - clocks, cycles as u64);
+        }
+    }
+
+    #[test]
+    fn test_run_until_frame() {
+        // a rom looping forever at its entry point, leaving the lcd alone
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        assert!(!emulator.is_frame_ready());
+        emulator.run_until_frame();
+
+        // a frame apart, give or take how far the instructions went past it
+        for _ in 0..3 {
+            assert!(emulator.is_frame_ready());
+            assert_eq!(emulator.cpu.mmu.read_byte(0xFF44), 144);
+
+            let cycles = emulator.run_until_frame();
+            assert!(cycles > 70224 - 24 - 12);
+            assert!(cycles < 70224 + 24 + 12);
+        }
+    }
+
+    #[test]
+    fn test_step_frame() {
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+
+        // square 1 playing loud on both sides
+        emulator.cpu.mmu.write_byte(0xFF26, 0x80);
+        emulator.cpu.mmu.write_byte(0xFF24, 0x77);
+        emulator.cpu.mmu.write_byte(0xFF25, 0xFF);
+        emulator.cpu.mmu.write_byte(0xFF11, 0x80);
+        emulator.cpu.mmu.write_byte(0xFF12, 0xF0);
+        emulator.cpu.mmu.write_byte(0xFF14, 0x87);
+
+        emulator.step_frame();
+        let samples_in_a_frame = SAMPLE_RATE * CLOCKS_IN_A_FRAME as usize / 4_194_304;
+        for _ in 0..3 {
+            let output = emulator.step_frame();
+            assert!(emulator.is_frame_ready());
+            assert_eq!(output.video.len(), 160 * 144 * 3);
+
+            // give or take a sample, depending on where the frame ends
+            assert!(output.audio.len() >= samples_in_a_frame - 1);
+            assert!(output.audio.len() <= samples_in_a_frame + 2);
+            assert!(output.audio.iter().any(|sample| *sample != 0));
+        }
+    }
+
+    // the samples left at the end of a frame stay in the buffer the sink gets
+    #[test]
+    fn test_step_frame_audio_sink() {
+        struct RecordingSink {
+            received: Rc<RefCell<usize>>,
+        }
+
+        impl AudioSink for RecordingSink {
+            fn submit(&mut self, samples: &[AudioOutType]) {
+                *self.received.borrow_mut() += samples.len();
+            }
+        }
+
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        let received = Rc::new(RefCell::new(0));
+        emulator
+            .cpu
+            .mmu
+            .sound
+            .set_audio_sink(Box::new(RecordingSink {
+                received: received.clone(),
+            }));
+
+        for _ in 0..10 {
+            assert!(emulator.step_frame().audio.is_empty());
+        }
+
+        // all but the buffer being filled, give or take the first frame
+        let samples_in_a_frame = SAMPLE_RATE * CLOCKS_IN_A_FRAME as usize / 4_194_304;
+        let received = *received.borrow();
+        assert_eq!(received % AUDIO_BUFFER_SIZE, 0);
+        assert!(received + AUDIO_BUFFER_SIZE > 9 * samples_in_a_frame);
+        assert!(received <= 10 * samples_in_a_frame);
+    }
+
+    // the gpu runs along with every cycle of the instructions, interrupts included
+    #[test]
+    fn test_gpu_lockstep() {
+        let mut rom = fs::read("tests/cpu_instrs/01-special.gb").unwrap();
+        // EI, then HALT in a loop, woken up by the vblank handler at 0x40: RETI
+        rom[0x100..0x104].copy_from_slice(&[0xFB, 0x76, 0x18, 0xFD]);
+        rom[0x40] = 0xD9;
+        let mut emulator = Emulator::from_bytes(rom).unwrap();
+        emulator.cpu.mmu.write_byte(0xFFFF, 0x01);
+
+        let mut cycles = 0u32;
+        let mut interrupts = 0;
+        for _ in 0..60_000 {
+            let t = emulator.step_instruction().unwrap();
+            cycles += t as u32;
+            // the operation and the interrupt dispatch in the same step
+            if t > 12 {
+                interrupts += 1;
+            }
+
+            let line = emulator.cpu.mmu.read_byte(0xFF44) as u32;
+            assert_eq!(line, cycles / 456 % 154, "after {} cycles", cycles);
+        }
+        assert_eq!(emulator.get_total_clocks(), cycles as u64);
+        assert!(cycles > 3 * CLOCKS_IN_A_FRAME);
+        assert!(interrupts >= 3);
+    }
+
+    #[test]
+    fn test_set_lyc() {
+        let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+        // enable the LYC=LY stat interrupt
+        emulator.cpu.mmu.write_byte(0xFF41, 0x40);
+
+        emulator.set_lyc(5);
+        assert_eq!(emulator.get_lyc(), 5);
+        assert_eq!(emulator.cpu.mmu.interrupt_flags & 2, 0);
+
+        // the gpu is at line 0
+        emulator.set_lyc(0);
+        assert_eq!(emulator.get_lyc(), 0);
+        assert_eq!(emulator.cpu.mmu.interrupt_flags & 2, 2);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::cpu::is_bit_set;
+use crate::state::{SaveState, StateReader, StateWriter};
+use std::io;
 use std::iter;
 
 const TILES_IN_A_TILEMAP_ROW: usize = 32;
@@ -14,14 +16,38 @@ const TILEDATA1_OFFSET: usize = 0;
 const TILEDATA0_OFFSET: usize = 0x9000 - 0x8000;
 const TILEDATA_SHARED: usize = 0x8800 - 0x8000; // when tile index >= 128
 
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+
+const TILES_IN_VRAM: usize = 384;
+const TILES_IN_A_DUMP_ROW: usize = 16;
+
+const LINE_CYCLES: u16 = 456;
+const MODE3_BASE_CYCLES: u16 = 172;
+
 /// Expose the memories of the GPU
 pub trait GPUMemoriesAccess {
-    fn read_oam(&mut self, addr: u16) -> u8;
+    fn read_oam(&self, addr: u16) -> u8;
     fn write_oam(&mut self, addr: u16, byte: u8);
-    fn read_vram(&mut self, addr: u16) -> u8;
+    // vram addresses go from 0 to 0x3FFF, the second 0x2000 bytes are the cgb bank 1
+    fn read_vram(&self, addr: u16) -> u8;
     fn write_vram(&mut self, addr: u16, byte: u8);
-    fn read_byte(&mut self, addr: u16) -> u8;
+    fn read_byte(&self, addr: u16) -> u8;
     fn write_byte(&mut self, addr: u16, byte: u8);
+
+    // switches the cgb colour palettes and tile attributes on or off
+    fn set_cgb_mode(&mut self, _enabled: bool) {}
+
+    // the mode deciding what the cpu can access: 2 and 3 lock the oam, 3 the vram too
+    fn mode(&self) -> u8 {
+        0
+    }
+
+    // back to the power on state, keeping what the frontend configured
+    fn reset(&mut self);
+
+    // runs for t cycles of the normal speed clock. Returns whether to raise the vblank
+    // and the stat interrupts
+    fn step(&mut self, t: u8) -> (bool, bool);
 }
 
 #[derive(Clone, Copy)]
@@ -34,24 +60,20 @@ pub enum Colour {
 }
 
 impl Colour {
-    pub fn from_u8(value: u8) -> Self {
-        match value {
-            1 => Colour::Light,
-            2 => Colour::Dark,
-            3 => Colour::On,
-            _ => Colour::Off,
-        }
+    // the raw 2 bit shade, as stored in the palettes
+    pub fn to_u8(self) -> u8 {
+        self as u8
     }
 }
 
+// only the low 2 bits count, like in the palette registers
 impl From<u8> for Colour {
     fn from(val: u8) -> Self {
-        match val {
+        match val & 0b11 {
             0 => Colour::Off,
             1 => Colour::Light,
             2 => Colour::Dark,
-            3 => Colour::On,
-            _ => panic!("Impossible colour"),
+            _ => Colour::On,
         }
     }
 }
@@ -85,19 +107,125 @@ impl Palette {
     }
 
     fn update(&mut self, value: u8) {
-        self.colour_0 = Colour::from_u8(value & 0b0000_0011);
-        self.colour_1 = Colour::from_u8((value & 0b0000_1100) >> 2);
-        self.colour_2 = Colour::from_u8((value & 0b0011_0000) >> 4);
-        self.colour_3 = Colour::from_u8((value & 0b1100_0000) >> 6);
+        self.colour_0 = Colour::from(value);
+        self.colour_1 = Colour::from(value >> 2);
+        self.colour_2 = Colour::from(value >> 4);
+        self.colour_3 = Colour::from(value >> 6);
         self.byte = value;
     }
 }
 
+// cgb colour palettes: 8 palettes of 4 colours, each colour 15 bit RGB in two bytes,
+// little endian. Accessed one byte at a time through an index and a data register
+struct ColourPaletteRam {
+    data: [u8; 64],
+    index: u8,            // the byte the data register accesses
+    auto_increment: bool, // index goes forward after writing the data register
+}
+
+impl ColourPaletteRam {
+    fn new() -> Self {
+        ColourPaletteRam {
+            data: [0; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        (if self.auto_increment { 0x80 } else { 0 }) | 0x40 | self.index
+    }
+
+    fn write_index(&mut self, byte: u8) {
+        self.index = byte & 0x3F;
+        self.auto_increment = byte & 0x80 != 0;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.data[self.index as usize] = byte;
+
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    // the 15 bit colour of a colour number in a palette
+    fn colour(&self, palette: u8, colour_number: u8) -> u16 {
+        let index = (palette as usize * 4 + colour_number as usize) * 2;
+        (self.data[index] as u16 | (self.data[index + 1] as u16) << 8) & 0x7FFF
+    }
+}
+
+// cgb attributes of a bg/window tile, stored in vram bank 1 at the same tilemap position
+struct TileAttributes {
+    palette: u8,    // which bg colour palette
+    bank: bool,     // vram bank of the tile data
+    flip_x: bool,   // 1 = flipped horizontally
+    flip_y: bool,   // 1 = flipped vertically
+    priority: bool, // 1 = above sprites (unless colour is 0)
+}
+
+impl TileAttributes {
+    fn from_byte(byte: u8) -> Self {
+        TileAttributes {
+            palette: byte & 0b111,
+            bank: byte & 0x08 != 0,
+            flip_x: byte & 0x20 != 0,
+            flip_y: byte & 0x40 != 0,
+            priority: byte & 0x80 != 0,
+        }
+    }
+}
+
+// what ended up being drawn on a pixel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelSource {
+    Background,
+    Window,
+    Sprite,
+}
+
+pub struct PixelInfo {
+    pub bg_color: u8,     // colour number of the bg/window, before palette application
+    pub final_shade: u16, // the shade on screen after palette application, the 15 bit colour on cgb
+    pub source: PixelSource,
+}
+
+// a snapshot of where the gpu is in the frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpuStatus {
+    pub line: u8,
+    pub mode: u8,
+    pub modeclock: u16,
+    pub compare: bool, // line == compare line
+}
+
+// an oam entry, decoded for debuggers. The coordinates are the oam ones,
+// the screen position plus 8 for x and 16 for y
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpriteInfo {
+    pub y: u8,
+    pub x: u8,
+    pub tile_number: u8,
+    pub behind_bg: bool, // only shows over bg colour 0
+    pub flip_y: bool,
+    pub flip_x: bool,
+    pub palette: u8,     // dmg object palette, 0 or 1
+    pub cgb_palette: u8, // cgb only, object colour palette
+    pub bank: u8,        // cgb only, vram bank of the tile
+}
+
 struct SpriteOptions {
-    z: bool,       // 0 = above background, 1 = below background (unless colour is 0)
-    flip_y: bool,  // 1 = flipped vertically
-    flip_x: bool,  // 1 = flipped horizontally
-    palette: bool, // 0 meanse use object palette 0, 1 means use object palette 1
+    z: bool,         // 0 = above background, 1 = below background (unless colour is 0)
+    flip_y: bool,    // 1 = flipped vertically
+    flip_x: bool,    // 1 = flipped horizontally
+    palette: bool,   // 0 meanse use object palette 0, 1 means use object palette 1
+    cgb_palette: u8, // cgb only, which object colour palette
+    bank: bool,      // cgb only, vram bank of the tile data
 }
 
 impl SpriteOptions {
@@ -107,10 +235,14 @@ impl SpriteOptions {
             flip_y: false,
             flip_x: false,
             palette: false,
+            cgb_palette: 0,
+            bank: false,
         }
     }
 
     pub fn update(&mut self, value: u8) {
+        self.cgb_palette = value & 0b111;
+        self.bank = (value & 0x08) != 0;
         self.palette = (value & 0x10) != 0;
         self.flip_x = (value & 0x20) != 0;
         self.flip_y = (value & 0x40) != 0;
@@ -118,7 +250,9 @@ impl SpriteOptions {
     }
 
     pub fn byte(&self) -> u8 {
-        (if self.palette { 0x10 } else { 0 })
+        self.cgb_palette
+            | (if self.bank { 0x08 } else { 0 })
+            | (if self.palette { 0x10 } else { 0 })
             | (if self.flip_x { 0x20 } else { 0 })
             | (if self.flip_y { 0x40 } else { 0 })
             | (if self.z { 0x80 } else { 0 })
@@ -167,10 +301,28 @@ impl Sprite {
     }
 }
 
+// the scroll and window registers a line is drawn with, taken when its mode 3 starts.
+// Raster effects changing them mid line only show from the next one
+#[derive(Clone, Copy, Default)]
+struct LineRegisters {
+    scroll_x: u8,
+    scroll_y: u8,
+    window_x: u8,
+    window_y: u8,
+}
+
+// when the screen gets drawn. WholeFrame draws all the lines at once as vblank starts,
+// with the registers the frame ended with: it's faster, but raster effects are lost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    PerScanline,
+    WholeFrame,
+}
+
 pub struct GPU {
-    vram: [u8; 8192],
-    sprites: Vec<Sprite>,    // todo: make it an array of 40
-    buffer: [u8; 160 * 144], // every pixel can have 4 values (4 shades of grey)
+    vram: [u8; 2 * VRAM_BANK_SIZE], // bank 1 is only reachable in cgb mode
+    sprites: Vec<Sprite>,           // todo: make it an array of 40
+    buffer: [u8; 160 * 144],        // every pixel can have 4 values (4 shades of grey)
 
     modeclock: u16,
     mode: u8,
@@ -187,6 +339,7 @@ pub struct GPU {
 
     compare_enabled: bool, // stat reg. Should compare with compare line?
     compare_line: u8,      // when line == compare_line an interrupt is triggered
+    compare_interrupt_pending: bool, // raised by a write to the compare line, reported on next step
 
     scroll_x: u8,
     scroll_y: u8,
@@ -195,10 +348,132 @@ pub struct GPU {
     obj_palette_1: Palette,
     window_x: u8,
     window_y: u8,
+    window_line_counter: u8, // window row to draw next, only advances on lines the window is drawn
+    window_rows: [u8; 144],  // window row drawn on each line, for pixel_at
+    line_registers: LineRegisters,
+
+    variable_mode3: bool, // mode 3 lasts longer with fine scrolling, window and sprites?
+    mode3_length: u16,    // cycles the current line spends in mode 3
+    render_mode: RenderMode,
+
+    // cgb only
+    cgb_mode: bool,
+    bg_colour_palettes: ColourPaletteRam,
+    obj_colour_palettes: ColourPaletteRam,
+    cgb_buffer: [u16; 160 * 144], // 15 bit colours, drawn instead of buffer in cgb mode
+}
+
+impl SaveState for GPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram);
+        for sprite in self.sprites.iter() {
+            for property in 0..4 {
+                state.write_u8(sprite.get(property));
+            }
+        }
+        state.write_bytes(&self.buffer);
+
+        state.write_u16(self.modeclock);
+        state.write_u8(self.mode);
+        state.write_u8(self.line);
+
+        state.write_bool(self.bg_enabled);
+        state.write_bool(self.obj_enabled);
+        state.write_bool(self.obj_size);
+        state.write_bool(self.bg_map);
+        state.write_bool(self.bg_tile);
+        state.write_bool(self.window_enabled);
+        state.write_bool(self.window_map);
+        state.write_bool(self.lcd_enabled);
+
+        state.write_bool(self.compare_enabled);
+        state.write_u8(self.compare_line);
+        state.write_bool(self.compare_interrupt_pending);
+
+        state.write_u8(self.scroll_x);
+        state.write_u8(self.scroll_y);
+        state.write_u8(self.bg_palette.byte);
+        state.write_u8(self.obj_palette_0.byte);
+        state.write_u8(self.obj_palette_1.byte);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_y);
+        state.write_u8(self.window_line_counter);
+        state.write_bytes(&self.window_rows);
+        state.write_u8(self.line_registers.scroll_x);
+        state.write_u8(self.line_registers.scroll_y);
+        state.write_u8(self.line_registers.window_x);
+        state.write_u8(self.line_registers.window_y);
+
+        state.write_bool(self.variable_mode3);
+        state.write_u16(self.mode3_length);
+
+        state.write_bool(self.cgb_mode);
+        for palettes in [&self.bg_colour_palettes, &self.obj_colour_palettes].iter() {
+            state.write_bytes(&palettes.data);
+            state.write_u8(palettes.read_index());
+        }
+        for colour in self.cgb_buffer.iter() {
+            state.write_u16(*colour);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_bytes(&mut self.vram)?;
+        for sprite in self.sprites.iter_mut() {
+            for property in 0..4 {
+                sprite.update(property, state.read_u8()?);
+            }
+        }
+        state.read_bytes(&mut self.buffer)?;
+
+        self.modeclock = state.read_u16()?;
+        self.mode = state.read_u8()? & 0b11;
+        self.line = state.read_u8()?;
+
+        self.bg_enabled = state.read_bool()?;
+        self.obj_enabled = state.read_bool()?;
+        self.obj_size = state.read_bool()?;
+        self.bg_map = state.read_bool()?;
+        self.bg_tile = state.read_bool()?;
+        self.window_enabled = state.read_bool()?;
+        self.window_map = state.read_bool()?;
+        self.lcd_enabled = state.read_bool()?;
+
+        self.compare_enabled = state.read_bool()?;
+        self.compare_line = state.read_u8()?;
+        self.compare_interrupt_pending = state.read_bool()?;
+
+        self.scroll_x = state.read_u8()?;
+        self.scroll_y = state.read_u8()?;
+        self.bg_palette.update(state.read_u8()?);
+        self.obj_palette_0.update(state.read_u8()?);
+        self.obj_palette_1.update(state.read_u8()?);
+        self.window_x = state.read_u8()?;
+        self.window_y = state.read_u8()?;
+        self.window_line_counter = state.read_u8()?;
+        state.read_bytes(&mut self.window_rows)?;
+        self.line_registers.scroll_x = state.read_u8()?;
+        self.line_registers.scroll_y = state.read_u8()?;
+        self.line_registers.window_x = state.read_u8()?;
+        self.line_registers.window_y = state.read_u8()?;
+
+        self.variable_mode3 = state.read_bool()?;
+        self.mode3_length = state.read_u16()?;
+
+        self.cgb_mode = state.read_bool()?;
+        for palettes in [&mut self.bg_colour_palettes, &mut self.obj_colour_palettes].iter_mut() {
+            state.read_bytes(&mut palettes.data)?;
+            palettes.write_index(state.read_u8()?);
+        }
+        for colour in self.cgb_buffer.iter_mut() {
+            *colour = state.read_u16()?;
+        }
+        Ok(())
+    }
 }
 
 impl GPUMemoriesAccess for GPU {
-    fn read_oam(&mut self, addr: u16) -> u8 {
+    fn read_oam(&self, addr: u16) -> u8 {
         let sprite_num = addr >> 2;
         if sprite_num > 39 {
             panic!("Tried to access sprite number {}", sprite_num);
@@ -216,13 +491,13 @@ impl GPUMemoriesAccess for GPU {
         let property = (addr & 3) as u8;
         self.sprites[sprite_num as usize].update(property, byte);
     }
-    fn read_vram(&mut self, addr: u16) -> u8 {
+    fn read_vram(&self, addr: u16) -> u8 {
         self.vram[addr as usize]
     }
     fn write_vram(&mut self, addr: u16, byte: u8) {
         self.vram[addr as usize] = byte
     }
-    fn read_byte(&mut self, addr: u16) -> u8 {
+    fn read_byte(&self, addr: u16) -> u8 {
         match addr {
             0xFF40 => {
                 (if self.bg_enabled { 0x01 } else { 0 })
@@ -247,6 +522,10 @@ impl GPUMemoriesAccess for GPU {
             0xFF49 => self.obj_palette_1.byte,
             0xFF4A => self.window_y,
             0xFF4B => self.window_x,
+            0xFF68 if self.cgb_mode => self.bg_colour_palettes.read_index(),
+            0xFF69 if self.cgb_mode => self.bg_colour_palettes.read_data(),
+            0xFF6A if self.cgb_mode => self.obj_colour_palettes.read_index(),
+            0xFF6B if self.cgb_mode => self.obj_colour_palettes.read_data(),
             _ => 0,
         }
     }
@@ -268,16 +547,17 @@ impl GPUMemoriesAccess for GPU {
             }
             0xFF42 => {
                 self.scroll_y = byte;
+                self.latch_line_registers_outside_mode3();
             }
             0xFF43 => {
                 self.scroll_x = byte;
+                self.latch_line_registers_outside_mode3();
             }
             0xFF44 => {
-                self.line = 0;
-                println!("line reset");
+                // LY is read only
             }
             0xFF45 => {
-                self.compare_line = byte;
+                self.compare_interrupt_pending |= self.set_compare_line(byte);
             }
             0xFF46 => {
                 // DMA transfer, handled from outside
@@ -293,19 +573,125 @@ impl GPUMemoriesAccess for GPU {
             }
             0xFF4A => {
                 self.window_y = byte;
+                self.latch_line_registers_outside_mode3();
             }
             0xFF4B => {
                 self.window_x = byte;
+                self.latch_line_registers_outside_mode3();
             }
+            0xFF68 if self.cgb_mode => self.bg_colour_palettes.write_index(byte),
+            0xFF69 if self.cgb_mode => self.bg_colour_palettes.write_data(byte),
+            0xFF6A if self.cgb_mode => self.obj_colour_palettes.write_index(byte),
+            0xFF6B if self.cgb_mode => self.obj_colour_palettes.write_data(byte),
             _ => {}
         }
     }
+
+    fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    // with the lcd off, everything is accessible
+    fn reset(&mut self) {
+        let cgb_mode = self.cgb_mode;
+        let variable_mode3 = self.variable_mode3;
+        let render_mode = self.render_mode;
+
+        *self = GPU::new();
+        self.cgb_mode = cgb_mode;
+        self.variable_mode3 = variable_mode3;
+        self.render_mode = render_mode;
+    }
+
+    fn mode(&self) -> u8 {
+        if self.lcd_enabled {
+            self.mode
+        } else {
+            0
+        }
+    }
+
+    // go forward based on the cpu's last operation clocks. The clocks past the end
+    // of a mode count towards the next one, so that a frame always lasts 70224 cycles.
+    // Returns whether to raise the vblank and the stat interrupts
+    fn step(&mut self, t: u8) -> (bool, bool) {
+        self.modeclock += t as u16;
+
+        let mut vblank_interrupt: bool = false;
+        let mut compare_interrupt: bool = self.compare_interrupt_pending;
+        self.compare_interrupt_pending = false;
+
+        // todo: implement it as a state machine?
+        match self.mode {
+            // scanline, oam read mode
+            2 => {
+                if self.modeclock >= 80 {
+                    self.modeclock -= 80;
+                    self.mode = 3;
+                    self.latch_line_registers();
+                    self.mode3_length = self.compute_mode3_length();
+                }
+            }
+            // scanline, vram read mode
+            3 => {
+                if self.modeclock >= self.mode3_length {
+                    // enter hblank mode
+                    self.modeclock -= self.mode3_length;
+                    self.mode = 0;
+
+                    if self.render_mode == RenderMode::PerScanline {
+                        self.render_scan_to_buffer();
+                    }
+                }
+            }
+            // hblank, whatever mode 3 left of the line
+            0 => {
+                let hblank_length = LINE_CYCLES - 80 - self.mode3_length;
+                if self.modeclock >= hblank_length {
+                    self.modeclock -= hblank_length;
+                    self.line += 1;
+
+                    if self.line == 144 {
+                        // enter vblank mode
+                        self.mode = 1;
+                        if self.render_mode == RenderMode::WholeFrame {
+                            self.render_frame_to_buffer();
+                        }
+                        vblank_interrupt = true;
+                    } else {
+                        self.mode = 2;
+                    }
+
+                    compare_interrupt = self.check_compare_int();
+                }
+            }
+            // vblank (10 lines)
+            1 => {
+                if self.modeclock >= LINE_CYCLES {
+                    self.modeclock -= LINE_CYCLES;
+                    self.line += 1;
+
+                    // restart
+                    if self.line > 153 {
+                        self.mode = 2;
+                        self.line = 0;
+                        self.window_line_counter = 0;
+                    }
+
+                    compare_interrupt = self.check_compare_int();
+                }
+            }
+            _ => panic!("Sorry what?"),
+        }
+
+        (vblank_interrupt, compare_interrupt)
+    }
 }
 
 impl GPU {
     pub fn new() -> Self {
         GPU {
-            vram: [0; 8192],
+            vram: [0; 2 * VRAM_BANK_SIZE],
             sprites: iter::repeat_with(Sprite::new).take(40).collect(),
             buffer: [0; 160 * 144],
             modeclock: 0,
@@ -321,6 +707,7 @@ impl GPU {
             lcd_enabled: false,
             compare_enabled: false,
             compare_line: 0,
+            compare_interrupt_pending: false,
             scroll_x: 0,
             scroll_y: 0,
             bg_palette: Palette::new(),
@@ -328,6 +715,18 @@ impl GPU {
             obj_palette_1: Palette::new(),
             window_x: 0,
             window_y: 0,
+            window_line_counter: 0,
+            window_rows: [0; 144],
+            line_registers: LineRegisters::default(),
+
+            variable_mode3: true,
+            mode3_length: MODE3_BASE_CYCLES,
+            render_mode: RenderMode::PerScanline,
+
+            cgb_mode: false,
+            bg_colour_palettes: ColourPaletteRam::new(),
+            obj_colour_palettes: ColourPaletteRam::new(),
+            cgb_buffer: [0; 160 * 144],
         }
     }
 
@@ -335,286 +734,701 @@ impl GPU {
         self.line == self.compare_line
     }
 
-    pub fn get_buffer(&self) -> &[u8; 160 * 144] {
-        &self.buffer
+    pub fn get_compare_line(&self) -> u8 {
+        self.compare_line
     }
 
-    fn get_tileset_index(&self, mut index: u8) -> usize {
-        let mut offset: usize = if self.bg_tile {
-            TILEDATA1_OFFSET
-        } else {
-            TILEDATA0_OFFSET
-        };
+    // updates the compare line, returns true if compare stat interrupt should raise.
+    // The interrupt is edge triggered: it raises only if the coincidence wasn't there already
+    pub fn set_compare_line(&mut self, compare_line: u8) -> bool {
+        let was_raised = self.check_compare_int();
 
-        if index >= 128 {
-            offset = TILEDATA_SHARED;
-            index -= 128;
-        }
+        self.compare_line = compare_line;
 
-        offset + 2 * TILE_SIZE * (index as usize)
+        !was_raised && self.check_compare_int()
     }
 
-    // draws a line on the buffer
-    pub fn render_scan_to_buffer(&mut self) {
-        let line_to_draw: usize = self.line.wrapping_add(self.scroll_y) as usize;
+    // true if a write to the compare line raised the stat interrupt, and the next step will report it
+    pub fn is_compare_interrupt_pending(&self) -> bool {
+        self.compare_interrupt_pending
+    }
 
-        // save colour numbers being rendered before palette application. 0 is transparent
-        let mut rendering_row = [0u8; 160];
+    // with false mode 3 always lasts 172 cycles, like it used to
+    pub fn set_variable_mode3(&mut self, enabled: bool) {
+        self.variable_mode3 = enabled;
+    }
 
-        // background
-        if self.bg_enabled {
-            let tilemap_offset = if self.bg_map {
-                TILEMAP1_OFFSET
-            } else {
-                TILEMAP0_OFFSET
-            };
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    pub fn get_render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
 
-            // the row of the cell in the tilemap
-            let tilemap_y: usize = (line_to_draw / TILE_SIZE) % TILES_IN_A_TILEMAP_COL;
+    // takes the scroll and window registers the line is drawn with
+    fn latch_line_registers(&mut self) {
+        self.line_registers = LineRegisters {
+            scroll_x: self.scroll_x,
+            scroll_y: self.scroll_y,
+            window_x: self.window_x,
+            window_y: self.window_y,
+        };
+    }
 
-            // the row of the pixel in the cell
-            let cell_y: usize = line_to_draw % TILE_SIZE;
+    // a write outside mode 3 is in time for the next line to be drawn
+    fn latch_line_registers_outside_mode3(&mut self) {
+        if self.mode != 3 {
+            self.latch_line_registers();
+        }
+    }
 
-            // for each pixel in the line (which is long 160 pixel)
-            #[allow(clippy::needless_range_loop)]
-            for row_pixel in 0..TILES_IN_A_SCREEN_ROW * TILE_SIZE {
-                let curr_pixel_x = self.scroll_x as usize + row_pixel;
+    // how long mode 3 lasts on the current line. The pixel fifo stalls
+    // to discard the fine scroll_x pixels, to restart fetching at the window
+    // and to fetch every sprite on the line (up to 10)
+    fn compute_mode3_length(&self) -> u16 {
+        if !self.variable_mode3 {
+            return MODE3_BASE_CYCLES;
+        }
 
-                // the col of the cell in the tilemap
-                let tilemap_x: usize = (curr_pixel_x / TILE_SIZE) % TILES_IN_A_TILEMAP_ROW;
+        let mut length = MODE3_BASE_CYCLES + (self.line_registers.scroll_x % 8) as u16;
 
-                // the col of the pixel in the cell
-                let cell_x: usize = curr_pixel_x % TILE_SIZE;
+        if self.window_enabled
+            && self.line_registers.window_y <= self.line
+            && self.window_screen_x() < 160
+        {
+            length += 6;
+        }
 
-                // find the tile in the vram
-                let tilemap_index =
-                    tilemap_offset + (tilemap_y * TILES_IN_A_TILEMAP_ROW + tilemap_x) as usize;
+        if self.obj_enabled {
+            for sprite in self
+                .oam_search(self.line)
+                .into_iter()
+                .map(|index| &self.sprites[index])
+            {
+                // sprites past the right edge aren't fetched
+                if sprite.get(1) >= 168 {
+                    continue;
+                }
+                // the fetch waits for the bg tile under the sprite to be done
+                let tile_offset = sprite.x.wrapping_add(self.line_registers.scroll_x) % 8;
+                length += 6 + 5 - (tile_offset as u16).min(5);
+            }
+        }
 
-                let pos = self.vram[tilemap_index];
+        length
+    }
 
-                // find out the row in the tile data
-                let tileset_index: usize = self.get_tileset_index(pos) + 2 * cell_y as usize;
+    pub fn status(&self) -> GpuStatus {
+        GpuStatus {
+            line: self.line,
+            mode: self.mode,
+            modeclock: self.modeclock,
+            compare: self.compare(),
+        }
+    }
 
-                // a tile pixel line is encoded in two consecutive bytes
-                let byte_1 = self.vram[tileset_index];
-                let byte_2 = self.vram[tileset_index + 1];
+    pub fn get_buffer(&self) -> &[u8; 160 * 144] {
+        &self.buffer
+    }
 
-                // get the pixel colour from the line
-                let high_bit: u8 = is_bit_set(7 - cell_x as u8, byte_2 as u16) as u8;
-                let low_bit: u8 = is_bit_set(7 - cell_x as u8, byte_1 as u16) as u8;
-                let colour_number = (high_bit << 1) + low_bit;
-                let palette_colour = self.bg_palette.get(colour_number);
+    // the screen in cgb mode, 15 bit colours: red in the low bits, then green, then blue
+    pub fn get_cgb_buffer(&self) -> &[u16; 160 * 144] {
+        &self.cgb_buffer
+    }
 
-                rendering_row[row_pixel] = colour_number;
+    // the buffer expanded to RGB, 3 bytes per pixel, row after row
+    pub fn get_rgb_buffer(&self, palette: &[(u8, u8, u8); 4]) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.buffer.len() * 3);
 
-                let index: usize =
-                    (self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE) + row_pixel;
-                self.buffer[index] = palette_colour as u8;
-            }
+        for &pixel in self.buffer.iter() {
+            let (r, g, b) = palette[pixel as usize];
+            rgb.extend_from_slice(&[r, g, b]);
         }
 
-        // window
-        if self.window_enabled && self.window_y <= self.line {
-            // window_x is treated as 7 if it's anywhere from 0-6
-            let window_x = (if self.window_x < 7 { 7 } else { self.window_x }).wrapping_sub(7);
-            let tilemap_offset = if self.window_map {
-                TILEMAP1_OFFSET
-            } else {
-                TILEMAP0_OFFSET
-            };
+        rgb
+    }
 
-            let window_line: usize = self.line.wrapping_sub(self.window_y) as usize;
+    // the oam entry of sprite index, from 0 to 39
+    pub fn sprite(&self, index: usize) -> SpriteInfo {
+        let sprite = &self.sprites[index];
+
+        SpriteInfo {
+            y: sprite.get(0),
+            x: sprite.get(1),
+            tile_number: sprite.tile_number,
+            behind_bg: sprite.options.z,
+            flip_y: sprite.options.flip_y,
+            flip_x: sprite.options.flip_x,
+            palette: sprite.options.palette as u8,
+            cgb_palette: sprite.options.cgb_palette,
+            bank: sprite.options.bank as u8,
+        }
+    }
 
-            // the row of the cell in the window tilemap
-            let tilemap_y: usize = (window_line / TILE_SIZE) % TILES_IN_A_TILEMAP_COL;
+    // the colour numbers of a tile, row after row. Tiles are numbered from the start
+    // of the vram, 384 per bank: the ones past 383 are in cgb bank 1, past 767 there are none
+    pub fn tile_pixels(&self, tile_index: usize) -> [[u8; 8]; 8] {
+        debug_assert!(tile_index < 2 * TILES_IN_VRAM, "no tile {}", tile_index);
 
-            // the row of the pixel in the cell
-            let cell_y: usize = window_line % TILE_SIZE;
+        let mut pixels = [[0; 8]; 8];
+        let bank_offset = (tile_index / TILES_IN_VRAM) * VRAM_BANK_SIZE;
+        let tile_start = bank_offset + (tile_index % TILES_IN_VRAM) * 2 * TILE_SIZE;
 
-            #[allow(clippy::needless_range_loop)]
-            for pixel in (window_x as usize)..TILES_IN_A_SCREEN_ROW * TILE_SIZE {
-                let mut curr_pixel_x = (pixel as u8).wrapping_add(self.scroll_x);
-                if curr_pixel_x >= window_x {
-                    curr_pixel_x = pixel as u8 - window_x;
-                }
+        for (row, row_pixels) in pixels.iter_mut().enumerate() {
+            for (column, pixel) in row_pixels.iter_mut().enumerate() {
+                *pixel = self.tile_row_colour_number(tile_start + 2 * row, column as u8);
+            }
+        }
+        pixels
+    }
 
-                // the col of the cell in the tilemap
-                let tilemap_x: usize = (curr_pixel_x as usize / TILE_SIZE) % TILES_IN_A_TILEMAP_ROW;
+    // the 384 tiles of vram bank 0 in a 16x24 tiles grid, 128x192 pixels,
+    // coloured with the bg palette. For debugging tools
+    pub fn dump_tiles(&self) -> Vec<u8> {
+        let width = TILES_IN_A_DUMP_ROW * TILE_SIZE;
+        let mut dump = vec![0; TILES_IN_VRAM * TILE_SIZE * TILE_SIZE];
 
-                // the col of the pixel in the cell
-                let cell_x: usize = curr_pixel_x as usize % TILE_SIZE;
+        for tile in 0..TILES_IN_VRAM {
+            let tile_x = (tile % TILES_IN_A_DUMP_ROW) * TILE_SIZE;
+            let tile_y = (tile / TILES_IN_A_DUMP_ROW) * TILE_SIZE;
 
-                // find the tile in the vram
-                let tilemap_index =
-                    tilemap_offset + (tilemap_y * TILES_IN_A_TILEMAP_ROW + tilemap_x) as usize;
+            for (row, row_pixels) in self.tile_pixels(tile).iter().enumerate() {
+                for (column, &colour_number) in row_pixels.iter().enumerate() {
+                    dump[(tile_y + row) * width + tile_x + column] =
+                        self.bg_palette.get(colour_number).to_u8();
+                }
+            }
+        }
 
-                let pos = self.vram[tilemap_index];
+        dump
+    }
 
-                // find out the row in the tile data
-                let tileset_index: usize = self.get_tileset_index(pos) + 2 * cell_y as usize;
+    fn get_tileset_index(&self, mut index: u8) -> usize {
+        let mut offset: usize = if self.bg_tile {
+            TILEDATA1_OFFSET
+        } else {
+            TILEDATA0_OFFSET
+        };
 
-                // a tile pixel line is encoded in two consecutive bytes
-                let byte_1 = self.vram[tileset_index];
-                let byte_2 = self.vram[tileset_index + 1];
+        if index >= 128 {
+            offset = TILEDATA_SHARED;
+            index -= 128;
+        }
 
-                // get the pixel colour from the line
-                let high_bit: u8 = is_bit_set(7 - cell_x as u8, byte_2 as u16) as u8;
-                let low_bit: u8 = is_bit_set(7 - cell_x as u8, byte_1 as u16) as u8;
-                let colour_number = (high_bit << 1) + low_bit;
-                let palette_colour = self.bg_palette.get(colour_number);
+        offset + 2 * TILE_SIZE * (index as usize)
+    }
 
-                rendering_row[pixel] = colour_number;
+    fn bg_tilemap_offset(&self) -> usize {
+        if self.bg_map {
+            TILEMAP1_OFFSET
+        } else {
+            TILEMAP0_OFFSET
+        }
+    }
 
-                let index: usize = (self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE) + pixel;
-                self.buffer[index] = palette_colour as u8;
-            }
+    fn window_tilemap_offset(&self) -> usize {
+        if self.window_map {
+            TILEMAP1_OFFSET
+        } else {
+            TILEMAP0_OFFSET
         }
+    }
 
-        // sprites
-        if self.obj_enabled {
-            let sprite_height: u8 = if self.obj_size { 16 } else { 8 };
+    // window_x is treated as 7 if it's anywhere from 0-6
+    fn window_screen_x(&self) -> u8 {
+        (if self.line_registers.window_x < 7 {
+            7
+        } else {
+            self.line_registers.window_x
+        })
+        .wrapping_sub(7)
+    }
 
-            for sprite_num in 0..40 {
-                let sprite = &self.sprites[sprite_num];
+    // the x coordinate inside the window tilemap of a screen pixel
+    fn window_tilemap_x(&self, pixel: u8, window_x: u8) -> u8 {
+        let curr_pixel_x = pixel.wrapping_add(self.line_registers.scroll_x);
+        if curr_pixel_x >= window_x {
+            pixel - window_x
+        } else {
+            curr_pixel_x
+        }
+    }
 
-                // not insersecting with scanline, dont draw
-                if self.line.wrapping_sub(sprite.y) >= sprite_height {
-                    continue;
-                }
+    // a tile pixel line is encoded in two consecutive bytes,
+    // returns the colour number of the pixel at column (0 is the leftmost)
+    fn tile_row_colour_number(&self, tileset_index: usize, column: u8) -> u8 {
+        let byte_1 = self.vram[tileset_index];
+        let byte_2 = self.vram[tileset_index + 1];
 
-                let mut pos = sprite.tile_number;
+        let high_bit: u8 = is_bit_set(7 - column, byte_2 as u16) as u8;
+        let low_bit: u8 = is_bit_set(7 - column, byte_1 as u16) as u8;
+        (high_bit << 1) + low_bit
+    }
 
-                // handle upside down
-                let mut sprite_pixel_row = if sprite.options.flip_y {
-                    sprite_height - self.line.wrapping_sub(sprite.y) - 1
-                } else {
-                    self.line.wrapping_sub(sprite.y)
-                };
+    // the colour number of the bg/window pixel at coordinates x, y of the tilemap
+    fn tilemap_colour_number(&self, tilemap_offset: usize, x: usize, y: usize) -> u8 {
+        // the cell in the tilemap
+        let tilemap_x: usize = (x / TILE_SIZE) % TILES_IN_A_TILEMAP_ROW;
+        let tilemap_y: usize = (y / TILE_SIZE) % TILES_IN_A_TILEMAP_COL;
 
-                // go to next tile if we have to render 2nd part of the 16pixel sprite
-                if sprite_pixel_row >= 8 {
-                    pos = pos.wrapping_add(1);
-                    sprite_pixel_row -= 8;
-                }
+        // the pixel in the cell
+        let cell_x: usize = x % TILE_SIZE;
+        let cell_y: usize = y % TILE_SIZE;
 
-                // sprites always use tiledata1
-                let tile_in_tileset: usize = TILEDATA1_OFFSET
-                    + (2 * 8 * pos as usize + sprite_pixel_row as usize * 2) as usize;
+        // find the tile in the vram
+        let tilemap_index = tilemap_offset + tilemap_y * TILES_IN_A_TILEMAP_ROW + tilemap_x;
 
-                // a tile pixel line is encoded in two consecutive bytes
-                let byte_1 = self.vram[tile_in_tileset];
-                let byte_2 = self.vram[tile_in_tileset + 1];
+        let pos = self.vram[tilemap_index];
 
-                for pixel in 0..8u8 {
-                    let ix = if sprite.options.flip_x {
-                        pixel
-                    } else {
-                        7 - pixel
-                    };
+        // find out the row in the tile data
+        let tileset_index: usize = self.get_tileset_index(pos) + 2 * cell_y;
 
-                    let curr_x = sprite.x.wrapping_add(7 - pixel);
+        self.tile_row_colour_number(tileset_index, cell_x as u8)
+    }
 
-                    // out of the line, don't draw
-                    if curr_x >= 160 {
-                        continue;
-                    }
+    // like tilemap_colour_number, but following the cgb attributes of the tile
+    fn cgb_tilemap_colour_number(
+        &self,
+        tilemap_offset: usize,
+        x: usize,
+        y: usize,
+    ) -> (u8, TileAttributes) {
+        let tilemap_x: usize = (x / TILE_SIZE) % TILES_IN_A_TILEMAP_ROW;
+        let tilemap_y: usize = (y / TILE_SIZE) % TILES_IN_A_TILEMAP_COL;
+        let tilemap_index = tilemap_offset + tilemap_y * TILES_IN_A_TILEMAP_ROW + tilemap_x;
+
+        let attributes = TileAttributes::from_byte(self.vram[VRAM_BANK_SIZE + tilemap_index]);
+        let pos = self.vram[tilemap_index];
+
+        let cell_x = if attributes.flip_x {
+            TILE_SIZE - 1 - x % TILE_SIZE
+        } else {
+            x % TILE_SIZE
+        };
+        let cell_y = if attributes.flip_y {
+            TILE_SIZE - 1 - y % TILE_SIZE
+        } else {
+            y % TILE_SIZE
+        };
 
-                    let high_bit: u8 = is_bit_set(7 - ix, byte_2 as u16) as u8;
-                    let low_bit: u8 = is_bit_set(7 - ix, byte_1 as u16) as u8;
+        let bank_offset = if attributes.bank { VRAM_BANK_SIZE } else { 0 };
+        let tileset_index: usize = bank_offset + self.get_tileset_index(pos) + 2 * cell_y;
 
-                    let colour_number = (high_bit << 1) + low_bit;
+        (
+            self.tile_row_colour_number(tileset_index, cell_x as u8),
+            attributes,
+        )
+    }
 
-                    // transparent, don't draw
-                    if colour_number == 0 {
-                        continue;
-                    }
+    // the indexes of the sprites on the given line, the first 10 in oam order.
+    // The y kept in a sprite is the oam one minus 16, its top line. Sprites partially
+    // above the screen wrap around 0, so line - y goes past the sprite height
+    // everywhere but on their visible rows
+    fn oam_search(&self, line: u8) -> Vec<usize> {
+        let sprite_height: u8 = if self.obj_size { 16 } else { 8 };
+
+        (0..self.sprites.len())
+            .filter(|&index| line.wrapping_sub(self.sprites[index].y) < sprite_height)
+            .take(10)
+            .collect()
+    }
 
-                    // bg pixel wins over sprite, don't draw
-                    if sprite.options.z && (rendering_row[curr_x as usize] != 0) {
-                        continue;
-                    }
+    // the colour number of the sprite pixel at column (0 is the leftmost) on the given line.
+    // None if the sprite doesn't intersect the pixel
+    fn sprite_colour_number(&self, sprite: &Sprite, line: u8, column: u8) -> Option<u8> {
+        let sprite_height: u8 = if self.obj_size { 16 } else { 8 };
 
-                    let palette = if sprite.options.palette {
-                        &self.obj_palette_1
-                    } else {
-                        &self.obj_palette_0
-                    };
-                    let colour = palette.get(colour_number);
+        // not insersecting with scanline
+        if line.wrapping_sub(sprite.y) >= sprite_height || column >= 8 {
+            return None;
+        }
+
+        // tall sprites ignore the lowest bit: the top half is the even tile, the bottom the odd one
+        let mut pos = if self.obj_size {
+            sprite.tile_number & 0xFE
+        } else {
+            sprite.tile_number
+        };
+
+        // handle upside down
+        let mut sprite_pixel_row = if sprite.options.flip_y {
+            sprite_height - line.wrapping_sub(sprite.y) - 1
+        } else {
+            line.wrapping_sub(sprite.y)
+        };
+
+        // go to next tile if we have to render 2nd part of the 16pixel sprite
+        if sprite_pixel_row >= 8 {
+            pos = pos.wrapping_add(1);
+            sprite_pixel_row -= 8;
+        }
+
+        // sprites always use tiledata1, on cgb from either bank
+        let bank_offset = if self.cgb_mode && sprite.options.bank {
+            VRAM_BANK_SIZE
+        } else {
+            0
+        };
+        let tile_in_tileset: usize =
+            bank_offset + TILEDATA1_OFFSET + 2 * 8 * pos as usize + sprite_pixel_row as usize * 2;
+
+        let column = if sprite.options.flip_x {
+            7 - column
+        } else {
+            column
+        };
+
+        Some(self.tile_row_colour_number(tile_in_tileset, column))
+    }
+
+    // draws the 144 lines one after the other, with the current registers
+    fn render_frame_to_buffer(&mut self) {
+        let line = self.line;
+
+        self.latch_line_registers();
+        self.window_line_counter = 0;
+        for frame_line in 0..144 {
+            self.line = frame_line;
+            self.render_scan_to_buffer();
+        }
+
+        self.line = line;
+    }
+
+    // draws a line on the buffer
+    pub fn render_scan_to_buffer(&mut self) {
+        if self.cgb_mode {
+            self.render_cgb_scan_to_buffer();
+            return;
+        }
+
+        let line_to_draw: usize = self.line.wrapping_add(self.line_registers.scroll_y) as usize;
+
+        // save colour numbers being rendered before palette application. 0 is transparent
+        let mut rendering_row = [0u8; 160];
+
+        // background
+        if self.bg_enabled {
+            let tilemap_offset = self.bg_tilemap_offset();
+
+            // for each pixel in the line (which is long 160 pixel)
+            #[allow(clippy::needless_range_loop)]
+            for row_pixel in 0..TILES_IN_A_SCREEN_ROW * TILE_SIZE {
+                let curr_pixel_x = self.line_registers.scroll_x as usize + row_pixel;
+
+                let colour_number =
+                    self.tilemap_colour_number(tilemap_offset, curr_pixel_x, line_to_draw);
+                let palette_colour = self.bg_palette.get(colour_number);
+
+                rendering_row[row_pixel] = colour_number;
+
+                let index: usize =
+                    (self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE) + row_pixel;
+                self.buffer[index] = palette_colour.to_u8();
+            }
+        } else {
+            // a blank line, white whatever the palette. Only the sprites show
+            let line_start = self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE;
+            let line_end = line_start + TILES_IN_A_SCREEN_ROW * TILE_SIZE;
+            for pixel in self.buffer[line_start..line_end].iter_mut() {
+                *pixel = Colour::Off.to_u8();
+            }
+        }
+
+        // window, on the dmg it goes away with the bg
+        let window_x = self.window_screen_x();
+        if self.bg_enabled
+            && self.window_enabled
+            && self.line_registers.window_y <= self.line
+            && (window_x as usize) < 160
+        {
+            let tilemap_offset = self.window_tilemap_offset();
+
+            let window_line: usize = self.window_line_counter as usize;
+            self.window_rows[self.line as usize] = self.window_line_counter;
+            self.window_line_counter = self.window_line_counter.wrapping_add(1);
+
+            #[allow(clippy::needless_range_loop)]
+            for pixel in (window_x as usize)..TILES_IN_A_SCREEN_ROW * TILE_SIZE {
+                let curr_pixel_x = self.window_tilemap_x(pixel as u8, window_x);
+
+                let colour_number =
+                    self.tilemap_colour_number(tilemap_offset, curr_pixel_x as usize, window_line);
+                let palette_colour = self.bg_palette.get(colour_number);
+
+                rendering_row[pixel] = colour_number;
+
+                let index: usize = (self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE) + pixel;
+                self.buffer[index] = palette_colour.to_u8();
+            }
+        }
+
+        // sprites
+        if self.obj_enabled {
+            for sprite_num in self.oam_search(self.line) {
+                let sprite = &self.sprites[sprite_num];
+
+                for column in 0..8u8 {
+                    let curr_x = sprite.x.wrapping_add(column);
+
+                    // out of the line, don't draw
+                    if curr_x >= 160 {
+                        continue;
+                    }
+
+                    let colour_number = match self.sprite_colour_number(sprite, self.line, column) {
+                        Some(colour_number) => colour_number,
+                        // not insersecting with scanline, dont draw
+                        None => break,
+                    };
+
+                    // transparent, don't draw
+                    if colour_number == 0 {
+                        continue;
+                    }
+
+                    // bg pixel wins over sprite, don't draw
+                    if sprite.options.z && (rendering_row[curr_x as usize] != 0) {
+                        continue;
+                    }
+
+                    let palette = if sprite.options.palette {
+                        &self.obj_palette_1
+                    } else {
+                        &self.obj_palette_0
+                    };
+                    let colour = palette.get(colour_number);
                     let index: usize =
                         (self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE) + curr_x as usize;
-                    self.buffer[index] = colour as u8;
+                    self.buffer[index] = colour.to_u8();
                 }
             }
         }
     }
 
-    // returns true if compare stat interrupt should raise
-    fn check_compare_int(&self) -> bool {
-        self.compare_enabled && self.compare()
-    }
+    // draws a line on the cgb buffer.
+    // The bg can't be turned off: the lcdc bit 0 takes away the bg and window priority instead
+    fn render_cgb_scan_to_buffer(&mut self) {
+        let line_start = self.line as usize * TILES_IN_A_SCREEN_ROW * TILE_SIZE;
+        let bg_line: usize = self.line.wrapping_add(self.line_registers.scroll_y) as usize;
+
+        let window_x = self.window_screen_x();
+        let window_drawn = self.window_enabled
+            && self.line_registers.window_y <= self.line
+            && (window_x as usize) < 160;
+        let window_line: usize = self.window_line_counter as usize;
+        if window_drawn {
+            self.window_rows[self.line as usize] = self.window_line_counter;
+            self.window_line_counter = self.window_line_counter.wrapping_add(1);
+        }
 
-    // go forward based on the cpu's last operation clocks
-    pub fn step(&mut self, t: u8) -> (bool, bool) {
-        self.modeclock += t as u16;
+        // colour numbers of the bg and window before palette application,
+        // and whether they are drawn above sprites
+        let mut rendering_row = [(0u8, false); 160];
+
+        // background and window
+        #[allow(clippy::needless_range_loop)]
+        for pixel in 0..TILES_IN_A_SCREEN_ROW * TILE_SIZE {
+            let (colour_number, attributes) = if window_drawn && pixel >= window_x as usize {
+                let window_pixel_x = self.window_tilemap_x(pixel as u8, window_x);
+                self.cgb_tilemap_colour_number(
+                    self.window_tilemap_offset(),
+                    window_pixel_x as usize,
+                    window_line,
+                )
+            } else {
+                self.cgb_tilemap_colour_number(
+                    self.bg_tilemap_offset(),
+                    self.line_registers.scroll_x as usize + pixel,
+                    bg_line,
+                )
+            };
 
-        let mut vblank_interrupt: bool = false;
-        let mut compare_interrupt: bool = false;
+            rendering_row[pixel] = (colour_number, attributes.priority);
+            self.cgb_buffer[line_start + pixel] = self
+                .bg_colour_palettes
+                .colour(attributes.palette, colour_number);
+        }
 
-        // todo: implement it as a state machine?
-        match self.mode {
-            // scanline, oam read mode
-            2 => {
-                if self.modeclock >= 80 {
-                    self.modeclock = 0;
-                    self.mode = 3;
-                }
-            }
-            // scanline, vram read mode
-            3 => {
-                if self.modeclock >= 172 {
-                    // enter hblank mode
-                    self.modeclock = 0;
-                    self.mode = 0;
+        // sprites. The lowest oam index wins a pixel, even when the bg then hides it
+        if self.obj_enabled {
+            let mut sprite_drawn = [false; 160];
+
+            for sprite_num in self.oam_search(self.line) {
+                let sprite = &self.sprites[sprite_num];
+
+                for column in 0..8u8 {
+                    let curr_x = sprite.x.wrapping_add(column);
+
+                    // out of the line, or a previous sprite took it, don't draw
+                    if curr_x >= 160 || sprite_drawn[curr_x as usize] {
+                        continue;
+                    }
+
+                    let colour_number = match self.sprite_colour_number(sprite, self.line, column) {
+                        Some(colour_number) => colour_number,
+                        // not insersecting with scanline, dont draw
+                        None => break,
+                    };
 
-                    self.render_scan_to_buffer();
+                    // transparent, don't draw
+                    if colour_number == 0 {
+                        continue;
+                    }
+                    sprite_drawn[curr_x as usize] = true;
+
+                    // bg pixel wins over sprite, don't draw
+                    let (bg_colour_number, bg_priority) = rendering_row[curr_x as usize];
+                    if self.bg_enabled && bg_colour_number != 0 && (bg_priority || sprite.options.z)
+                    {
+                        continue;
+                    }
+
+                    self.cgb_buffer[line_start + curr_x as usize] = self
+                        .obj_colour_palettes
+                        .colour(sprite.options.cgb_palette, colour_number);
                 }
             }
-            // hblank
-            0 => {
-                if self.modeclock >= 204 {
-                    self.modeclock = 0;
-                    self.line += 1;
+        }
+    }
 
-                    if self.line == 144 {
-                        // enter vblank mode
-                        self.mode = 1;
-                        vblank_interrupt = true;
-                    } else {
-                        self.mode = 2;
-                    }
+    // decodes the pixel at screen coordinates x, y from the current vram, oam and registers,
+    // applying the same priorities the renderer does. The window row is the one last drawn on y
+    pub fn pixel_at(&self, x: u8, y: u8) -> PixelInfo {
+        if self.cgb_mode {
+            return self.cgb_pixel_at(x, y);
+        }
 
-                    compare_interrupt = self.check_compare_int();
+        let mut info = PixelInfo {
+            bg_color: 0,
+            final_shade: Colour::Off.to_u8().into(),
+            source: PixelSource::Background,
+        };
+
+        if self.bg_enabled {
+            let colour_number = self.tilemap_colour_number(
+                self.bg_tilemap_offset(),
+                self.line_registers.scroll_x as usize + x as usize,
+                y.wrapping_add(self.line_registers.scroll_y) as usize,
+            );
+
+            info.bg_color = colour_number;
+            info.final_shade = self.bg_palette.get(colour_number).to_u8().into();
+        }
+
+        let window_x = self.window_screen_x();
+        if self.bg_enabled
+            && self.window_enabled
+            && self.line_registers.window_y <= y
+            && window_x <= x
+        {
+            let colour_number = self.tilemap_colour_number(
+                self.window_tilemap_offset(),
+                self.window_tilemap_x(x, window_x) as usize,
+                self.window_rows[y as usize] as usize,
+            );
+
+            info.bg_color = colour_number;
+            info.final_shade = self.bg_palette.get(colour_number).to_u8().into();
+            info.source = PixelSource::Window;
+        }
+
+        if self.obj_enabled {
+            for sprite in self
+                .oam_search(y)
+                .into_iter()
+                .map(|index| &self.sprites[index])
+            {
+                let colour_number =
+                    match self.sprite_colour_number(sprite, y, x.wrapping_sub(sprite.x)) {
+                        Some(colour_number) => colour_number,
+                        None => continue,
+                    };
+
+                // transparent, or bg pixel wins over sprite
+                if colour_number == 0 || (sprite.options.z && info.bg_color != 0) {
+                    continue;
                 }
+
+                let palette = if sprite.options.palette {
+                    &self.obj_palette_1
+                } else {
+                    &self.obj_palette_0
+                };
+                info.final_shade = palette.get(colour_number).to_u8().into();
+                info.source = PixelSource::Sprite;
             }
-            // vblank (10 lines)
-            1 => {
-                if self.modeclock >= 456 {
-                    self.modeclock = 0;
-                    self.line += 1;
+        }
 
-                    // restart
-                    if self.line > 153 {
-                        self.mode = 2;
-                        self.line = 0;
-                    }
+        info
+    }
 
-                    compare_interrupt = self.check_compare_int();
+    // like pixel_at, following the cgb tile attributes and sprite priorities
+    fn cgb_pixel_at(&self, x: u8, y: u8) -> PixelInfo {
+        let window_x = self.window_screen_x();
+        let (source, (colour_number, attributes)) =
+            if self.window_enabled && self.line_registers.window_y <= y && window_x <= x {
+                (
+                    PixelSource::Window,
+                    self.cgb_tilemap_colour_number(
+                        self.window_tilemap_offset(),
+                        self.window_tilemap_x(x, window_x) as usize,
+                        self.window_rows[y as usize] as usize,
+                    ),
+                )
+            } else {
+                (
+                    PixelSource::Background,
+                    self.cgb_tilemap_colour_number(
+                        self.bg_tilemap_offset(),
+                        self.line_registers.scroll_x as usize + x as usize,
+                        y.wrapping_add(self.line_registers.scroll_y) as usize,
+                    ),
+                )
+            };
+
+        let mut info = PixelInfo {
+            bg_color: colour_number,
+            final_shade: self
+                .bg_colour_palettes
+                .colour(attributes.palette, colour_number),
+            source,
+        };
+
+        if self.obj_enabled {
+            // the first opaque sprite in oam takes the pixel
+            let sprite_pixel = self
+                .oam_search(y)
+                .into_iter()
+                .map(|index| &self.sprites[index])
+                .filter_map(|sprite| {
+                    self.sprite_colour_number(sprite, y, x.wrapping_sub(sprite.x))
+                        .filter(|&colour_number| colour_number != 0)
+                        .map(|colour_number| (sprite, colour_number))
+                })
+                .next();
+
+            if let Some((sprite, colour_number)) = sprite_pixel {
+                // bg pixel wins over sprite
+                if !(self.bg_enabled
+                    && info.bg_color != 0
+                    && (attributes.priority || sprite.options.z))
+                {
+                    info.final_shade = self
+                        .obj_colour_palettes
+                        .colour(sprite.options.cgb_palette, colour_number);
+                    info.source = PixelSource::Sprite;
                 }
             }
-            _ => panic!("Sorry what?"),
         }
 
-        (vblank_interrupt, compare_interrupt)
+        info
+    }
+
+    // returns true if compare stat interrupt should raise
+    fn check_compare_int(&self) -> bool {
+        self.compare_enabled && self.compare()
     }
 }
 
@@ -627,6 +1441,23 @@ impl Default for GPU {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_colour_conversion() {
+        for value in 0..=255u8 {
+            assert_eq!(Colour::from(value).to_u8(), value & 0b11);
+        }
+        for value in 0..4 {
+            assert_eq!(Colour::from(value).to_u8(), value);
+        }
+
+        let mut palette = Palette::new();
+        palette.update(0b0001_1011);
+        assert_eq!(palette.get(0).to_u8(), 3);
+        assert_eq!(palette.get(1).to_u8(), 2);
+        assert_eq!(palette.get(2).to_u8(), 1);
+        assert_eq!(palette.get(3).to_u8(), 0);
+    }
+
     // test scroll_y write and read access, as well as the default value
     #[test]
     fn test_scroll_y() {
@@ -768,6 +1599,300 @@ mod tests {
         assert_eq!(gpu.read_byte(0xFF44), 15);
     }
 
+    // a write to LY doesn't restart the frame
+    #[test]
+    fn test_line_write_ignored() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80);
+
+        for _ in 0..(3 * 456 + 100) / 4 {
+            gpu.step(4);
+        }
+        assert_eq!(gpu.read_byte(0xFF44), 3);
+
+        gpu.write_byte(0xFF44, 0);
+        assert_eq!(gpu.read_byte(0xFF44), 3);
+
+        for _ in 0..456 / 4 {
+            gpu.step(4);
+        }
+        assert_eq!(gpu.read_byte(0xFF44), 4);
+    }
+
+    // writing the compare line raises the interrupt only on the coincidence rising edge
+    #[test]
+    fn test_compare_line_write() {
+        let mut gpu = GPU::new();
+
+        gpu.write_byte(0xFF41, 0x40);
+        gpu.line = 3;
+
+        assert!(!gpu.set_compare_line(5));
+        assert!(gpu.set_compare_line(3));
+        assert!(!gpu.set_compare_line(3));
+        assert_eq!(gpu.get_compare_line(), 3);
+
+        // through the register, it's reported on next step
+        gpu.write_byte(0xFF45, 7);
+        gpu.write_byte(0xFF45, 3);
+        assert_eq!(gpu.read_byte(0xFF45), 3);
+        assert_eq!(gpu.step(0), (false, true));
+        assert_eq!(gpu.step(0), (false, false));
+    }
+
+    // the coincidence is rechecked as soon as the compare line is written, not at the next line
+    #[test]
+    fn test_compare_line_write_after_stepping() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80);
+        gpu.write_byte(0xFF41, 0x40);
+        gpu.write_byte(0xFF45, 0x90);
+
+        // into line 2
+        for _ in 0..(2 * LINE_CYCLES / 4 + 1) {
+            gpu.step(4);
+        }
+        assert_eq!(gpu.read_byte(0xFF44), 2);
+        assert_eq!(gpu.read_byte(0xFF41) & 0x04, 0);
+        assert!(!gpu.is_compare_interrupt_pending());
+
+        gpu.write_byte(0xFF45, 2);
+        assert_eq!(gpu.read_byte(0xFF41) & 0x04, 0x04);
+        assert!(gpu.is_compare_interrupt_pending());
+        assert_eq!(gpu.step(0), (false, true));
+        assert!(!gpu.is_compare_interrupt_pending());
+    }
+
+    // steps the gpu by step_clocks for three frames, returns the clocks it entered vblank at
+    fn vblanks_at(step_clocks: u8) -> Vec<u32> {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80);
+
+        let mut vblanks_at = Vec::new();
+        for steps in 0..(3 * 70224 / step_clocks as u32) {
+            if gpu.step(step_clocks).0 {
+                vblanks_at.push((steps + 1) * step_clocks as u32);
+            }
+        }
+        vblanks_at
+    }
+
+    #[test]
+    fn test_vblank_once_per_frame() {
+        // entering vblank, at line 144
+        assert_eq!(vblanks_at(4), vec![65664, 65664 + 70224, 65664 + 2 * 70224]);
+    }
+
+    // 24 doesn't divide the length of the modes, what's left of one counts towards the next
+    #[test]
+    fn test_modeclock_carry_over() {
+        assert_eq!(vblanks_at(24), vblanks_at(4));
+    }
+
+    // draws a frame of a screen with bg, window and a sprite, never changing registers
+    fn render_static_frame(render_mode: RenderMode) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.set_render_mode(render_mode);
+
+        for index in 0..8 * 16 {
+            gpu.write_vram(index, (index * 37) as u8);
+        }
+        for index in 0..0x400 {
+            gpu.write_vram(0x1800 + index, (index % 8) as u8);
+            gpu.write_vram(0x1C00 + index, (index % 5) as u8);
+        }
+        gpu.write_oam(0, 40);
+        gpu.write_oam(1, 50);
+        gpu.write_oam(2, 7);
+        for sprite in 1..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+
+        gpu.write_byte(0xFF42, 5);
+        gpu.write_byte(0xFF43, 3);
+        gpu.write_byte(0xFF4A, 60);
+        gpu.write_byte(0xFF4B, 87);
+        gpu.write_byte(0xFF47, 0b1110_0100);
+        gpu.write_byte(0xFF48, 0b0001_1011);
+        gpu.write_byte(0xFF40, 0xF3);
+
+        while !gpu.step(4).0 {}
+        gpu
+    }
+
+    #[test]
+    fn test_whole_frame_render_mode() {
+        let per_scanline = render_static_frame(RenderMode::PerScanline);
+        let whole_frame = render_static_frame(RenderMode::WholeFrame);
+
+        assert_eq!(whole_frame.get_render_mode(), RenderMode::WholeFrame);
+        assert!(per_scanline.buffer.iter().any(|&shade| shade != 0));
+        assert_eq!(per_scanline.buffer[..], whole_frame.buffer[..]);
+        assert_eq!(whole_frame.line, 144);
+    }
+
+    #[test]
+    fn test_bg_disabled_blank_line() {
+        let mut gpu = GPU::new();
+        for sprite in 0..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+
+        // tile 0 is all colour 3, black with the identity palette
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0xFF);
+            gpu.write_vram(row * 2 + 1, 0xFF);
+        }
+        gpu.write_byte(0xFF47, 0b1110_0100);
+        gpu.write_byte(0xFF40, 0x91);
+        gpu.line = 10;
+        gpu.render_scan_to_buffer();
+        assert!(gpu.buffer[10 * 160..11 * 160]
+            .iter()
+            .all(|&shade| shade == 3));
+
+        // the colour 0 of this palette is black, still the line goes white
+        gpu.write_byte(0xFF47, 0b0001_1011);
+        gpu.write_byte(0xFF40, 0x90);
+        gpu.render_scan_to_buffer();
+        assert!(gpu.buffer[10 * 160..11 * 160]
+            .iter()
+            .all(|&shade| shade == Colour::Off.to_u8()));
+        assert_eq!(gpu.pixel_at(0, 10).final_shade, Colour::Off.to_u8().into());
+
+        // the window goes away with the bg
+        gpu.write_byte(0xFF4A, 0);
+        gpu.write_byte(0xFF4B, 7);
+        gpu.write_byte(0xFF40, 0xB0);
+        gpu.render_scan_to_buffer();
+        assert!(gpu.buffer[10 * 160..11 * 160]
+            .iter()
+            .all(|&shade| shade == Colour::Off.to_u8()));
+        assert_eq!(gpu.pixel_at(0, 10).source, PixelSource::Background);
+        assert_eq!(gpu.pixel_at(0, 10).final_shade, Colour::Off.to_u8().into());
+    }
+
+    #[test]
+    fn test_pixel_at() {
+        let mut gpu = GPU::new();
+
+        // bg and sprites enabled, bg uses tiledata1
+        gpu.write_byte(0xFF40, 0x93);
+        gpu.write_byte(0xFF47, 0b1110_0100);
+        gpu.write_byte(0xFF48, 0b1110_0100);
+
+        // tile 0 is all colour 1, tile 1 is all colour 3
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+
+        // sprite 0 uses tile 1, top left corner at 10, 0
+        gpu.write_oam(0, 16);
+        gpu.write_oam(1, 18);
+        gpu.write_oam(2, 1);
+
+        let bg_pixel = gpu.pixel_at(100, 0);
+        assert_eq!(bg_pixel.source, PixelSource::Background);
+        assert_eq!(bg_pixel.bg_color, 1);
+        assert_eq!(bg_pixel.final_shade, 1);
+
+        let sprite_pixel = gpu.pixel_at(12, 0);
+        assert_eq!(sprite_pixel.source, PixelSource::Sprite);
+        assert_eq!(sprite_pixel.bg_color, 1);
+        assert_eq!(sprite_pixel.final_shade, 3);
+
+        // matches what the renderer draws
+        gpu.render_scan_to_buffer();
+        for x in 0..160u8 {
+            assert_eq!(
+                u16::from(gpu.get_buffer()[x as usize]),
+                gpu.pixel_at(x, 0).final_shade
+            );
+        }
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut gpu = GPU::new();
+
+        // window enabled on tilemap0, covering the bg, tiledata1
+        gpu.write_byte(0xFF40, 0xB1);
+        gpu.write_byte(0xFF47, 0b1110_0100);
+        gpu.write_byte(0xFF4A, 0);
+        gpu.write_byte(0xFF4B, 7);
+
+        // tile 0 has colour 1 on its first row, colour 2 on its second one
+        gpu.write_vram(0, 0xFF);
+        gpu.write_vram(3, 0xFF);
+
+        // window drawn on line 0
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.get_buffer()[0], 1);
+
+        // window disabled for lines 1 and 2
+        gpu.write_byte(0xFF40, 0x91);
+        gpu.line = 1;
+        gpu.render_scan_to_buffer();
+        gpu.line = 2;
+        gpu.render_scan_to_buffer();
+
+        // line 3 picks up from the window's second row, not its fourth
+        gpu.write_byte(0xFF40, 0xB1);
+        gpu.line = 3;
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.get_buffer()[3 * 160], 2);
+        assert_eq!(gpu.window_line_counter, 2);
+        assert_eq!(gpu.pixel_at(0, 3).source, PixelSource::Window);
+        assert_eq!(gpu.pixel_at(0, 3).bg_color, 2);
+        assert_eq!(gpu.pixel_at(0, 0).bg_color, 1);
+    }
+
+    // a sprite behind the bg only shows over bg colour number 0, whatever the palette
+    #[test]
+    fn test_sprite_behind_bg() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x10 | 0x02 | 0x01);
+        gpu.write_byte(0xFF48, 0b1110_0100);
+        // bg colour 2 shows as white, like colour 0
+        gpu.write_byte(0xFF47, 0b1100_0100);
+
+        // the bg is tile 0, colour 0 on the left half and 2 on the right one
+        for row in 0..8 {
+            gpu.write_vram(row * 2 + 1, 0x0F);
+        }
+        // tile 1, for the sprites, is all colour 3
+        for row in 0..8 {
+            gpu.write_vram(16 + row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+
+        // the other sprites off the screen
+        for sprite in 1..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+        gpu.write_oam(0, 16);
+        gpu.write_oam(1, 8);
+        gpu.write_oam(2, 1);
+        gpu.write_oam(3, 0x80);
+
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.get_buffer()[..8], [3, 3, 3, 3, 0, 0, 0, 0]);
+        for x in 0..8u8 {
+            assert_eq!(
+                u16::from(gpu.get_buffer()[x as usize]),
+                gpu.pixel_at(x, 0).final_shade
+            );
+        }
+
+        // without the bg, and the window with it, the sprite shows everywhere
+        gpu.write_byte(0xFF40, 0x80 | 0x20 | 0x10 | 0x02);
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.get_buffer()[..8], [3; 8]);
+        assert_eq!(gpu.pixel_at(7, 0).final_shade, 3);
+    }
+
     // test sprite write and read in the oam area 0xFE00-0xFE9F
     #[test]
     fn test_sprite() {
@@ -819,4 +1944,428 @@ mod tests {
         assert!(gpu.sprites[39].options.palette);
         assert_eq!(gpu.read_oam(3), 0b00010000);
     }
+
+    // scroll_x fine offset makes mode 3 longer, hblank pays for it
+    #[test]
+    fn test_mode3_length_scroll_x() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF43, 3);
+
+        gpu.step(80);
+        gpu.step(172);
+        assert_eq!(gpu.status().mode, 3);
+        gpu.step(3);
+        assert_eq!(gpu.status().mode, 0);
+
+        gpu.step(200);
+        assert_eq!(gpu.status().mode, 0);
+        gpu.step(1);
+        assert_eq!(gpu.status().mode, 2);
+        assert_eq!(gpu.status().line, 1);
+
+        // fixed timing ignores it
+        gpu.set_variable_mode3(false);
+        gpu.step(80);
+        gpu.step(172);
+        assert_eq!(gpu.status().mode, 0);
+        gpu.step(204);
+        assert_eq!(gpu.status().line, 2);
+    }
+
+    // each line is drawn with the scroll_x it had when its mode 3 started
+    #[test]
+    fn test_scroll_x_latched_per_line() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x10 | 0x01);
+        gpu.write_byte(0xFF47, 0b1110_0100);
+
+        // the whole bg is tile 0, its first column has colour 1
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0b1000_0000);
+        }
+
+        for line in 0..8u8 {
+            gpu.write_byte(0xFF43, line);
+            while gpu.status().mode != 3 {
+                gpu.step(4);
+            }
+
+            // too late for this line
+            gpu.write_byte(0xFF43, 4);
+            assert_eq!(gpu.read_byte(0xFF43), 4);
+            while gpu.status().mode != 0 {
+                gpu.step(4);
+            }
+
+            let row = &gpu.get_buffer()[line as usize * 160..(line as usize + 1) * 160];
+            let first_column = row.iter().position(|colour| *colour == 1).unwrap();
+            assert_eq!(first_column, (8 - line as usize) % 8, "line {}", line);
+        }
+    }
+
+    #[test]
+    fn test_mode3_length_window_and_sprites() {
+        let mut gpu = GPU::new();
+        assert_eq!(gpu.compute_mode3_length(), 172);
+
+        // window on line 0
+        gpu.write_byte(0xFF40, 0x80 | 0x20);
+        gpu.write_byte(0xFF4A, 0);
+        gpu.write_byte(0xFF4B, 7);
+        assert_eq!(gpu.compute_mode3_length(), 178);
+
+        // sprites enabled: all 40 sit on line 0 by default, only 10 are fetched.
+        // oam x is 8, aligned to the bg tile, so each costs 11
+        gpu.write_byte(0xFF40, 0x80 | 0x02);
+        assert_eq!(gpu.compute_mode3_length(), 172 + 10 * 11);
+
+        // off screen sprites are free
+        for sprite in 0..40 {
+            gpu.write_oam(sprite * 4 + 1, 168);
+        }
+        assert_eq!(gpu.compute_mode3_length(), 172);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut gpu = GPU::new();
+        gpu.set_compare_line(1);
+
+        gpu.step(80);
+        assert_eq!(
+            gpu.status(),
+            GpuStatus {
+                line: 0,
+                mode: 3,
+                modeclock: 0,
+                compare: false
+            }
+        );
+
+        // rest of mode 3 and hblank, into line 1
+        gpu.step(172);
+        gpu.step(204);
+        gpu.step(10);
+        assert_eq!(
+            gpu.status(),
+            GpuStatus {
+                line: 1,
+                mode: 2,
+                modeclock: 10,
+                compare: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_rgb_buffer() {
+        let mut gpu = GPU::new();
+        let palette = [(1, 2, 3), (4, 5, 6), (7, 8, 9), (10, 11, 12)];
+
+        gpu.buffer[1] = 2;
+        gpu.buffer[160] = 3;
+        gpu.buffer[160 * 144 - 1] = 1;
+
+        let rgb = gpu.get_rgb_buffer(&palette);
+
+        assert_eq!(rgb.len(), 160 * 144 * 3);
+        assert_eq!(rgb[0..3], [1, 2, 3]);
+        assert_eq!(rgb[3..6], [7, 8, 9]);
+        assert_eq!(rgb[160 * 3..160 * 3 + 3], [10, 11, 12]);
+        assert_eq!(rgb[rgb.len() - 3..], [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_tall_sprite_odd_tile_number() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x04 | 0x02);
+
+        // first row of tile 4 has colour 1, tile 5 colour 2
+        gpu.write_vram(4 * 16, 0xFF);
+        gpu.write_vram(5 * 16 + 1, 0xFF);
+
+        let mut sprite = Sprite::new();
+        sprite.tile_number = 5;
+
+        assert_eq!(gpu.sprite_colour_number(&sprite, 0, 0), Some(1));
+        assert_eq!(gpu.sprite_colour_number(&sprite, 8, 0), Some(2));
+
+        // flipped, the odd tile goes on top
+        sprite.options.flip_y = true;
+        assert_eq!(gpu.sprite_colour_number(&sprite, 7, 0), Some(2));
+        assert_eq!(gpu.sprite_colour_number(&sprite, 15, 0), Some(1));
+    }
+
+    // flipped vertically, a tall sprite mirrors its 16 rows: the odd tile goes on top,
+    // upside down
+    #[test]
+    fn test_tall_sprite_flip_y_rows() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x04 | 0x02);
+        gpu.write_byte(0xFF48, 0b1110_0100);
+
+        // row r of the sprite has a pixel in column r % 8: colour 1 in tile 2, colour 2 in tile 3
+        for row in 0..8 {
+            gpu.write_vram(2 * 16 + row * 2, 0x80 >> row);
+            gpu.write_vram(3 * 16 + row * 2 + 1, 0x80 >> row);
+        }
+
+        // the other sprites off the screen
+        for sprite in 1..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+        gpu.write_oam(0, 16);
+        gpu.write_oam(1, 8);
+        gpu.write_oam(2, 3);
+        gpu.write_oam(3, 0x40);
+
+        for line in 0..16u8 {
+            gpu.line = line;
+            gpu.render_scan_to_buffer();
+
+            let sprite_row = 15 - line as usize;
+            let colour = if sprite_row >= 8 { 2 } else { 1 };
+            let row = &gpu.get_buffer()[line as usize * 160..line as usize * 160 + 8];
+            for (column, pixel) in row.iter().enumerate() {
+                let expected = if column == sprite_row % 8 { colour } else { 0 };
+                assert_eq!(*pixel, expected, "line {} column {}", line, column);
+            }
+        }
+    }
+
+    // a tall sprite at oam y 8 shows its bottom half on lines 0-7
+    #[test]
+    fn test_sprite_partially_above_screen() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x04 | 0x02);
+        gpu.write_byte(0xFF48, 0b1110_0100);
+
+        // every row of tile 0 has colour 1, tile 1 colour 2
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+
+        // the other sprites off the screen
+        for sprite in 1..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+        gpu.write_oam(0, 8);
+        gpu.write_oam(1, 8);
+
+        for line in 0..8 {
+            gpu.line = line;
+            gpu.render_scan_to_buffer();
+            assert_eq!(gpu.buffer[line as usize * 160], 2, "line {}", line);
+        }
+
+        assert_eq!(gpu.oam_search(0), vec![0]);
+        assert_eq!(gpu.oam_search(8).len(), 0);
+    }
+
+    // only the first 10 sprites of a line in oam order are drawn
+    #[test]
+    fn test_oam_search_limit() {
+        let mut gpu = GPU::new();
+        gpu.write_byte(0xFF40, 0x80 | 0x02);
+        gpu.write_byte(0xFF48, 0b1110_0100);
+
+        for row in 0..8 {
+            gpu.write_vram(row * 2, 0xFF);
+        }
+
+        // 12 sprites on line 0, 8 pixels apart. The first is on another line
+        gpu.write_oam(0, 100);
+        for sprite in 1..13 {
+            gpu.write_oam(sprite * 4, 16);
+            gpu.write_oam(sprite * 4 + 1, 8 + 8 * sprite as u8);
+        }
+
+        assert_eq!(gpu.oam_search(0), (1..11).collect::<Vec<usize>>());
+
+        gpu.line = 0;
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.buffer[8 * 10], 1);
+        assert_eq!(gpu.buffer[8 * 11], 0);
+        assert_eq!(gpu.pixel_at(8 * 10, 0).final_shade, 1);
+        assert_eq!(gpu.pixel_at(8 * 11, 0).final_shade, 0);
+    }
+
+    #[test]
+    fn test_sprite_info() {
+        let mut gpu = GPU::new();
+        gpu.write_oam(4 * 7, 40);
+        gpu.write_oam(4 * 7 + 1, 30);
+        gpu.write_oam(4 * 7 + 2, 0x42);
+        gpu.write_oam(4 * 7 + 3, 0b1101_1011);
+
+        assert_eq!(
+            gpu.sprite(7),
+            SpriteInfo {
+                y: 40,
+                x: 30,
+                tile_number: 0x42,
+                behind_bg: true,
+                flip_y: true,
+                flip_x: false,
+                palette: 1,
+                cgb_palette: 3,
+                bank: 1,
+            }
+        );
+        assert!(!gpu.sprite(6).behind_bg);
+        assert_eq!(gpu.sprite(6).tile_number, 0);
+    }
+
+    #[test]
+    fn test_tile_pixels() {
+        let mut gpu = GPU::new();
+
+        // tile 3: colour 1 on the left column, 2 on the right one, 3 on the last row
+        for row in 0..7 {
+            gpu.write_vram(3 * 16 + row * 2, 0x80);
+            gpu.write_vram(3 * 16 + row * 2 + 1, 0x01);
+        }
+        gpu.write_vram(3 * 16 + 14, 0xFF);
+        gpu.write_vram(3 * 16 + 15, 0xFF);
+        // tile 3 of bank 1
+        gpu.write_vram(VRAM_BANK_SIZE as u16 + 3 * 16, 0xFF);
+
+        let pixels = gpu.tile_pixels(3);
+        for row in pixels.iter().take(7) {
+            assert_eq!(*row, [1, 0, 0, 0, 0, 0, 0, 2]);
+        }
+        assert_eq!(pixels[7], [3; 8]);
+
+        assert_eq!(gpu.tile_pixels(4), [[0; 8]; 8]);
+        assert_eq!(gpu.tile_pixels(TILES_IN_VRAM + 3)[0], [1; 8]);
+    }
+
+    #[test]
+    fn test_dump_tiles() {
+        let mut gpu = GPU::new();
+        // shades reversed
+        gpu.write_byte(0xFF47, 0b0001_1011);
+
+        // row 1 of tile 17, colour numbers 0 1 2 3 0 1 2 3
+        gpu.write_vram(17 * 16 + 2, 0b0101_0101);
+        gpu.write_vram(17 * 16 + 3, 0b0011_0011);
+
+        let dump = gpu.dump_tiles();
+        assert_eq!(dump.len(), 128 * 192);
+
+        // tile 17 is the second of the second row of tiles
+        let start = (8 + 1) * 128 + 8;
+        assert_eq!(dump[start..start + 8], [3, 2, 1, 0, 3, 2, 1, 0]);
+        assert_eq!(dump[start - 128..start - 120], [3; 8]);
+    }
+
+    #[test]
+    fn test_colour_palette_registers() {
+        let mut gpu = GPU::new();
+        gpu.set_cgb_mode(true);
+
+        // auto increment from the second colour of palette 1
+        gpu.write_byte(0xFF68, 0x80 | 0x0A);
+        gpu.write_byte(0xFF69, 0x1F);
+        gpu.write_byte(0xFF69, 0x7C);
+
+        assert_eq!(gpu.read_byte(0xFF68), 0xC0 | 0x0C);
+        assert_eq!(gpu.bg_colour_palettes.colour(1, 1), 0x7C1F);
+
+        // without auto increment the index stays
+        gpu.write_byte(0xFF68, 0x0A);
+        assert_eq!(gpu.read_byte(0xFF69), 0x1F);
+        gpu.write_byte(0xFF69, 0x1F);
+        assert_eq!(gpu.read_byte(0xFF68), 0x4A);
+
+        gpu.write_byte(0xFF68, 0x0B);
+        assert_eq!(gpu.read_byte(0xFF69), 0x7C);
+
+        // the object palettes are separate
+        gpu.write_byte(0xFF6A, 0x8A);
+        assert_eq!(gpu.read_byte(0xFF6B), 0);
+    }
+
+    #[test]
+    fn test_colour_palette_registers_dmg() {
+        let mut gpu = GPU::new();
+
+        gpu.write_byte(0xFF68, 0x80);
+        gpu.write_byte(0xFF69, 0x1F);
+
+        assert_eq!(gpu.bg_colour_palettes.read_index(), 0x40);
+        assert_eq!(gpu.bg_colour_palettes.data[0], 0);
+    }
+
+    #[test]
+    fn test_cgb_tile_attributes() {
+        let mut gpu = GPU::new();
+        gpu.set_cgb_mode(true);
+        gpu.write_byte(0xFF40, 0b1001_0001);
+
+        // tile 0 in bank 1 is a full line of colour 1 on its first row
+        gpu.write_vram(VRAM_BANK_SIZE as u16, 0xFF);
+
+        // first tilemap cell: palette 2, tile data from bank 1, flipped vertically
+        gpu.write_vram((VRAM_BANK_SIZE + TILEMAP0_OFFSET) as u16, 0b0100_1010);
+
+        // palette 2, colour 1
+        gpu.write_byte(0xFF68, 0x80 | 0x12);
+        gpu.write_byte(0xFF69, 0x34);
+        gpu.write_byte(0xFF69, 0x12);
+
+        // flipped, the first row of the tile is drawn on line 7
+        gpu.line = 7;
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.cgb_buffer[7 * 160], 0x1234);
+        assert_eq!(gpu.cgb_buffer[7 * 160 + 8], 0);
+        assert_eq!(gpu.pixel_at(0, 7).final_shade, 0x1234);
+        assert_eq!(gpu.pixel_at(8, 7).final_shade, 0);
+
+        gpu.line = 0;
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.cgb_buffer[0], 0);
+    }
+
+    // on cgb the sprite first in oam wins, whatever the x coordinates
+    #[test]
+    fn test_cgb_sprite_priority() {
+        let mut gpu = GPU::new();
+        gpu.set_cgb_mode(true);
+        gpu.write_byte(0xFF40, 0b1000_0010);
+
+        // tile 1 is all colour 3
+        for row in 0..8 {
+            gpu.write_vram(16 + row * 2, 0xFF);
+            gpu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+
+        // colour 3 of object palette 0 and 1
+        gpu.write_byte(0xFF6A, 0x80 | 0x06);
+        gpu.write_byte(0xFF6B, 0x11);
+        gpu.write_byte(0xFF6B, 0x11);
+        gpu.write_byte(0xFF6A, 0x80 | 0x0E);
+        gpu.write_byte(0xFF6B, 0x22);
+        gpu.write_byte(0xFF6B, 0x22);
+
+        for sprite in 0..40 {
+            gpu.write_oam(sprite * 4, 0);
+        }
+        // sprite 0 at x 4 with palette 0, sprite 1 at x 0 with palette 1
+        gpu.write_oam(0, 16);
+        gpu.write_oam(1, 12);
+        gpu.write_oam(2, 1);
+        gpu.write_oam(4, 16);
+        gpu.write_oam(5, 8);
+        gpu.write_oam(6, 1);
+        gpu.write_oam(7, 0x01);
+
+        gpu.render_scan_to_buffer();
+        assert_eq!(gpu.cgb_buffer[..4], [0x2222; 4]);
+        assert_eq!(gpu.cgb_buffer[4..12], [0x1111; 8]);
+        for x in 0..12u8 {
+            assert_eq!(gpu.cgb_buffer[x as usize], gpu.pixel_at(x, 0).final_shade);
+        }
+    }
 }
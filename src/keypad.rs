@@ -1,8 +1,76 @@
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use std::io;
+
+const SGB_PACKET_BITS: u8 = 128;
+
 pub struct Key {
     rows: [u8; 2],
     column: u8,
+    sgb_transfer: SgbTransfer,
+}
+
+// super game boy packets, sent through the selection bits of the joypad register.
+// A reset pulse (both lines low) starts a packet, then every bit is a pulse of one line:
+// P14 low for a 0, P15 low for a 1, with both lines going high again in between.
+// 128 bits, least significant first, and a 0 stop bit end it.
+// They are only recorded: the joypad keeps reading like on a dmg,
+// so games asking for the sgb see it's not there and go on
+struct SgbTransfer {
+    active: bool,     // a reset pulse started a packet
+    ready: bool,      // the lines went high, the next pulse is a new bit
+    bits_read: u8,    // bits of the packet received so far
+    packet: [u8; 16], // the packet being received
+    packets: Vec<[u8; 16]>,
+}
+
+impl SgbTransfer {
+    fn new() -> Self {
+        SgbTransfer {
+            active: false,
+            ready: false,
+            bits_read: 0,
+            packet: [0; 16],
+            packets: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, column: u8) {
+        let bit = match column {
+            0x00 => {
+                self.active = true;
+                self.ready = false;
+                self.bits_read = 0;
+                self.packet = [0; 16];
+                return;
+            }
+            0x30 => {
+                self.ready = self.active;
+                return;
+            }
+            0x20 => 0,
+            _ => 1,
+        };
+
+        if !self.ready {
+            return;
+        }
+        self.ready = false;
+
+        if self.bits_read < SGB_PACKET_BITS {
+            self.packet[self.bits_read as usize / 8] |= bit << (self.bits_read % 8);
+            self.bits_read += 1;
+        } else {
+            // the stop bit, a packet ending with a 1 is garbage
+            if bit == 0 {
+                self.packets.push(self.packet);
+            }
+            self.active = false;
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Button {
     DOWN,
     UP,
@@ -14,16 +82,67 @@ pub enum Button {
     A,
 }
 
+// which of the 8 buttons are held down, for frontends polling the whole pad at once
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ButtonState {
+    pub down: bool,
+    pub up: bool,
+    pub left: bool,
+    pub right: bool,
+    pub start: bool,
+    pub select: bool,
+    pub b: bool,
+    pub a: bool,
+}
+
+impl ButtonState {
+    pub fn new() -> Self {
+        ButtonState::default()
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::DOWN => self.down = pressed,
+            Button::UP => self.up = pressed,
+            Button::LEFT => self.left = pressed,
+            Button::RIGHT => self.right = pressed,
+            Button::START => self.start = pressed,
+            Button::SELECT => self.select = pressed,
+            Button::B => self.b = pressed,
+            Button::A => self.a = pressed,
+        }
+    }
+
+    // the rows of the joypad register, buttons and directions. 0 means pressed
+    fn rows(&self) -> [u8; 2] {
+        let line = |pressed: bool, bit: u8| if pressed { 0 } else { bit };
+        [
+            0xC0 | line(self.start, 0x8)
+                | line(self.select, 0x4)
+                | line(self.b, 0x2)
+                | line(self.a, 0x1),
+            0xC0 | line(self.down, 0x8)
+                | line(self.up, 0x4)
+                | line(self.left, 0x2)
+                | line(self.right, 0x1),
+        ]
+    }
+}
+
 impl Key {
     pub fn new() -> Key {
         Key {
             rows: [0xCF, 0xCF],
             column: 0,
+            sgb_transfer: SgbTransfer::new(),
         }
     }
 
-    pub fn read_byte(&mut self) -> u8 {
+    // the top 2 bits always read 1, and so do the lines of a group that isn't selected.
+    // With both groups selected a line is low if either button on it is pressed
+    pub fn read_byte(&self) -> u8 {
         (match self.column {
+            0x00 => self.rows[0] & self.rows[1],
             0x10 => self.rows[0],
             0x20 => self.rows[1],
             _ => 0xCF,
@@ -32,9 +151,23 @@ impl Key {
 
     pub fn write_byte(&mut self, value: u8) {
         self.column = value & 0b110000;
+        self.sgb_transfer.write(self.column);
+    }
+
+    // the super game boy packets the game sent so far, oldest first
+    pub fn sgb_packets(&self) -> &[[u8; 16]] {
+        &self.sgb_transfer.packets
     }
 
-    pub fn press(&mut self, button: Button) {
+    // the low nibble of the register, the selected lines. 0 means pressed
+    fn selected_lines(&self) -> u8 {
+        self.read_byte() & 0x0F
+    }
+
+    // returns true if a selected line went from high to low, which raises the joypad interrupt
+    pub fn press(&mut self, button: Button) -> bool {
+        let lines_before = self.selected_lines();
+
         match button {
             Button::DOWN => self.rows[1] &= 0xC7,
             Button::UP => self.rows[1] &= 0xCB,
@@ -45,6 +178,18 @@ impl Key {
             Button::B => self.rows[0] &= 0xCD,
             Button::A => self.rows[0] &= 0xCE,
         }
+
+        lines_before & !self.selected_lines() != 0
+    }
+
+    // replaces the state of every button.
+    // Returns true if a selected line went from high to low, like press
+    pub fn set_buttons(&mut self, state: ButtonState) -> bool {
+        let lines_before = self.selected_lines();
+
+        self.rows = state.rows();
+
+        lines_before & !self.selected_lines() != 0
     }
 
     pub fn release(&mut self, button: Button) {
@@ -61,8 +206,101 @@ impl Key {
     }
 }
 
+impl SaveState for Key {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.rows);
+        state.write_u8(self.column);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.read_bytes(&mut self.rows)?;
+        self.column = state.read_u8()?;
+        Ok(())
+    }
+}
+
 impl Default for Key {
     fn default() -> Self {
         Key::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_byte_selection() {
+        let mut key = Key::new();
+
+        // nothing pressed, every line is high
+        for &select in [0x00, 0x10, 0x20, 0x30].iter() {
+            key.write_byte(select);
+            assert_eq!(key.read_byte(), 0xCF | select);
+        }
+
+        key.press(Button::A);
+        key.press(Button::LEFT);
+        key.press(Button::UP);
+
+        // selection written, what's read back
+        let expected = [(0x00, 0xC8), (0x10, 0xDE), (0x20, 0xE9), (0x30, 0xFF)];
+        for &(select, byte) in expected.iter() {
+            // only the selection bits are writable
+            key.write_byte(select | 0xCF);
+            assert_eq!(key.read_byte(), byte, "selection {:02X}", select);
+        }
+
+        key.release(Button::LEFT);
+        key.write_byte(0x00);
+        assert_eq!(key.read_byte(), 0xCA);
+    }
+
+    // pulses a packet through the joypad register, with the given stop bit
+    fn send_sgb_packet(key: &mut Key, packet: &[u8; 16], stop_bit: u8) {
+        key.write_byte(0x00);
+        key.write_byte(0x30);
+        for bit_index in 0..128 {
+            let bit = (packet[bit_index / 8] >> (bit_index % 8)) & 1;
+            key.write_byte(if bit == 1 { 0x10 } else { 0x20 });
+            key.write_byte(0x30);
+        }
+        key.write_byte(if stop_bit == 1 { 0x10 } else { 0x20 });
+        key.write_byte(0x30);
+    }
+
+    #[test]
+    fn test_sgb_packets() {
+        let mut key = Key::new();
+
+        // MLT_REQ, asking for 2 players
+        let mut mlt_req = [0; 16];
+        mlt_req[0] = 0x89;
+        mlt_req[1] = 0x01;
+        send_sgb_packet(&mut key, &mlt_req, 0);
+
+        let mut counting = [0; 16];
+        for (index, byte) in counting.iter_mut().enumerate() {
+            *byte = (index as u8) * 17;
+        }
+        send_sgb_packet(&mut key, &counting, 0);
+        send_sgb_packet(&mut key, &[0xFF; 16], 1);
+
+        assert_eq!(key.sgb_packets(), &[mlt_req, counting]);
+
+        // like on a dmg, no second controller answers
+        key.write_byte(0x30);
+        assert_eq!(key.read_byte(), 0xFF);
+
+        // repeated selections between pulses don't count as more bits
+        key.write_byte(0x00);
+        key.write_byte(0x30);
+        for _ in 0..129 {
+            key.write_byte(0x20);
+            key.write_byte(0x20);
+            key.write_byte(0x30);
+        }
+        assert_eq!(key.sgb_packets().len(), 3);
+        assert_eq!(key.sgb_packets()[2], [0; 16]);
+    }
+}
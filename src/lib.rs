@@ -1,18 +1,17 @@
 #![allow(dead_code)]
 
-extern crate csv;
-extern crate lazy_static;
-#[macro_use]
-extern crate log;
-extern crate serde_derive;
-
+pub mod backend;
 pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+#[cfg(feature = "std")]
 pub mod emu;
 pub mod gpu;
 pub mod keypad;
 pub mod link;
 pub mod mem;
 pub mod sound;
+pub mod state;
 pub mod timers;
 pub mod utils;
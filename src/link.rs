@@ -1,33 +1,86 @@
 /// Link cable
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use std::io;
+
+// t cycles it takes to shift a whole byte, with the internal 8192Hz serial clock
+const TRANSFER_CYCLES: u32 = 8 * 512;
+
+// the gameboy at the other end of the cable
+pub trait SerialPeer {
+    // receives the byte shifted out, returns the one shifted in
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+// nothing connected: the line is pulled high
+pub struct Disconnected;
+
+impl SerialPeer for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
 
 pub struct Link {
-    buffer_out: [char; 256],
-    buffer_index: usize,
+    output: String, // every byte sent, as text
     data: u8,
     control: u8,
+    peer: Box<dyn SerialPeer>,
+    transfer_cycles_left: u32, // 0 when no transfer is running
 }
 
 impl Link {
     pub fn new() -> Self {
         Link {
-            buffer_out: [char::from(32); 256],
-            buffer_index: 0,
+            output: String::new(),
             data: 0,
             control: 0,
+            peer: Box::new(Disconnected),
+            transfer_cycles_left: 0,
         }
     }
 
+    // stops any transfer, the peer stays connected and the output is kept
+    pub fn reset(&mut self) {
+        self.data = 0;
+        self.control = 0;
+        self.transfer_cycles_left = 0;
+    }
+
+    pub fn connect_peer(&mut self, peer: Box<dyn SerialPeer>) {
+        self.peer = peer;
+    }
+
     pub fn set_data(&mut self, byte: u8) {
         self.data = byte;
     }
 
     pub fn set_control(&mut self, byte: u8) {
         self.control = byte;
-        if byte == 0x81 {
+
+        // transfer start, with the internal clock. With the external one the peer would drive it
+        if byte & 0x81 == 0x81 {
             self.send();
+            self.transfer_cycles_left = TRANSFER_CYCLES;
         }
     }
 
+    // moves the running transfer forward. Returns true if the serial interrupt should raise
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        if self.transfer_cycles_left == 0 {
+            return false;
+        }
+
+        self.transfer_cycles_left = self.transfer_cycles_left.saturating_sub(cycles as u32);
+        if self.transfer_cycles_left > 0 {
+            return false;
+        }
+
+        self.data = self.peer.exchange(self.data);
+        self.control &= 0x7F;
+        true
+    }
+
     pub fn get_data(&self) -> u8 {
         self.data
     }
@@ -37,12 +90,28 @@ impl Link {
     }
 
     fn send(&mut self) {
-        self.buffer_out[self.buffer_index] = self.data as char;
-        self.buffer_index = (self.buffer_index + 1) % 256;
+        self.output.push(self.data as char);
     }
 
-    pub fn get_buffer(&self) -> [char; 256] {
-        self.buffer_out
+    // what was sent since the start, test roms print their results through it
+    pub fn get_output(&self) -> &str {
+        &self.output
+    }
+}
+
+// the peer and the output buffer live outside the emulated gameboy, they are left alone
+impl SaveState for Link {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u32(self.transfer_cycles_left);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.transfer_cycles_left = state.read_u32()?;
+        Ok(())
     }
 }
 
@@ -53,16 +122,14 @@ impl Default for Link {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
 
     #[test]
     fn link_inizialization() {
         let link = Link::new();
 
-        assert_eq!(link.buffer_out[0], ' ');
-        assert_eq!(link.buffer_out[255], ' ');
-        assert_eq!(link.buffer_index, 0);
+        assert_eq!(link.get_output(), "");
     }
 
     #[test]
@@ -76,8 +143,48 @@ mod tests {
         link.set_data(b'w');
         link.send();
 
-        assert_eq!(link.get_buffer()[0], 'w');
-        assert_eq!(link.get_buffer()[1], 'o');
-        assert_eq!(link.get_buffer()[2], 'w');
+        assert_eq!(link.get_output(), "wow");
+
+        // nothing is dropped, however long the output
+        for _ in 0..300 {
+            link.send();
+        }
+        assert_eq!(link.get_output().len(), 303);
+        assert!(link.get_output().starts_with("wow"));
+    }
+
+    // sends back what it gets
+    pub struct Echo;
+
+    impl SerialPeer for Echo {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            byte
+        }
+    }
+
+    #[test]
+    fn transfer() {
+        let mut link = Link::new();
+
+        // nothing connected
+        link.set_data(0x42);
+        link.set_control(0x81);
+        assert!(!link.tick(255));
+        while !link.tick(4) {}
+        assert_eq!(link.get_data(), 0xFF);
+        assert_eq!(link.get_control(), 0x01);
+
+        // no transfer running, no interrupt
+        assert!(!link.tick(4));
+
+        link.connect_peer(Box::new(Echo));
+        link.set_data(0x42);
+        link.set_control(0x81);
+        let mut cycles = 0;
+        while !link.tick(4) {
+            cycles += 4;
+        }
+        assert_eq!(cycles + 4, TRANSFER_CYCLES);
+        assert_eq!(link.get_data(), 0x42);
     }
 }
@@ -1,17 +1,179 @@
-use crate::gpu::GPUMemoriesAccess;
-use crate::keypad::Key;
+use crate::gpu::{GPUMemoriesAccess, VRAM_BANK_SIZE};
+use crate::keypad::{Button, ButtonState, Key};
 use crate::link::Link;
 use crate::sound::Sound;
+use crate::state::{invalid_state, SaveState, StateReader, StateWriter};
 use crate::timers::Timers;
 use cartridge::CartridgeAccess;
 
+use std::io;
+
+const OAM_DMA_LENGTH: u16 = 160;
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8; // on dmg, only the first two exist
+
+// the interrupt sources, numbered after their bit in IE and IF
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+// The OAM DMA unit copies 160 bytes to the OAM, one every machine cycle
+struct OamDma {
+    active: bool,
+    source: u16, // where the copy starts from
+    index: u16,  // how many bytes have been copied so far
+}
+
+impl OamDma {
+    fn new() -> Self {
+        OamDma {
+            active: false,
+            source: 0,
+            index: 0,
+        }
+    }
+
+    fn start(&mut self, source: u16) {
+        self.active = true;
+        self.source = source;
+        self.index = 0;
+    }
+
+    // returns the address to copy from and the oam index to copy to, if still running
+    fn next(&mut self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+
+        let next = (self.source + self.index, self.index);
+
+        self.index += 1;
+        if self.index == OAM_DMA_LENGTH {
+            self.active = false;
+        }
+
+        Some(next)
+    }
+
+    // while the DMA is running, the cpu can only access HRAM and the io registers
+    fn blocks(&self, addr: u16) -> bool {
+        self.active && addr < 0xFF00
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.active);
+        state.write_u16(self.source);
+        state.write_u16(self.index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.active = state.read_bool()?;
+        self.source = state.read_u16()?;
+        self.index = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// the io registers as the DMG boot rom leaves them. The apu is powered first,
+// writing the others would be ignored otherwise. DIV is set apart, it can't be written
+const POST_BOOT_IO: [(u16, u8); 34] = [
+    (0xFF26, 0x80),
+    (0xFF00, 0xCF),
+    (0xFF01, 0x00),
+    (0xFF02, 0x7E),
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF40, 0x91),
+    (0xFF42, 0x00),
+    (0xFF43, 0x00),
+    (0xFF45, 0x00),
+    (0xFF47, 0xFC),
+    (0xFFFF, 0x00),
+];
+const POST_BOOT_SYSTEM_COUNTER: u16 = 0xABCC;
+
+pub const DMG_BOOT_ROM_SIZE: usize = 0x0100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x0900;
+
+// what the memories hold when the gameboy is switched on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RamInitPattern {
+    Zero,
+    Ones,
+    Random(u64), // the same seed always gives the same bytes
+}
+
+impl RamInitPattern {
+    // the source of the bytes to fill the memories with, one after the other
+    fn filler(self) -> impl FnMut() -> u8 {
+        // xorshift, it only needs a non zero state
+        let mut state = match self {
+            RamInitPattern::Random(seed) => seed ^ 0x9E37_79B9_7F4A_7C15,
+            _ => 0,
+        }
+        .max(1);
+
+        move || match self {
+            RamInitPattern::Zero => 0x00,
+            RamInitPattern::Ones => 0xFF,
+            RamInitPattern::Random(_) => {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            }
+        }
+    }
+}
+
 pub struct MMU<M: GPUMemoriesAccess> {
     still_bios: bool,
-    bios: [u8; 0x0100],
+    bios: Vec<u8>, // the dmg boot rom, or the cgb one which is mapped at 0x0200-0x08FF too
 
-    wram: [u8; 0x2000],
+    wram: [u8; WRAM_BANKS * WRAM_BANK_SIZE], // bank 0 is fixed, banks 1-7 are switched in at 0xD000
     zram: [u8; 0x0080],
 
+    // cgb only, off unless asked for: this is a dmg, even for cgb compatible games
+    cgb_mode: bool,
+    wram_bank: u8, // 0xFF70
+    vram_bank: u8, // 0xFF4F
+
     pub cartridge: Box<dyn CartridgeAccess>,
     pub timers: Timers,
     pub sound: Sound,
@@ -20,6 +182,20 @@ pub struct MMU<M: GPUMemoriesAccess> {
     pub interrupt_flags: u8,
 
     pub oam_dma_source: u8,
+    oam_dma: OamDma,
+
+    // the gpu locks the cpu out of vram and oam while using them.
+    // Some games only work with lax timings, with this off
+    ppu_access_blocking: bool,
+
+    ram_init_pattern: RamInitPattern,
+
+    // KEY1, the cgb speed switch
+    speed_switch_prepared: bool, // the next STOP switches speed
+    double_speed: bool,
+
+    frame_completed: bool, // the gpu entered vblank since the last take_frame_completed
+
     pub gpu: M,
     pub key: Key,
     pub link: Link,
@@ -29,11 +205,15 @@ impl<M: GPUMemoriesAccess> MMU<M> {
     pub fn new(gpu: M, cartridge: Box<dyn CartridgeAccess>) -> MMU<M> {
         MMU {
             still_bios: false,
-            bios: [0; 0x0100],
+            bios: vec![0; DMG_BOOT_ROM_SIZE],
 
-            wram: [0; 0x2000],
+            wram: [0; WRAM_BANKS * WRAM_BANK_SIZE],
             zram: [0; 0x0080],
 
+            cgb_mode: false,
+            wram_bank: 1,
+            vram_bank: 0,
+
             cartridge,
             sound: Sound::new(),
 
@@ -43,64 +223,259 @@ impl<M: GPUMemoriesAccess> MMU<M> {
             interrupt_flags: 0xe0,
 
             oam_dma_source: 0,
+            oam_dma: OamDma::new(),
+
+            ppu_access_blocking: true,
+            ram_init_pattern: RamInitPattern::Zero,
+
+            speed_switch_prepared: false,
+            double_speed: false,
+
+            frame_completed: false,
+
             gpu,
             key: Key::new(),
             link: Link::new(),
         }
     }
 
-    pub fn set_bios(&mut self, bios: [u8; 0x0100]) {
+    // everything back to the power on state, without the bios.
+    // The cartridge keeps its ram, the frontend settings and the link peer stay
+    pub fn reset(&mut self) {
+        self.still_bios = false;
+
+        self.wram = [0; WRAM_BANKS * WRAM_BANK_SIZE];
+        self.zram = [0; 0x0080];
+        self.wram_bank = 1;
+        self.vram_bank = 0;
+
+        self.cartridge.reset();
+        self.timers = Timers::new();
+        self.sound.power_cycle();
+
+        self.interrupt_enable = 0;
+        self.interrupt_flags = 0xe0;
+
+        self.oam_dma_source = 0;
+        self.oam_dma = OamDma::new();
+
+        self.speed_switch_prepared = false;
+        self.double_speed = false;
+        self.frame_completed = false;
+
+        self.gpu.reset();
+        self.key = Key::new();
+        self.link.reset();
+
+        // zero is how everything starts already
+        if self.ram_init_pattern != RamInitPattern::Zero {
+            self.fill_ram();
+        }
+    }
+
+    // fills work ram, hram, vram and oam with pattern, now and on every reset
+    pub fn set_ram_init_pattern(&mut self, pattern: RamInitPattern) {
+        self.ram_init_pattern = pattern;
+        self.fill_ram();
+    }
+
+    fn fill_ram(&mut self) {
+        let mut next_byte = self.ram_init_pattern.filler();
+
+        for byte in self.wram.iter_mut().chain(self.zram.iter_mut()) {
+            *byte = next_byte();
+        }
+        for addr in 0..2 * VRAM_BANK_SIZE as u16 {
+            self.gpu.write_vram(addr, next_byte());
+        }
+        for addr in 0..0xA0 {
+            self.gpu.write_oam(addr, next_byte());
+        }
+    }
+
+    // maps a boot rom, DMG_BOOT_ROM_SIZE or CGB_BOOT_ROM_SIZE bytes long
+    pub fn set_bios(&mut self, bios: Vec<u8>) {
         self.bios = bios;
         self.still_bios = true; // TODO: move this into a reset fn
     }
 
+    // true until the boot rom hands over to the cartridge
+    pub fn is_bios_mapped(&self) -> bool {
+        self.still_bios
+    }
+
+    // whether the boot rom shadows the cartridge at addr
+    fn bios_at(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        self.still_bios && addr < self.bios.len() && !(0x0100..0x0200).contains(&addr)
+    }
+
+    // sets the interrupt bit in IF, the cpu serves it once enabled in IE
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flags |= interrupt.mask();
+    }
+
+    // the interrupts both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flags & 0x1F
+    }
+
+    // presses a joypad button, raising the joypad interrupt if one of the selected lines fell
+    pub fn press_button(&mut self, button: Button) {
+        if self.key.press(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn sgb_packets(&self) -> &[[u8; 16]] {
+        self.key.sgb_packets()
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.key.release(button);
+    }
+
+    // sets all the buttons at once, raising the joypad interrupt for the newly pressed ones
+    pub fn set_buttons(&mut self, state: ButtonState) {
+        if self.key.set_buttons(state) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn set_ppu_access_blocking(&mut self, enabled: bool) {
+        self.ppu_access_blocking = enabled;
+    }
+
+    // true if the cpu can't reach addr right now: during OAM DMA only HRAM and io are left,
+    // and the gpu takes vram while drawing, oam while searching it and drawing
+    fn cpu_blocked(&self, addr: u16) -> bool {
+        if self.oam_dma.blocks(addr) {
+            return true;
+        }
+
+        if !self.ppu_access_blocking {
+            return false;
+        }
+
+        match addr {
+            0x8000..=0x9FFF => self.gpu.mode() == 3,
+            0xFE00..=0xFE9F => matches!(self.gpu.mode(), 2 | 3),
+            _ => false,
+        }
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // turning it off goes back to the dmg single banks
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+        self.gpu.set_cgb_mode(enabled);
+        if !enabled {
+            self.wram_bank = 1;
+            self.vram_bank = 0;
+            self.speed_switch_prepared = false;
+            self.double_speed = false;
+        }
+    }
+
+    // index in wram of an address in 0xC000-0xFDFF, through the selected bank
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = (addr & 0x1FFF) as usize;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank as usize * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    // index in the gpu vram of an address in 0x8000-0x9FFF, through the selected bank
+    fn vram_index(&self, addr: u16) -> u16 {
+        (self.vram_bank as usize * VRAM_BANK_SIZE) as u16 + (addr & 0x1FFF)
+    }
+
+    // bank 0 can't be selected, it selects bank 1
+    fn select_wram_bank(&mut self, byte: u8) {
+        if !self.cgb_mode {
+            return;
+        }
+        self.wram_bank = match byte & 0b111 {
+            0 => 1,
+            bank => bank,
+        };
+    }
+
+    fn select_vram_bank(&mut self, byte: u8) {
+        if self.cgb_mode {
+            self.vram_bank = byte & 1;
+        }
+    }
+
+    // current speed in bit 7, prepared switch in bit 0. There's no KEY1 on dmg
+    fn read_key1(&self) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+        0x7E | (if self.double_speed { 0x80 } else { 0 })
+            | (if self.speed_switch_prepared { 1 } else { 0 })
+    }
+
+    // true if the gpu completed a frame since the last call
+    pub fn take_frame_completed(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_completed, false)
+    }
+
     pub fn tick_timers(&mut self, cycles: u8) {
         self.timers.tick(cycles);
     }
-}
 
-pub trait Memory {
-    fn read_byte(&mut self, addr: u16) -> u8;
-    fn write_byte(&mut self, addr: u16, byte: u8);
+    // copies a byte to the OAM every machine cycle, while the DMA is running
+    fn tick_oam_dma(&mut self, cpu_cycles: u8) {
+        for _ in 0..cpu_cycles / 4 {
+            let (source, index) = match self.oam_dma.next() {
+                Some(next) => next,
+                None => return,
+            };
 
-    fn read_word(&mut self, addr: u16) -> u16 {
-        (self.read_byte(addr) as u16) | ((self.read_byte(addr + 1) as u16) << 8)
+            let to_be_copied = self.bus_read_byte(source);
+            self.gpu.write_oam(index, to_be_copied);
+        }
     }
 
-    fn write_word(&mut self, addr: u16, word: u16) {
-        self.write_byte(addr, (word & 0x00FF) as u8);
-        self.write_byte(addr + 1, ((word & 0xFF00) >> 8) as u8);
+    // reads from the bus, as seen by the DMA unit
+    fn bus_read_byte(&mut self, addr: u16) -> u8 {
+        // the bios gets unmapped as soon as the cpu leaves it
+        if self.still_bios && addr == 0x0100 {
+            self.still_bios = false;
+        }
+
+        self.bus_peek_byte(addr)
     }
-    fn tick(&mut self, _cpu_cycles: u8) {}
-}
 
-impl<M: GPUMemoriesAccess> Memory for MMU<M> {
-    fn read_byte(&mut self, addr: u16) -> u8 {
+    // the value on the bus at addr, without side effects
+    fn bus_peek_byte(&self, addr: u16) -> u8 {
         // TODO: once everything works and is tested, refactor using actual ranges
         match addr & 0xF000 {
             // BIOS
             0x0000 => {
-                if self.still_bios {
-                    match addr {
-                        0x0100 => self.still_bios = false,
-                        0x0000..=0x00FF => return self.bios[addr as usize],
-                        _ => panic!("Unhandled memory access"),
-                    }
+                if self.bios_at(addr) {
+                    return self.bios[addr as usize];
                 }
                 self.cartridge.read_rom(addr)
             }
 
             0x1000 | 0x2000 | 0x3000 => self.cartridge.read_rom(addr), // ROM 0
             0x4000 | 0x5000 | 0x6000 | 0x7000 => self.cartridge.read_rom(addr),
-            0x8000 | 0x9000 => self.gpu.read_vram(addr & 0x1FFF), // VRAM
-            0xA000 | 0xB000 => self.cartridge.read_ram(addr & 0x1FFF), // External RAM
-            0xC000 | 0xD000 | 0xE000 => self.wram[(addr & 0x1FFF) as usize], // Working RAM
+            0x8000 | 0x9000 => self.gpu.read_vram(self.vram_index(addr)), // VRAM
+            0xA000 | 0xB000 => self.cartridge.read_ram(addr & 0x1FFF),    // External RAM
+            0xC000 | 0xD000 | 0xE000 => self.wram[self.wram_index(addr)], // Working RAM
 
             0xF000 => {
                 match addr & 0x0F00 {
                     0x0000 | 0x0100 | 0x0200 | 0x0300 | 0x0400 | 0x0500 | 0x0600 | 0x0700
                     | 0x0800 | 0x0900 | 0x0A00 | 0x0B00 | 0x0C00 | 0x0D00 => {
-                        self.wram[(addr & 0x1FFF) as usize]
+                        self.wram[self.wram_index(addr)]
                     } // Working RAM echo
 
                     // GPU OAM
@@ -132,10 +507,24 @@ impl<M: GPUMemoriesAccess> Memory for MMU<M> {
                                     0xF => self.interrupt_flags,
                                     _ => 0,
                                 },
-                                0x10 | 0x20 | 0x30 => self.sound.read_byte(addr),
+                                0x10 | 0x20 | 0x30 => self.sound.peek_byte(addr),
                                 0x40 | 0x50 | 0x60 | 0x70 => {
                                     if addr == 0xFF46 {
                                         self.oam_dma_source
+                                    } else if addr == 0xFF4D {
+                                        self.read_key1()
+                                    } else if addr == 0xFF4F {
+                                        if self.cgb_mode {
+                                            0xFE | self.vram_bank
+                                        } else {
+                                            0xFF
+                                        }
+                                    } else if addr == 0xFF70 {
+                                        if self.cgb_mode {
+                                            0xF8 | self.wram_bank
+                                        } else {
+                                            0xFF
+                                        }
                                     } else {
                                         self.gpu.read_byte(addr)
                                     }
@@ -152,14 +541,17 @@ impl<M: GPUMemoriesAccess> Memory for MMU<M> {
             _ => panic!("Unhandled memory access"),
         }
     }
-    fn write_byte(&mut self, addr: u16, byte: u8) {
+
+    // writes to the bus, as seen by the DMA unit
+    fn bus_write_byte(&mut self, addr: u16, byte: u8) {
         // TODO: once everything works and is tested, refactor using actual ranges
         match addr & 0xF000 {
             0x0000 | 0x1000 | 0x2000 | 0x3000 => self.cartridge.write_rom(addr, byte), // BIOS AND ROM 0
             0x4000 | 0x5000 | 0x6000 | 0x7000 => self.cartridge.write_rom(addr, byte), // ROM 1
             // VRAM
             0x8000 | 0x9000 => {
-                self.gpu.write_vram(addr & 0x1FFF, byte);
+                let index = self.vram_index(addr);
+                self.gpu.write_vram(index, byte);
             }
             // External RAM
             0xA000 | 0xB000 => {
@@ -167,14 +559,16 @@ impl<M: GPUMemoriesAccess> Memory for MMU<M> {
             }
             // Working RAM
             0xC000 | 0xD000 | 0xE000 => {
-                self.wram[(addr & 0x1FFF) as usize] = byte;
+                let index = self.wram_index(addr);
+                self.wram[index] = byte;
             }
 
             0xF000 => {
                 match addr & 0x0F00 {
                     0x0000 | 0x0100 | 0x0200 | 0x0300 | 0x0400 | 0x0500 | 0x0600 | 0x0700
                     | 0x0800 | 0x0900 | 0x0A00 | 0x0B00 | 0x0C00 | 0x0D00 => {
-                        self.wram[(addr & 0x1FFF) as usize] = byte;
+                        let index = self.wram_index(addr);
+                        self.wram[index] = byte;
                     }
                     // GPU OAM
                     0x0E00 => {
@@ -212,15 +606,26 @@ impl<M: GPUMemoriesAccess> Memory for MMU<M> {
                             self.zram[(addr & 0x007F) as usize] = byte;
                         } else if addr >= 0xFF40 {
                             if addr == 0xFF46 {
-                                // OAM DMA transfer
+                                // OAM DMA transfer, copied a byte every machine cycle
                                 self.oam_dma_source = byte;
-                                let start: u16 = (byte as u16) << 8;
-                                for i in 0u16..160 {
-                                    let to_be_copied = self.read_byte(start + i);
-                                    self.gpu.write_oam(i, to_be_copied);
+                                self.oam_dma.start(oam_dma_start_address(byte));
+                                return;
+                            }
+                            if addr == 0xFF4D {
+                                // only the prepare bit is writable
+                                if self.cgb_mode {
+                                    self.speed_switch_prepared = byte & 1 != 0;
                                 }
                                 return;
                             }
+                            if addr == 0xFF4F {
+                                self.select_vram_bank(byte);
+                                return;
+                            }
+                            if addr == 0xFF70 {
+                                self.select_wram_bank(byte);
+                                return;
+                            }
                             self.gpu.write_byte(addr, byte);
                         } else if addr >= 0xFF10 {
                             self.sound.write_byte(addr, byte);
@@ -234,21 +639,191 @@ impl<M: GPUMemoriesAccess> Memory for MMU<M> {
             _ => panic!("Unhandled memory write"),
         }
     }
+}
+
+// the address OAM DMA starts copying from, given the source high byte.
+// The DMA unit can't see past the WRAM: sources from 0xE000 up
+// read the WRAM echo, so 0xFE00 and 0xFF00 end up copying from 0xDE00 and 0xDF00
+fn oam_dma_start_address(source: u8) -> u16 {
+    let start: u16 = (source as u16) << 8;
+    if start >= 0xE000 {
+        start - 0x2000
+    } else {
+        start
+    }
+}
+
+pub trait Memory {
+    fn read_byte(&mut self, addr: u16) -> u8;
+    fn write_byte(&mut self, addr: u16, byte: u8);
+
+    // reads without side effects, for debugging tools.
+    // Memories that can't be inspected this way read as an open bus
+    fn peek_byte(&self, _addr: u16) -> u8 {
+        0xFF
+    }
+
+    // words are little endian: the low byte is at addr. Unlike the register pairs
+    fn read_word(&mut self, addr: u16) -> u16 {
+        (self.read_byte(addr) as u16) | ((self.read_byte(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn write_word(&mut self, addr: u16, word: u16) {
+        self.write_byte(addr, (word & 0x00FF) as u8);
+        self.write_byte(addr.wrapping_add(1), ((word & 0xFF00) >> 8) as u8);
+    }
+    fn tick(&mut self, _cpu_cycles: u8) {}
+
+    // called on STOP. If a speed switch was prepared, performs it and returns true
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+
+    // cgb only, everything but the timers runs at half the cpu clock
+    fn is_double_speed(&self) -> bool {
+        false
+    }
+
+    // called when the cpu starts past the boot rom, to set up what it would have left behind
+    fn post_boot(&mut self) {}
+}
+
+// the bios and the rom are not saved, the state goes along with the same cartridge.
+// The ppu access blocking is a compatibility setting, not hardware state, so it is left alone
+impl<M: GPUMemoriesAccess + SaveState> SaveState for MMU<M> {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.still_bios);
+        state.write_bytes(&self.wram);
+        state.write_bytes(&self.zram);
+        state.write_u8(self.interrupt_enable);
+        state.write_u8(self.interrupt_flags);
+        state.write_u8(self.oam_dma_source);
+        self.oam_dma.save_state(state);
+        state.write_bool(self.speed_switch_prepared);
+        state.write_bool(self.double_speed);
+        state.write_bool(self.cgb_mode);
+        state.write_u8(self.wram_bank);
+        state.write_u8(self.vram_bank);
+        self.cartridge.save_state(state);
+        self.timers.save_state(state);
+        self.sound.save_state(state);
+        self.gpu.save_state(state);
+        self.key.save_state(state);
+        self.link.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.still_bios = state.read_bool()?;
+        state.read_bytes(&mut self.wram)?;
+        state.read_bytes(&mut self.zram)?;
+        self.interrupt_enable = state.read_u8()?;
+        self.interrupt_flags = state.read_u8()?;
+        self.oam_dma_source = state.read_u8()?;
+        self.oam_dma.load_state(state)?;
+        self.speed_switch_prepared = state.read_bool()?;
+        self.double_speed = state.read_bool()?;
+        self.cgb_mode = state.read_bool()?;
+        self.wram_bank = state.read_u8()?;
+        self.vram_bank = state.read_u8()?;
+        if !(1..WRAM_BANKS as u8).contains(&self.wram_bank) || self.vram_bank > 1 {
+            return Err(invalid_state("unknown memory bank"));
+        }
+        self.cartridge.load_state(state)?;
+        self.timers.load_state(state)?;
+        self.sound.load_state(state)?;
+        self.gpu.load_state(state)?;
+        self.key.load_state(state)?;
+        self.link.load_state(state)
+    }
+}
+
+impl<M: GPUMemoriesAccess> Memory for MMU<M> {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        if self.cpu_blocked(addr) {
+            return 0xFF;
+        }
+        self.bus_read_byte(addr)
+    }
+
+    // what the cpu would read, DMA and gpu blocking included
+    fn peek_byte(&self, addr: u16) -> u8 {
+        if self.cpu_blocked(addr) {
+            return 0xFF;
+        }
+        self.bus_peek_byte(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, byte: u8) {
+        if self.cpu_blocked(addr) {
+            return;
+        }
+        self.bus_write_byte(addr, byte)
+    }
+
+    fn post_boot(&mut self) {
+        for (addr, value) in POST_BOOT_IO.iter() {
+            self.write_byte(*addr, *value);
+        }
+        self.timers.set_system_counter(POST_BOOT_SYSTEM_COUNTER);
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_prepared {
+            return false;
+        }
+
+        self.speed_switch_prepared = false;
+        self.double_speed = !self.double_speed;
+        // the divider is reset by the switch
+        self.timers.change_divider(0);
+
+        true
+    }
+
+    fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
 
     fn tick(&mut self, cpu_cycles: u8) {
+        self.tick_oam_dma(cpu_cycles);
+
         let raise_interrupt = self.timers.tick(cpu_cycles);
 
         if raise_interrupt {
-            let interrupt_flags = self.read_byte(0xFF0F);
-            self.write_byte(0xFF0F, interrupt_flags | 4);
+            self.request_interrupt(Interrupt::Timer);
+        }
+
+        if self.link.tick(cpu_cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+
+        // in double speed the gpu and the sound see half the cpu cycles
+        let t = if self.double_speed {
+            cpu_cycles / 2
+        } else {
+            cpu_cycles
+        };
+
+        let (vblank_interrupt, stat_interrupt) = self.gpu.step(t);
+        if vblank_interrupt {
+            self.request_interrupt(Interrupt::VBlank);
+            // a frame is completed as the gpu enters vblank
+            self.frame_completed = true;
         }
+        if stat_interrupt {
+            self.request_interrupt(Interrupt::Stat);
+        }
+
+        self.sound.tick(t);
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use cartridge::load_rom;
+    use gpu::GPU;
+    use link::tests::Echo;
 
     struct DummyGPU {
         vram: [u8; 65536],
@@ -274,29 +849,39 @@ mod tests {
     }
 
     impl GPUMemoriesAccess for DummyGPU {
-        fn read_oam(&mut self, addr: u16) -> u8 {
+        fn read_oam(&self, addr: u16) -> u8 {
             self.oam[addr as usize]
         }
         fn write_oam(&mut self, addr: u16, byte: u8) {
             self.oam[addr as usize] = byte;
         }
-        fn read_vram(&mut self, addr: u16) -> u8 {
+        fn read_vram(&self, addr: u16) -> u8 {
             self.vram[addr as usize]
         }
         fn write_vram(&mut self, addr: u16, byte: u8) {
             self.vram[addr as usize] = byte;
         }
-        fn read_byte(&mut self, addr: u16) -> u8 {
+        fn read_byte(&self, addr: u16) -> u8 {
             self.registers[addr as usize]
         }
         fn write_byte(&mut self, addr: u16, byte: u8) {
             self.registers[addr as usize] = byte;
         }
+        fn reset(&mut self) {
+            *self = DummyGPU::new();
+        }
+
+        fn step(&mut self, _t: u8) -> (bool, bool) {
+            (false, false)
+        }
     }
 
     #[test]
     fn little_endian() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_word(0xC000, 0x1FF);
         assert_eq!(0x1FF, mmu.read_word(0xC000))
@@ -304,7 +889,10 @@ mod tests {
 
     #[test]
     fn read_and_write_byte() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0xC000, 0x1);
         assert_eq!(0x1, mmu.read_byte(0xC000))
@@ -314,7 +902,25 @@ mod tests {
     /// for addresses < 0x0100, rom should be accessed instead of bios
     #[test]
     fn bios_gets_replaced_by_rom() {
-        // use mocks
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        let rom_byte = mmu.cartridge.read_rom(0x0000);
+
+        // a cgb boot rom leaves the cartridge header visible
+        mmu.set_bios(vec![0xAA; CGB_BOOT_ROM_SIZE]);
+        assert_eq!(mmu.read_byte(0x0000), 0xAA);
+        assert_eq!(mmu.read_byte(0x0104), mmu.cartridge.read_rom(0x0104));
+        assert_eq!(mmu.read_byte(0x0200), 0xAA);
+        assert_eq!(mmu.read_byte(0x08FF), 0xAA);
+        assert_eq!(mmu.read_byte(0x0900), mmu.cartridge.read_rom(0x0900));
+        assert!(mmu.is_bios_mapped());
+
+        mmu.read_byte(0x0100);
+        assert!(!mmu.is_bios_mapped());
+        assert_eq!(mmu.read_byte(0x0000), rom_byte);
+        assert_eq!(mmu.read_byte(0x0200), mmu.cartridge.read_rom(0x0200));
     }
 
     /// test successful mapping for rom access
@@ -328,7 +934,10 @@ mod tests {
     /// from 0xA000 to 0xBFFF should access eram
     #[test]
     fn eram_access() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         assert_eq!(mmu.read_byte(0xA000), 0xFF);
         // returns 0xFF because this rom doesnt need an eram
@@ -346,9 +955,12 @@ mod tests {
     /// from 0xC000 to 0xFDFF should access wram
     #[test]
     fn wram_access() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
-        mmu.wram = [1; 0x2000];
+        mmu.wram = [1; WRAM_BANKS * WRAM_BANK_SIZE];
         mmu.wram[0xD000 & 0x1FFF] = 2;
 
         assert_eq!(mmu.read_byte(0xBFFF), 0xFF);
@@ -363,7 +975,10 @@ mod tests {
     /// from 0xC000 to 0xFDFF should write to wram at addr &0x1FFF
     #[test]
     fn wram_write() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0xC000, 1);
         mmu.write_byte(0xD000, 1);
@@ -381,7 +996,10 @@ mod tests {
     /// careful, cause the areas overlaps with IO
     #[test]
     fn zram_access() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.zram = [1; 0x0080];
         mmu.zram[0xFF80 & 0x007F] = 2;
@@ -400,7 +1018,10 @@ mod tests {
     /// from 0xFF80 to 0xFFFF should write to zram at addr &0x007F
     #[test]
     fn zram_write() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0xFF80, 1);
         mmu.write_byte(0xFFB0, 1);
@@ -415,7 +1036,7 @@ mod tests {
     fn gpu_vram_access() {
         let mut mmu = MMU::new(
             DummyGPU::with([1; 65536], [0; 65536]),
-            load_rom("tests/cpu_instrs/01-special.gb"),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
         );
 
         assert_eq!(mmu.read_byte(0x7FFF), 0);
@@ -430,7 +1051,10 @@ mod tests {
     /// from 0x8000 to 0x9FFF should write to gpu vram at addr &0x1FFF
     #[test]
     fn gpu_vram_write() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0x8000, 1);
         mmu.write_byte(0x9000, 1);
@@ -447,7 +1071,7 @@ mod tests {
     fn gpu_oam_access() {
         let mut mmu = MMU::new(
             DummyGPU::with([0; 65536], [1; 65536]),
-            load_rom("tests/cpu_instrs/01-special.gb"),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
         );
 
         assert_eq!(mmu.read_byte(0xFDFF), 0);
@@ -461,7 +1085,10 @@ mod tests {
     /// from 0xFE00 to 0xFE9F should write to gpu oam at addr &0x00FF
     #[test]
     fn gpu_oam_write() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0xFE00, 1);
         mmu.write_byte(0xFE70, 1);
@@ -476,7 +1103,10 @@ mod tests {
     /// from 0xFF40 to 0xFF7F should write to gpu registers
     #[test]
     fn gpu_registers_write() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         for i in 0u16..64u16 {
             mmu.write_byte(0xFF40 + i, 1);
@@ -488,14 +1118,266 @@ mod tests {
         assert_eq!(mmu.gpu.registers[0xFF80], 0);
 
         for i in 0u16..64u16 {
-            assert_eq!(mmu.read_byte(0xFF40 + i), 1);
+            if 0xFF40 + i == 0xFF4D || 0xFF40 + i == 0xFF4F || 0xFF40 + i == 0xFF70 {
+                // KEY1 and the bank registers belong to the mmu, and are cgb only
+                assert_eq!(mmu.read_byte(0xFF40 + i), 0xFF);
+            } else {
+                assert_eq!(mmu.read_byte(0xFF40 + i), 1);
+            }
+        }
+    }
+
+    /// the gpu locks vram in mode 3, and oam in modes 2 and 3
+    #[test]
+    fn ppu_access_blocking() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xFE00, 0x34);
+        mmu.write_byte(0xFF40, 0x80);
+
+        // oam search
+        assert_eq!(mmu.gpu.status().mode, 2);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+
+        // drawing
+        mmu.gpu.step(80);
+        assert_eq!(mmu.gpu.status().mode, 3);
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.write_byte(0x8000, 0x56);
+
+        // hblank
+        mmu.gpu.step(172);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0x34);
+    }
+
+    /// with the blocking off, or the lcd off, everything is accessible
+    #[test]
+    fn ppu_access_blocking_off() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0x8000, 0x12);
+        mmu.gpu.step(80);
+        assert_eq!(mmu.gpu.status().mode, 3);
+
+        // lcd off
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+
+        mmu.write_byte(0xFF40, 0x80);
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+
+        let mut state = StateWriter::new();
+        mmu.save_state(&mut state);
+        mmu.set_ppu_access_blocking(false);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+
+        // loading a state doesn't turn it back on
+        mmu.load_state(&mut StateReader::new(&state.into_bytes()))
+            .unwrap();
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+    }
+
+    /// in cgb mode, 0xFF70 switches the wram bank at 0xD000
+    #[test]
+    fn wram_banks() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.set_cgb_mode(true);
+
+        mmu.write_byte(0xC000, 0x10);
+        mmu.write_byte(0xD000, 0x11);
+        mmu.write_byte(0xFF70, 2);
+        mmu.write_byte(0xD000, 0x22);
+
+        assert_eq!(mmu.read_byte(0xFF70), 0xFA);
+        assert_eq!(mmu.read_byte(0xD000), 0x22);
+        // bank 0 and the echo follow
+        assert_eq!(mmu.read_byte(0xC000), 0x10);
+        assert_eq!(mmu.read_byte(0xF000), 0x22);
+
+        // 0 selects bank 1
+        mmu.write_byte(0xFF70, 0);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF9);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+    }
+
+    /// on dmg, 0xFF70 does nothing
+    #[test]
+    fn wram_banks_dmg() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        assert!(!mmu.is_cgb_mode());
+
+        mmu.write_byte(0xD000, 0x11);
+        mmu.write_byte(0xFF70, 2);
+
+        assert_eq!(mmu.read_byte(0xFF70), 0xFF);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+    }
+
+    /// in cgb mode, 0xFF4F switches the vram bank
+    #[test]
+    fn vram_banks() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.set_cgb_mode(true);
+
+        mmu.write_byte(0x8010, 0x11);
+        mmu.write_byte(0xFF4F, 1);
+        mmu.write_byte(0x8010, 0x22);
+
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0x8010), 0x22);
+        assert_eq!(mmu.gpu.vram[0x0010], 0x11);
+        assert_eq!(mmu.gpu.vram[0x2010], 0x22);
+
+        mmu.write_byte(0xFF4F, 0);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        assert_eq!(mmu.read_byte(0x8010), 0x11);
+    }
+
+    /// OAM DMA from the echo area should copy the mirrored WRAM bytes
+    #[test]
+    fn oam_dma_from_echo_ram() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+
+        for i in 0..0x2000 {
+            mmu.wram[i] = i as u8;
+        }
+
+        mmu.write_byte(0xFF46, 0xE0);
+        for _ in 0..160 {
+            mmu.tick(4);
+        }
+        for i in 0u16..160 {
+            assert_eq!(mmu.gpu.oam[i as usize], mmu.wram[i as usize]);
+        }
+
+        mmu.write_byte(0xFF46, 0xE1);
+        for _ in 0..160 {
+            mmu.tick(4);
+        }
+
+        assert_eq!(mmu.read_byte(0xFF46), 0xE1);
+        for i in 0u16..160 {
+            assert_eq!(mmu.gpu.oam[i as usize], mmu.wram[(0x0100 + i) as usize]);
+        }
+
+        // sources above the echo area keep reading the wram
+        mmu.write_byte(0xFF46, 0xFF);
+        for _ in 0..160 {
+            mmu.tick(4);
+        }
+        for i in 0u16..160 {
+            assert_eq!(mmu.gpu.oam[i as usize], mmu.wram[(0x1F00 + i) as usize]);
+        }
+    }
+
+    /// peeking sees what the cpu would read, OAM DMA blocking included
+    #[test]
+    fn peek_byte() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0xC123, 0x42);
+        mmu.zram[0] = 0x24;
+
+        assert_eq!(mmu.peek_byte(0xC123), 0x42);
+        assert_eq!(mmu.peek_byte(0xC123), mmu.read_byte(0xC123));
+        // echo ram
+        assert_eq!(mmu.peek_byte(0xE123), 0x42);
+
+        // during OAM DMA only HRAM is left
+        mmu.write_byte(0xFF46, 0xC0);
+        mmu.tick(4);
+        assert_eq!(mmu.peek_byte(0xC123), 0xFF);
+        assert_eq!(mmu.peek_byte(0xC123), mmu.read_byte(0xC123));
+        assert_eq!(mmu.peek_byte(0xFF80), 0x24);
+    }
+
+    /// OAM DMA copies a byte every machine cycle, blocking the cpu out of everything but HRAM
+    #[test]
+    fn oam_dma_timing() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+
+        for i in 0..160 {
+            mmu.wram[i] = 1 + i as u8;
+        }
+        mmu.zram[0] = 0x42;
+
+        mmu.write_byte(0xFF46, 0xC0);
+
+        // 10 machine cycles
+        mmu.tick(40);
+
+        for i in 0..160 {
+            let expected = if i < 10 { 1 + i as u8 } else { 0 };
+            assert_eq!(mmu.gpu.oam[i], expected);
+        }
+
+        assert_eq!(mmu.read_byte(0xC000), 0xFF);
+        assert_eq!(mmu.read_byte(0xFF80), 0x42);
+        mmu.write_byte(0xC000, 0);
+        assert_eq!(mmu.wram[0], 1);
+
+        for _ in 10..160 {
+            mmu.tick(4);
+        }
+
+        for i in 0..160 {
+            assert_eq!(mmu.gpu.oam[i], 1 + i as u8);
+        }
+        assert_eq!(mmu.read_byte(0xC000), 1);
+    }
+
+    #[test]
+    fn serial_transfer() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.link.connect_peer(Box::new(Echo));
+        mmu.write_byte(0xFF0F, 0);
+
+        mmu.write_byte(0xFF01, 0x5A);
+        mmu.write_byte(0xFF02, 0x81);
+
+        for _ in 0..1024 {
+            mmu.tick(4);
         }
+
+        assert_eq!(mmu.read_byte(0xFF01), 0x5A);
+        assert_eq!(mmu.read_byte(0xFF02) & 0x80, 0);
+        assert_ne!(mmu.read_byte(0xFF0F) & 8, 0);
     }
 
     /// unmapped area (0xFEA0-0xFEFF) is unwritable and reads should always return 0xFF
     #[test]
     fn unmapped_areas() {
-        let mut mmu = MMU::new(DummyGPU::new(), load_rom("tests/cpu_instrs/01-special.gb"));
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
 
         mmu.write_byte(0xFEA0, 0);
         assert_eq!(mmu.read_byte(0xFEA0), 0xFF);
@@ -508,4 +1390,142 @@ mod tests {
         mmu.write_byte(0xFEFF, 0);
         assert_eq!(mmu.read_byte(0xFEFF), 0xFF);
     }
+
+    #[test]
+    fn request_interrupt() {
+        let mut mmu = MMU::new(
+            DummyGPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0xFF0F, 0xE0);
+
+        mmu.request_interrupt(Interrupt::Timer);
+        assert_eq!(mmu.read_byte(0xFF0F), 0xE4);
+        assert_eq!(mmu.pending_interrupts(), 0);
+
+        mmu.request_interrupt(Interrupt::Joypad);
+        mmu.write_byte(0xFFFF, 0xFF);
+        assert_eq!(mmu.pending_interrupts(), 0x14);
+
+        mmu.write_byte(0xFFFF, Interrupt::Joypad.mask());
+        assert_eq!(mmu.pending_interrupts(), 0x10);
+    }
+
+    /// the joypad interrupt only comes from a selected line going low
+    #[test]
+    fn joypad_interrupt() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0xFF0F, 0);
+
+        // directions selected
+        mmu.write_byte(0xFF00, 0x20);
+
+        mmu.press_button(Button::A);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x10, 0);
+
+        mmu.press_button(Button::LEFT);
+        assert_ne!(mmu.read_byte(0xFF0F) & 0x10, 0);
+
+        // the line is already low
+        mmu.write_byte(0xFF0F, 0);
+        mmu.press_button(Button::LEFT);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x10, 0);
+
+        mmu.release_button(Button::LEFT);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x10, 0);
+        mmu.press_button(Button::RIGHT);
+        assert_ne!(mmu.read_byte(0xFF0F) & 0x10, 0);
+    }
+
+    #[test]
+    fn set_buttons() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        mmu.write_byte(0xFF0F, 0);
+
+        // buttons selected
+        mmu.write_byte(0xFF00, 0x10);
+
+        let mut state = ButtonState::new();
+        state.set(Button::A, true);
+        state.set(Button::START, true);
+        mmu.set_buttons(state);
+        assert_eq!(mmu.read_byte(0xFF00), 0xD6);
+        assert_ne!(mmu.read_byte(0xFF0F) & 0x10, 0);
+
+        // the same lines stay low, no new interrupt
+        mmu.write_byte(0xFF0F, 0);
+        state.set(Button::START, false);
+        mmu.set_buttons(state);
+        assert_eq!(mmu.read_byte(0xFF00), 0xDE);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x10, 0);
+
+        // the directions are untouched
+        mmu.write_byte(0xFF00, 0x20);
+        assert_eq!(mmu.read_byte(0xFF00), 0xEF);
+
+        state.set(Button::DOWN, true);
+        mmu.set_buttons(state);
+        assert_eq!(mmu.read_byte(0xFF00), 0xE7);
+        assert_ne!(mmu.read_byte(0xFF0F) & 0x10, 0);
+    }
+
+    #[test]
+    fn ram_init_pattern() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+        assert_eq!(mmu.read_byte(0xC000), 0x00);
+
+        mmu.set_ram_init_pattern(RamInitPattern::Ones);
+        for addr in [0xC000, 0xDFFF, 0xFF80, 0xFFFE, 0x8000, 0x9FFF, 0xFE00].iter() {
+            assert_eq!(mmu.read_byte(*addr), 0xFF);
+        }
+
+        // kept on reset
+        mmu.write_byte(0xC000, 0x12);
+        mmu.reset();
+        assert_eq!(mmu.read_byte(0xC000), 0xFF);
+
+        // the same seed fills the same bytes
+        mmu.set_ram_init_pattern(RamInitPattern::Random(42));
+        let wram = mmu.wram;
+        assert!(wram.iter().any(|byte| *byte != wram[0]));
+        mmu.set_ram_init_pattern(RamInitPattern::Random(42));
+        assert_eq!(mmu.wram[..], wram[..]);
+    }
+
+    /// echo ram ends at 0xFDFF, oam starts at 0xFE00
+    #[test]
+    fn echo_ram_boundary() {
+        let mut mmu = MMU::new(
+            GPU::new(),
+            load_rom("tests/cpu_instrs/01-special.gb").unwrap(),
+        );
+
+        mmu.write_byte(0xFDFE, 0x11);
+        mmu.write_byte(0xFDFF, 0x22);
+        mmu.write_byte(0xFE00, 0x33);
+        assert_eq!(mmu.wram[0x1DFE], 0x11);
+        assert_eq!(mmu.wram[0x1DFF], 0x22);
+        assert_eq!(mmu.wram[0x1E00], 0x00);
+        assert_eq!(mmu.gpu.read_oam(0), 0x33);
+
+        assert_eq!(mmu.read_byte(0xDDFE), 0x11);
+        assert_eq!(mmu.read_byte(0xDDFF), 0x22);
+        assert_eq!(mmu.read_byte(0xDE00), 0x00);
+
+        // and the other way around
+        mmu.write_byte(0xDDFF, 0x44);
+        mmu.write_byte(0xDE00, 0x55);
+        assert_eq!(mmu.read_byte(0xFDFF), 0x44);
+        assert_eq!(mmu.read_byte(0xFE00), 0x33);
+        assert_eq!(mmu.read_byte(0xFDFE), 0x11);
+    }
 }
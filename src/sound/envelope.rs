@@ -1,4 +1,6 @@
 use sound::{Sample, TimerDefaultPeriod};
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
 
 // every tick, increases or decreases volume
 #[derive(Clone, Copy)]
@@ -65,6 +67,23 @@ impl Envelope {
     }
 }
 
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.timer.save_state(state);
+        state.write_bool(self.add_mode);
+        state.write_u8(self.volume.0);
+        state.write_u8(self.volume_initial.0);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.timer.load_state(state)?;
+        self.add_mode = state.read_bool()?;
+        self.volume = Sample(state.read_u8()? & 0xF);
+        self.volume_initial = Sample(state.read_u8()? & 0xF);
+        Ok(())
+    }
+}
+
 impl Default for Envelope {
     fn default() -> Self {
         Envelope::new()
@@ -1,3 +1,6 @@
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
+
 // all the channels have a max length value of 64, except for wave
 #[derive(Clone, Copy)]
 #[repr(u16)]
@@ -116,3 +119,19 @@ impl Length {
         self.enable
     }
 }
+
+impl SaveState for Length {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enable);
+        state.write_u16(self.timer);
+        state.write_bool(self.half_period_passed);
+    }
+
+    // the max length is fixed by the channel, it's not part of the state
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.enable = state.read_bool()?;
+        self.timer = state.read_u16()?.min(self.max_length as u16);
+        self.half_period_passed = state.read_bool()?;
+        Ok(())
+    }
+}
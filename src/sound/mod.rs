@@ -1,3 +1,4 @@
+use std::io;
 use std::ops::{Add, AddAssign};
 
 use cpu::CPU_FREQ;
@@ -7,6 +8,7 @@ use sound::length::Length;
 use sound::noise::NoiseChannel;
 use sound::square::SquareChannel;
 use sound::wave::WaveChannel;
+use state::{SaveState, StateReader, StateWriter};
 
 pub mod envelope;
 pub mod length;
@@ -15,16 +17,27 @@ pub mod square;
 pub mod sweep;
 pub mod wave;
 
-pub const AUDIO_BUFFER_SIZE: usize = 1024;
+pub const AUDIO_BUFFER_SIZE: usize = 1024; // samples handed out at once, unless configured
 pub const SAMPLE_RATE: usize = 44_100;
 
 const WAVE_TABLE_START: u16 = 0xFF30;
+
+// what NR10 (FF10) to NR51 (FF25) read while powered off: zeroed registers,
+// with the write-only and the unused bits reading as 1
+const POWERED_OFF_READS: [u8; 0x16] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, // NR50-NR51
+];
+
 const DUTY_PATTERNS_LENGTH: u8 = 8;
 
-// final volume is moltiplied by this value
-const VOLUME_BOOST: u8 = 3;
+// final volume is moltiplied by this value, unless configured
+pub const DEFAULT_GAIN: f32 = 3.0;
 
-type AudioOutType = i16;
+pub type AudioOutType = i16;
 
 #[derive(Eq, Clone, Copy)]
 pub struct Sample(u8);
@@ -104,6 +117,9 @@ pub struct Sound {
 
     frame_sequencer: FrameSequencer, // responsible for ticking the channels
     sample_timer: Timer,             // timer for fetching the channels output
+    sample_rate: usize,              // samples per second the host plays
+    audio_buffer_size: usize,        // samples handed out at once
+    gain: f32,                       // the samples handed out are multiplied by it
 
     left_sound_output: SoundOutput,
     right_sound_output: SoundOutput,
@@ -114,6 +130,18 @@ pub struct Sound {
 
 impl Memory for Sound {
     fn read_byte(&mut self, addr: u16) -> u8 {
+        self.peek_byte(addr)
+    }
+
+    // reading the sound registers has no side effects
+    fn peek_byte(&self, addr: u16) -> u8 {
+        // powered off, NR10-NR51 read as zeroed whatever the channels kept,
+        // like the length counters. NR52 and the wave ram are still readable
+        if !self.power && (0x10..0x26).contains(&(addr & 0xff)) {
+            return POWERED_OFF_READS[(addr & 0xff) as usize - 0x10];
+        }
+
+        // the getters set the bits that can't be read
         match addr & 0xff {
             0x10 => self.get_nr10(),
             0x11 => self.get_nr11(),
@@ -200,23 +228,49 @@ impl Default for ChannelsOutput {
 struct SoundOutput {
     mixer: Mixer,
     volume_master: VolumeMaster,
+    high_pass_filter: HighPassFilter,
     out_buffer: OutputBuffer,
 }
 
 impl SoundOutput {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: usize, buffer_size: usize) -> Self {
         SoundOutput {
             mixer: Mixer::new(),
             volume_master: VolumeMaster::new(),
-            out_buffer: OutputBuffer::new(),
+            high_pass_filter: HighPassFilter::new(sample_rate),
+            out_buffer: OutputBuffer::with_size(buffer_size),
         }
     }
 
     pub fn receive(&mut self, channel_outputs: ChannelsOutput) {
         let mixed = self.mixer.mix(channel_outputs);
         let scaled = self.volume_master.apply(mixed);
+        let filtered = self.high_pass_filter.filter(scaled.0 as f32);
 
-        self.out_buffer.push(scaled);
+        self.out_buffer.push(Voltage(filtered.round() as i16));
+    }
+}
+
+// Removes the DC offset from the output, like the capacitor on the DMG does.
+// Without it, a sustained tone never fades towards silence and the output clicks
+pub struct HighPassFilter {
+    capacitor: f32,
+    charge_factor: f32, // how much charge the capacitor keeps between two samples
+}
+
+impl HighPassFilter {
+    pub fn new(sample_rate: usize) -> Self {
+        HighPassFilter {
+            capacitor: 0.0,
+            // the capacitor keeps 0.999958 of its charge every cpu cycle
+            charge_factor: 0.999_958f32.powf((CPU_FREQ / sample_rate) as f32),
+        }
+    }
+
+    pub fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
     }
 }
 
@@ -310,27 +364,59 @@ impl Mixer {
     }
 }
 
+impl SaveState for Mixer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.read());
+        state.write_bool(self.vin);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.read_u8()?);
+        self.vin = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for Mixer {
     fn default() -> Self {
         Mixer::new()
     }
 }
 
+// Receives the audio samples as soon as a buffer is filled
+pub trait AudioSink {
+    fn submit(&mut self, samples: &[AudioOutType]);
+}
+
 pub struct OutputBuffer {
     // output buffer
     buffer_index: usize,
     audio_available: bool,
-    buffer: [AudioOutType; AUDIO_BUFFER_SIZE],
-    buffer_2: [AudioOutType; AUDIO_BUFFER_SIZE],
+    buffer: Vec<AudioOutType>,
+    buffer_2: Vec<AudioOutType>,
+
+    // when set, filled buffers are handed to it instead of waiting for get_audio_buffer
+    sink: Option<Box<dyn AudioSink>>,
+    gain: f32, // the samples handed out are multiplied by it
 }
 
 impl OutputBuffer {
     pub fn new() -> Self {
+        OutputBuffer::with_size(AUDIO_BUFFER_SIZE)
+    }
+
+    // a buffer handing out size samples at once. Smaller means less latency,
+    // and more chances for the host to run out of samples
+    pub fn with_size(size: usize) -> Self {
+        assert!(size > 0, "the audio buffer can't be empty");
+
         OutputBuffer {
             buffer_index: 0,
             audio_available: false,
-            buffer: [0; AUDIO_BUFFER_SIZE],
-            buffer_2: [0; AUDIO_BUFFER_SIZE],
+            buffer: vec![0; size],
+            buffer_2: vec![0; size],
+            sink: None,
+            gain: DEFAULT_GAIN,
         }
     }
 
@@ -339,11 +425,13 @@ impl OutputBuffer {
         self.buffer_index += 1;
 
         if self.buffer_index == self.buffer.len() {
-            // todo: actually, a callback should be called here
-            self.audio_available = true;
+            for (boosted, sample) in self.buffer_2.iter_mut().zip(self.buffer.iter()) {
+                *boosted = amplify(*sample, self.gain);
+            }
 
-            for i in 0..AUDIO_BUFFER_SIZE {
-                self.buffer_2[i] = self.buffer[i] * VOLUME_BOOST as i16;
+            match self.sink.as_mut() {
+                Some(sink) => sink.submit(&self.buffer_2),
+                None => self.audio_available = true,
             }
 
             self.buffer_index = 0;
@@ -351,13 +439,35 @@ impl OutputBuffer {
     }
 
     // return the audio_buffer if it is filled
-    pub fn get_audio_buffer(&mut self) -> Option<&[AudioOutType; AUDIO_BUFFER_SIZE]> {
+    pub fn get_audio_buffer(&mut self) -> Option<&[AudioOutType]> {
         if !self.audio_available {
             return None;
         }
         self.audio_available = false;
         Some(&self.buffer_2)
     }
+
+    // the samples of the buffer being filled, taken out of it.
+    // With a sink set they are left for the sink, to get with the rest of the buffer
+    pub fn take_pending(&mut self) -> Vec<AudioOutType> {
+        if self.sink.is_some() {
+            return Vec::new();
+        }
+
+        let pending = self.buffer[0..self.buffer_index]
+            .iter()
+            .map(|sample| amplify(*sample, self.gain))
+            .collect();
+        self.buffer_index = 0;
+        pending
+    }
+}
+
+// the sample multiplied by gain, clipping at the edges of the range instead of wrapping around
+fn amplify(sample: AudioOutType, gain: f32) -> AudioOutType {
+    (sample as f32 * gain)
+        .round()
+        .clamp(AudioOutType::MIN as f32, AudioOutType::MAX as f32) as AudioOutType
 }
 
 impl Default for OutputBuffer {
@@ -368,6 +478,16 @@ impl Default for OutputBuffer {
 
 impl Sound {
     pub fn new() -> Self {
+        Sound::with_sample_rate(SAMPLE_RATE)
+    }
+
+    // a sound producing sample_rate samples per second of emulated time
+    pub fn with_sample_rate(sample_rate: usize) -> Self {
+        Sound::with_audio_buffer(sample_rate, AUDIO_BUFFER_SIZE)
+    }
+
+    // like with_sample_rate, handing out the samples buffer_size at a time
+    pub fn with_audio_buffer(sample_rate: usize, buffer_size: usize) -> Self {
         Sound {
             square_1: SquareChannel::new(),
             square_2: SquareChannel::new(),
@@ -375,15 +495,68 @@ impl Sound {
             noise: NoiseChannel::new(),
 
             frame_sequencer: FrameSequencer::new(),
-            sample_timer: Timer::new(CPU_FREQ / SAMPLE_RATE),
+            sample_timer: Timer::new(CPU_FREQ / sample_rate),
+            sample_rate,
+            audio_buffer_size: buffer_size,
+            gain: DEFAULT_GAIN,
 
-            left_sound_output: SoundOutput::new(),
-            right_sound_output: SoundOutput::new(),
+            left_sound_output: SoundOutput::new(sample_rate, buffer_size),
+            right_sound_output: SoundOutput::new(sample_rate, buffer_size),
 
             power: false,
         }
     }
 
+    // like the gameboy was just switched on. The host settings are kept:
+    // the sink, the sample rate, the buffer size and the gain
+    pub fn power_cycle(&mut self) {
+        let sink = self.left_sound_output.out_buffer.sink.take();
+        let gain = self.gain;
+
+        *self = Sound::with_audio_buffer(self.sample_rate, self.audio_buffer_size);
+        self.left_sound_output.out_buffer.sink = sink;
+        self.set_gain(gain);
+    }
+
+    // switches to the rate the host actually plays at, so the pitch stays right
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        self.sample_rate = sample_rate;
+        self.sample_timer = Timer::new(CPU_FREQ / sample_rate);
+        self.left_sound_output.high_pass_filter = HighPassFilter::new(sample_rate);
+        self.right_sound_output.high_pass_filter = HighPassFilter::new(sample_rate);
+    }
+
+    pub fn get_sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    // switches to handing out buffer_size samples at once. The samples waiting
+    // in the old buffers are dropped
+    pub fn set_audio_buffer_size(&mut self, buffer_size: usize) {
+        self.audio_buffer_size = buffer_size;
+        for output in [&mut self.left_sound_output, &mut self.right_sound_output].iter_mut() {
+            let sink = output.out_buffer.sink.take();
+            output.out_buffer = OutputBuffer::with_size(buffer_size);
+            output.out_buffer.sink = sink;
+            output.out_buffer.gain = self.gain;
+        }
+    }
+
+    pub fn get_audio_buffer_size(&self) -> usize {
+        self.audio_buffer_size
+    }
+
+    // the volume of the samples handed out, they clip instead of going past the range
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+        self.left_sound_output.out_buffer.gain = gain;
+        self.right_sound_output.out_buffer.gain = gain;
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain
+    }
+
     pub fn tick(&mut self, t: u8) {
         for _i in 0..t {
             self.tick_channels();
@@ -452,10 +625,20 @@ impl Sound {
         // todo: what about right sound output?
     }
 
-    pub fn get_audio_buffer(&mut self) -> Option<&[AudioOutType; AUDIO_BUFFER_SIZE]> {
+    pub fn get_audio_buffer(&mut self) -> Option<&[AudioOutType]> {
         self.left_sound_output.out_buffer.get_audio_buffer()
     }
 
+    // the samples produced since the last buffer was filled, without waiting for the rest
+    pub fn take_pending_samples(&mut self) -> Vec<AudioOutType> {
+        self.left_sound_output.out_buffer.take_pending()
+    }
+
+    // filled audio buffers will be submitted to the sink instead of being polled
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.left_sound_output.out_buffer.sink = Some(sink);
+    }
+
     // Square channel 1 sweep
     // NR10 FF10 -PPP NSSS Sweep period, negate, shift
     pub fn set_nr10(&mut self, value: u8) {
@@ -472,8 +655,6 @@ impl Sound {
     // Square channel 1 duty and length load
     // NR11 FF11 DDLL LLLL Duty, Length load (64-L)
     pub fn set_nr11(&mut self, value: u8) {
-        // on the DMG length counters are unaffected by power
-        // and can still be written while power off
         if self.power {
             self.square_1.write_register_1(value);
         }
@@ -527,8 +708,6 @@ impl Sound {
     // Square channel 2 duty and length load
     // NR21 FF16 DDLL LLLL Duty, Length load (64-L)
     pub fn set_nr21(&mut self, value: u8) {
-        // on the DMG length counters are unaffected by power
-        // and can still be written while power off
         if self.power {
             self.square_2.write_register_1(value);
         }
@@ -600,8 +779,6 @@ impl Sound {
     // Wave channel length load
     // NR31 FF1B LLLL LLLL Length load (256-L)
     pub fn set_nr31(&mut self, value: u8) {
-        // on the DMG length counters are unaffected by power
-        // and can still be written while power off
         self.wave.write_length_value(value)
     }
 
@@ -767,7 +944,7 @@ impl Sound {
             // When powered on, the frame sequencer is reset so that the
             // next step will be 0, the square duty units are reset to the first step
             // of the waveform, and the wave channel's sample buffer is reset to 0.
-            self.frame_sequencer.step = 7;
+            self.frame_sequencer.reset();
             self.square_1.duty_index = 0;
             self.square_2.duty_index = 0;
             self.wave.buffer = 0;
@@ -794,27 +971,33 @@ impl Sound {
 
     // called when power is set to off, through register nr52
     pub fn reset(&mut self) {
-        self.left_sound_output = SoundOutput::new();
-        self.right_sound_output = SoundOutput::new();
+        // the sink belongs to the frontend, it must survive the power off
+        let sink = self.left_sound_output.out_buffer.sink.take();
 
+        self.left_sound_output = SoundOutput::new(self.sample_rate, self.audio_buffer_size);
+        self.right_sound_output = SoundOutput::new(self.sample_rate, self.audio_buffer_size);
+
+        self.left_sound_output.out_buffer.sink = sink;
+        let gain = self.gain;
+        self.set_gain(gain);
+
+        // only the duties: the length counters survive the power off on DMG
         self.set_nr10(0);
-        self.set_nr11(0);
+        self.square_1.write_register_1(0);
         self.set_nr12(0);
         self.set_nr13(0);
         self.set_nr14(0);
 
-        self.set_nr21(0);
+        self.square_2.write_register_1(0);
         self.set_nr22(0);
         self.set_nr23(0);
         self.set_nr24(0);
 
         self.set_nr30(0);
-        self.set_nr31(0);
         self.set_nr32(0);
         self.set_nr33(0);
         self.set_nr34(0);
 
-        // powering off shouldn't affect NR41
         self.set_nr42(0);
         self.set_nr43(0);
         self.set_nr44(0);
@@ -831,6 +1014,48 @@ impl Sound {
     }
 }
 
+// the high pass filter and the output buffers only shape what the host plays, they are left alone
+impl SaveState for Sound {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.square_1.save_state(state);
+        self.square_2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+
+        self.frame_sequencer.save_state(state);
+        self.sample_timer.save_state(state);
+
+        for output in [&self.left_sound_output, &self.right_sound_output].iter() {
+            output.mixer.save_state(state);
+            state.write_u8(output.volume_master.get_volume());
+        }
+
+        state.write_bool(self.power);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.square_1.load_state(state)?;
+        self.square_2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+
+        self.frame_sequencer.load_state(state)?;
+        self.sample_timer.load_state(state)?;
+        // the state might come from a host playing at another rate
+        let period = CPU_FREQ / self.sample_rate;
+        self.sample_timer.curr = self.sample_timer.curr.min(period);
+        self.sample_timer.period = period;
+
+        for output in [&mut self.left_sound_output, &mut self.right_sound_output].iter_mut() {
+            output.mixer.load_state(state)?;
+            output.volume_master.set_volume(state.read_u8()? & 0b111);
+        }
+
+        self.power = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for Sound {
     fn default() -> Self {
         Sound::new()
@@ -860,12 +1085,26 @@ impl FrameSequencer {
         timer_up
     }
 
+    // the next step will be 0, a whole period from now
     pub fn reset(&mut self) {
-        self.step = 0;
+        self.step = 7;
         self.timer.restart();
     }
 }
 
+impl SaveState for FrameSequencer {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.timer.save_state(state);
+        state.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.timer.load_state(state)?;
+        self.step = state.read_u8()? % DUTY_PATTERNS_LENGTH;
+        Ok(())
+    }
+}
+
 impl Default for FrameSequencer {
     fn default() -> Self {
         FrameSequencer::new()
@@ -916,6 +1155,19 @@ impl TimerDefaultPeriod {
     }
 }
 
+impl SaveState for TimerDefaultPeriod {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_usize(self.period);
+        state.write_usize(self.curr);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.period = state.read_usize()?;
+        self.curr = state.read_usize()?;
+        Ok(())
+    }
+}
+
 impl Default for TimerDefaultPeriod {
     fn default() -> Self {
         TimerDefaultPeriod::new()
@@ -957,3 +1209,438 @@ impl Timer {
         self.curr = self.period;
     }
 }
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_usize(self.period);
+        state.write_usize(self.curr);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.period = state.read_usize()?;
+        self.curr = state.read_usize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct MockSink {
+        pushed: Rc<RefCell<usize>>,
+    }
+
+    impl AudioSink for MockSink {
+        fn submit(&mut self, samples: &[AudioOutType]) {
+            *self.pushed.borrow_mut() += samples.len();
+        }
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let sound = Sound::with_sample_rate(48_000);
+        assert_eq!(sound.sample_timer.period, CPU_FREQ / 48_000);
+        assert_eq!(sound.get_sample_rate(), 48_000);
+
+        let mut sound = Sound::new();
+        assert_eq!(sound.sample_timer.period, CPU_FREQ / SAMPLE_RATE);
+
+        // a state keeps the rate of whoever loads it
+        let mut state = StateWriter::new();
+        sound.save_state(&mut state);
+        sound.set_sample_rate(22_050);
+        sound
+            .load_state(&mut StateReader::new(&state.into_bytes()))
+            .unwrap();
+        assert_eq!(sound.sample_timer.period, CPU_FREQ / 22_050);
+    }
+
+    #[test]
+    fn test_high_pass_filter_decays() {
+        let mut filter = HighPassFilter::new(SAMPLE_RATE);
+
+        let first = filter.filter(15.0);
+        assert_eq!(first, 15.0);
+
+        let mut last = first;
+        for _ in 0..2000 {
+            let output = filter.filter(15.0);
+            assert!(output <= last);
+            last = output;
+        }
+
+        assert!(last.abs() < 0.01);
+    }
+
+    #[test]
+    fn test_audio_sink() {
+        let pushed = Rc::new(RefCell::new(0));
+        let mut sound = Sound::new();
+        sound.set_audio_sink(Box::new(MockSink {
+            pushed: pushed.clone(),
+        }));
+
+        // a sample is produced every CPU_FREQ / SAMPLE_RATE cycles
+        let cycles_per_buffer = CPU_FREQ / SAMPLE_RATE * AUDIO_BUFFER_SIZE;
+        for _ in 0..(2 * cycles_per_buffer - 1) {
+            sound.tick(1);
+        }
+        assert_eq!(*pushed.borrow(), AUDIO_BUFFER_SIZE);
+
+        sound.tick(1);
+        assert_eq!(*pushed.borrow(), 2 * AUDIO_BUFFER_SIZE);
+
+        // samples went to the sink, nothing to poll
+        assert!(sound.get_audio_buffer().is_none());
+    }
+
+    // loud samples clip at the edges of the range instead of wrapping around
+    #[test]
+    fn test_gain_saturates() {
+        let mut buffer = OutputBuffer::with_size(5);
+        buffer.gain = 8.0;
+
+        for voltage in [i16::MAX - 10, -20_000, 100, 20_000, -100].iter() {
+            buffer.push(Voltage(*voltage));
+        }
+        assert_eq!(
+            buffer.get_audio_buffer(),
+            Some(&[i16::MAX, i16::MIN, 800, i16::MAX, -800][..])
+        );
+
+        // the sound keeps its gain through a power off
+        let mut sound = Sound::new();
+        assert_eq!(sound.get_gain(), DEFAULT_GAIN);
+        sound.set_gain(0.5);
+        sound.write_byte(0xFF26, 0x80);
+        sound.write_byte(0xFF26, 0x00);
+        sound.power_cycle();
+        assert_eq!(sound.get_gain(), 0.5);
+        assert_eq!(sound.left_sound_output.out_buffer.gain, 0.5);
+    }
+
+    #[test]
+    fn test_audio_buffer_size() {
+        let mut sound = Sound::with_audio_buffer(SAMPLE_RATE, 256);
+        assert_eq!(sound.get_audio_buffer_size(), 256);
+
+        let cycles_per_buffer = CPU_FREQ / SAMPLE_RATE * 256;
+        for _ in 0..(cycles_per_buffer - 1) {
+            sound.tick(1);
+        }
+        assert!(sound.get_audio_buffer().is_none());
+
+        sound.tick(1);
+        assert_eq!(
+            sound.get_audio_buffer().map(|buffer| buffer.len()),
+            Some(256)
+        );
+        assert!(sound.get_audio_buffer().is_none());
+
+        // a power cycle keeps it, the samples waiting are dropped on a change
+        sound.power_cycle();
+        assert_eq!(sound.get_audio_buffer_size(), 256);
+        sound.tick(200);
+        sound.set_audio_buffer_size(64);
+        assert!(sound.take_pending_samples().is_empty());
+        for _ in 0..(CPU_FREQ / SAMPLE_RATE * 64) {
+            sound.tick(1);
+        }
+        assert_eq!(
+            sound.get_audio_buffer().map(|buffer| buffer.len()),
+            Some(64)
+        );
+    }
+
+    // ticks until the frame sequencer moves to step
+    fn tick_to_step(sound: &mut Sound, step: u8) {
+        while sound.frame_sequencer.step != step {
+            sound.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_power_on_restarts_frame_sequencer() {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+
+        // halfway through a step
+        tick_to_step(&mut sound, 3);
+        sound.tick(100);
+
+        sound.write_byte(0xFF26, 0x00);
+        sound.write_byte(0xFF26, 0x80);
+        assert_eq!(sound.frame_sequencer.step, 7);
+
+        // a whole period to step 0, and another to step 1
+        for _ in 0..8191 {
+            sound.tick(1);
+        }
+        assert_eq!(sound.frame_sequencer.step, 7);
+        sound.tick(1);
+        assert_eq!(sound.frame_sequencer.step, 0);
+
+        for _ in 0..8192 {
+            sound.tick(1);
+        }
+        assert_eq!(sound.frame_sequencer.step, 1);
+    }
+
+    // triggers square 2 with a length of 2 and returns the step it stopped at
+    fn square_2_length_2_stop_step(trigger_after_step: u8) -> u8 {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+        tick_to_step(&mut sound, trigger_after_step);
+
+        sound.write_byte(0xFF17, 0xF0);
+        sound.write_byte(0xFF16, 62);
+        sound.write_byte(0xFF19, 0b1100_0000);
+
+        while sound.square_2.is_running() {
+            sound.tick(1);
+        }
+        sound.frame_sequencer.step
+    }
+
+    #[test]
+    fn test_length_extra_clock_on_trigger() {
+        // the next step doesn't clock length, so the trigger does it once
+        assert_eq!(square_2_length_2_stop_step(0), 2);
+
+        // the next step clocks length, no extra clock
+        assert_eq!(square_2_length_2_stop_step(1), 4);
+    }
+
+    fn lengths(sound: &Sound) -> [u16; 4] {
+        [
+            sound.square_1.length.get_value(),
+            sound.square_2.length.get_value(),
+            sound.wave.read_length_value(),
+            sound.noise.read_length_value(),
+        ]
+    }
+
+    // on the DMG the length counters ignore the power
+    #[test]
+    fn test_length_written_while_powered_off() {
+        let mut sound = Sound::new();
+
+        // powering off keeps them
+        sound.write_byte(0xFF26, 0x80);
+        sound.write_byte(0xFF11, 63);
+        sound.write_byte(0xFF26, 0x00);
+        assert_eq!(sound.square_1.length.get_value(), 1);
+
+        sound.write_byte(0xFF11, 62);
+        sound.write_byte(0xFF16, 60);
+        sound.write_byte(0xFF1B, 250);
+        sound.write_byte(0xFF20, 56);
+        assert_eq!(lengths(&sound), [2, 4, 6, 8]);
+
+        // dacs on and trigger every channel
+        sound.write_byte(0xFF26, 0x80);
+        sound.write_byte(0xFF12, 0xF0);
+        sound.write_byte(0xFF17, 0xF0);
+        sound.write_byte(0xFF1A, 0x80);
+        sound.write_byte(0xFF21, 0xF0);
+        sound.write_byte(0xFF14, 0x80);
+        sound.write_byte(0xFF19, 0x80);
+        sound.write_byte(0xFF1E, 0x80);
+        sound.write_byte(0xFF23, 0x80);
+        assert_eq!(sound.read_byte(0xFF26), 0xFF);
+        assert_eq!(lengths(&sound), [2, 4, 6, 8]);
+
+        // enable the lengths when the next step clocks them, so there's no extra clock
+        tick_to_step(&mut sound, 1);
+        sound.write_byte(0xFF14, 0x40);
+        sound.write_byte(0xFF19, 0x40);
+        sound.write_byte(0xFF1E, 0x40);
+        sound.write_byte(0xFF23, 0x40);
+        assert_eq!(lengths(&sound), [2, 4, 6, 8]);
+
+        // the lengths are clocked 8192 cycles from now, then every 16384
+        let mut stopped_at = [0; 4];
+        let mut cycles = 0;
+        while sound.read_byte(0xFF26) & 0x0F != 0 {
+            sound.tick(4);
+            cycles += 4;
+            for (channel, stop) in stopped_at.iter_mut().enumerate() {
+                if *stop == 0 && sound.read_byte(0xFF26) & (1 << channel) == 0 {
+                    *stop = cycles;
+                }
+            }
+        }
+        assert_eq!(
+            stopped_at,
+            [
+                8192 + 16384,
+                8192 + 3 * 16384,
+                8192 + 5 * 16384,
+                8192 + 7 * 16384
+            ]
+        );
+    }
+
+    // the write only bits read as 1
+    #[test]
+    fn test_read_masks() {
+        let zeroed = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, // square 1
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF, // square 2
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // wave
+            0xFF, 0xFF, 0x00, 0x00, 0xBF, // noise
+            0x00, 0x00, // control
+        ];
+        let alternating = [
+            0xAA, 0xBF, 0xAA, 0xFF, 0xBF, // square 1
+            0xFF, 0xBF, 0xAA, 0xFF, 0xBF, // square 2
+            0xFF, 0xFF, 0xBF, 0xFF, 0xBF, // wave
+            0xFF, 0xFF, 0xAA, 0xAA, 0xBF, // noise
+            0xAA, 0xAA, // control
+        ];
+
+        for &(value, expected) in [(0x00, zeroed), (0xFF, [0xFF; 22]), (0xAA, alternating)].iter() {
+            let mut sound = Sound::new();
+            sound.write_byte(0xFF26, 0x80);
+
+            for addr in 0xFF10..0xFF26 {
+                sound.write_byte(addr, value);
+            }
+            for (addr, byte) in (0xFF10..0xFF26).zip(expected.iter()) {
+                assert_eq!(sound.read_byte(addr), *byte, "register {:X}", addr);
+            }
+        }
+
+        // the unused registers
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+        for &addr in [0xFF15, 0xFF1F, 0xFF27, 0xFF2F].iter() {
+            sound.write_byte(addr, 0x00);
+            assert_eq!(sound.read_byte(addr), 0xFF, "register {:X}", addr);
+        }
+    }
+
+    // power off clears NR10-NR51, but not the length counters
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+
+        for addr in 0xFF10..0xFF26 {
+            sound.write_byte(addr, 0xFF);
+        }
+        sound.write_byte(0xFF11, 0xCA);
+        sound.write_byte(0xFF16, 0x05);
+        sound.write_byte(0xFF1B, 0x20);
+        sound.write_byte(0xFF20, 0x30);
+
+        sound.write_byte(0xFF26, 0x00);
+
+        // every register with its writable bits zeroed
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, // square 1
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF, // square 2
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // wave
+            0xFF, 0xFF, 0x00, 0x00, 0xBF, // noise
+            0x00, 0x00, // control
+        ];
+        for (addr, value) in (0xFF10..0xFF26).zip(expected.iter()) {
+            assert_eq!(sound.read_byte(addr), *value, "register {:X}", addr);
+        }
+        assert_eq!(sound.read_byte(0xFF26), 0x70);
+
+        assert_eq!(sound.square_1.length.get_value(), 64 - 10);
+        assert_eq!(sound.square_2.length.get_value(), 64 - 5);
+        assert_eq!(sound.wave.read_length_value(), 256 - 0x20);
+        assert_eq!(sound.noise.read_length_value(), 64 - 0x30);
+    }
+
+    // powered off, the registers read as zeroed even when written or left with a state
+    #[test]
+    fn test_power_off_reads() {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+        sound.write_byte(0xFF30, 0x12);
+        sound.write_byte(0xFF26, 0x00);
+
+        // the length writes go through, the rest is ignored
+        for addr in 0xFF10..0xFF26 {
+            sound.write_byte(addr, 0xFF);
+        }
+        // a duty left behind by the channel
+        sound.square_2.write_register_1(0xC0);
+
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, // square 1
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF, // square 2
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // wave
+            0xFF, 0xFF, 0x00, 0x00, 0xBF, // noise
+            0x00, 0x00, // control
+        ];
+        for (addr, value) in (0xFF10..0xFF26).zip(expected.iter()) {
+            assert_eq!(sound.read_byte(addr), *value, "register {:X}", addr);
+        }
+        assert_eq!(sound.read_byte(0xFF26), 0x70);
+        assert_eq!(sound.square_1.length.get_value(), 64 - 63);
+        assert_eq!(sound.square_2.length.get_value(), 64 - 63);
+        assert_eq!(sound.wave.read_length_value(), 256 - 255);
+        assert_eq!(sound.noise.read_length_value(), 64 - 63);
+        assert_eq!(sound.read_byte(0xFF30), 0x12);
+    }
+
+    // a channel with its dac off never reads as running, even right after a trigger
+    #[test]
+    fn test_nr52_dac_off() {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+
+        // initial volume 0 and decreasing envelope: dac off
+        sound.write_byte(0xFF12, 0x00);
+        sound.write_byte(0xFF14, 0x80);
+        sound.write_byte(0xFF17, 0x00);
+        sound.write_byte(0xFF19, 0x80);
+        sound.write_byte(0xFF21, 0x00);
+        sound.write_byte(0xFF23, 0x80);
+        sound.write_byte(0xFF1A, 0x00);
+        sound.write_byte(0xFF1E, 0x80);
+        assert_eq!(sound.read_byte(0xFF26) & 0x0F, 0);
+
+        // an increasing envelope powers the dac, even from volume 0
+        sound.write_byte(0xFF12, 0x08);
+        sound.write_byte(0xFF14, 0x80);
+        sound.write_byte(0xFF21, 0xF0);
+        sound.write_byte(0xFF23, 0x80);
+        assert_eq!(sound.read_byte(0xFF26) & 0x0F, 0b1001);
+
+        // turning the dac off stops a running channel
+        sound.write_byte(0xFF12, 0x00);
+        assert_eq!(sound.read_byte(0xFF26) & 0x0F, 0b1000);
+    }
+
+    #[test]
+    fn test_wave_ram_blocked_while_playing() {
+        let mut sound = Sound::new();
+        sound.write_byte(0xFF26, 0x80);
+
+        sound.write_byte(0xFF30, 0x12);
+        assert_eq!(sound.read_byte(0xFF30), 0x12);
+
+        // dac on, then trigger
+        sound.write_byte(0xFF1A, 0x80);
+        sound.write_byte(0xFF1E, 0x80);
+        assert!(sound.wave.is_running());
+
+        // the channel isn't fetching a sample right now
+        assert_eq!(sound.read_byte(0xFF30), 0xFF);
+        sound.write_byte(0xFF31, 0x34);
+
+        // turning the dac off stops the channel, and the write didn't land
+        sound.write_byte(0xFF1A, 0x00);
+        assert!(!sound.wave.is_running());
+        assert_eq!(sound.read_byte(0xFF30), 0x12);
+        assert_ne!(sound.read_byte(0xFF31), 0x34);
+    }
+}
@@ -1,6 +1,8 @@
 use sound::envelope::Envelope;
 use sound::length::{Length, MaxLength};
 use sound::{Sample, Timer, Voltage};
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
 
 pub struct NoiseChannel {
     length: Length,
@@ -41,14 +43,10 @@ impl NoiseChannel {
         // put into bit 6 AFTER the shift, resulting in a 7-bit LFSR.
         let xor = (self.lfsr & 1) ^ ((self.lfsr & 0b10) >> 1);
 
-        self.lfsr >>= 1;
+        self.lfsr = (self.lfsr >> 1) | (xor << 14);
 
-        if xor != 0 {
-            self.lfsr |= 0b100_0000_0000;
-
-            if self.lfsr_width_mode != 0 {
-                self.lfsr |= 0b100_0000
-            }
+        if self.lfsr_width_mode != 0 {
+            self.lfsr = (self.lfsr & !0b100_0000) | (xor << 6);
         }
 
         self.timer.period = ((self.get_divisor() as u16) << (self.clock_shift as u16)) as usize;
@@ -110,6 +108,8 @@ impl NoiseChannel {
         }
     }
 
+    // the timer period is the divisor shifted by the clock shift.
+    // Code 0 stands for half of the base divisor of 16
     fn get_divisor(&self) -> u8 {
         match self.divisor_code {
             1 => 16,
@@ -123,6 +123,10 @@ impl NoiseChannel {
         }
     }
 
+    pub fn get_lfsr(&self) -> u16 {
+        self.lfsr
+    }
+
     pub fn reset(&mut self) {
         self.running = false;
         self.timer = Timer::new(0);
@@ -163,6 +167,10 @@ impl NoiseChannel {
         self.length.set_value(byte);
     }
 
+    pub fn read_length_value(&self) -> u16 {
+        self.length.get_value()
+    }
+
     pub fn write_register_4(&mut self, byte: u8) {
         let trigger = byte & 0b1000_0000 != 0;
 
@@ -186,6 +194,31 @@ impl NoiseChannel {
     }
 }
 
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        self.timer.save_state(state);
+        state.write_u16(self.lfsr);
+        state.write_u8(self.clock_shift);
+        state.write_u8(self.lfsr_width_mode);
+        state.write_u8(self.divisor_code);
+        state.write_bool(self.running);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.clock_shift = state.read_u8()? & 0x0F;
+        self.lfsr_width_mode = state.read_u8()? & 1;
+        self.divisor_code = state.read_u8()? & 0b111;
+        self.running = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for NoiseChannel {
     fn default() -> Self {
         NoiseChannel::new()
@@ -227,4 +260,94 @@ mod tests {
 
         assert_eq!(channel.read_register_3(), 0b1100_0001);
     }
+
+    // the lfsr values of a triggered channel, after each of the first clocks of its timer
+    fn lfsr_values(width_mode: u8, clocks: usize) -> Vec<u16> {
+        let mut channel: NoiseChannel = NoiseChannel::new();
+        let mut envelope = Envelope::new();
+        envelope.write(0xF0);
+        channel.set_envelope(envelope);
+        channel.write_register_3(width_mode << 3);
+        channel.write_register_4(0b1000_0000);
+
+        let mut values = Vec::new();
+        for _ in 0..clocks {
+            // divisor code 0 is a period of 8
+            for _ in 0..8 {
+                channel.tick();
+            }
+            values.push(channel.get_lfsr());
+        }
+        values
+    }
+
+    // the first period the values repeat with
+    fn period(values: &[u16]) -> usize {
+        (1..values.len())
+            .find(|&p| (0..values.len() - p).all(|i| values[i] == values[i + p]))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lfsr_width_mode() {
+        // past the high bits, still full from the trigger
+        let short = lfsr_values(1, 2 * 127 + 15)[15..].to_vec();
+        assert_eq!(period(&short), 127);
+
+        let long = lfsr_values(0, 2 * 32767);
+        assert_eq!(period(&long), 32767);
+
+        // the xor goes into bit 14 and, in 7 bit mode, into bit 6 as well
+        assert_eq!(lfsr_values(0, 1)[0], 0x3FFF);
+        assert_eq!(lfsr_values(1, 1)[0], 0x3FBF);
+    }
+
+    // a trigger restarts the lfsr from all 1s and reloads the timer, so the noise
+    // comes out the same every time, wherever the last one left it
+    #[test]
+    fn test_trigger_resets_lfsr() {
+        let mut channel: NoiseChannel = NoiseChannel::new();
+        let mut envelope = Envelope::new();
+        envelope.write(0xF0);
+        channel.set_envelope(envelope);
+        // period 32 << 1
+        channel.write_register_3(0x12);
+
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            channel.write_register_4(0b1000_0000);
+            assert_eq!(channel.get_lfsr(), 0x7FFF);
+
+            // stopping halfway through a timer period
+            let run: Vec<u8> = (0..64 * 100 + 37)
+                .map(|_| {
+                    channel.tick();
+                    channel.sample().0
+                })
+                .collect();
+            assert!(run.iter().any(|sample| *sample != 0));
+            runs.push(run);
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    // a corrupted state can't give the timer a shift past its width
+    #[test]
+    fn test_load_state_masks_registers() {
+        let mut channel: NoiseChannel = NoiseChannel::new();
+        channel.clock_shift = 0xFF;
+        channel.lfsr_width_mode = 0xFF;
+        channel.divisor_code = 0xFF;
+        let mut state = StateWriter::new();
+        channel.save_state(&mut state);
+
+        let mut channel: NoiseChannel = NoiseChannel::new();
+        channel
+            .load_state(&mut StateReader::new(&state.into_bytes()))
+            .unwrap();
+        assert_eq!(channel.read_register_3(), 0xFF);
+
+        channel.trigger();
+        channel.tick();
+    }
 }
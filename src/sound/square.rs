@@ -3,6 +3,8 @@ use sound::envelope::Envelope;
 use sound::length::MaxLength;
 use sound::sweep::Sweep;
 use sound::{Length, Sample, Timer, Voltage, DUTY_PATTERNS_LENGTH};
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
 
 pub struct SquareChannel {
     sweep: Sweep,
@@ -269,6 +271,31 @@ impl SquareChannel {
     }
 }
 
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        self.duty_timer.save_state(state);
+        state.write_usize(self.duty_index);
+        state.write_u8(self.duty);
+        state.write_u16(self.frequency);
+        state.write_bool(self.running);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.duty_timer.load_state(state)?;
+        self.duty_index = state.read_usize()? % DUTY_PATTERNS_LENGTH as usize;
+        self.duty = state.read_u8()? & 0b11;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.running = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for SquareChannel {
     fn default() -> Self {
         SquareChannel::new()
@@ -309,4 +336,34 @@ mod tests {
 
         assert_eq!(channel.read_register_4(), 0xFF);
     }
+
+    #[test]
+    fn test_sweep_overflow() {
+        let mut channel: SquareChannel = SquareChannel::new();
+        channel.envelope.write(0xF0);
+
+        // period 1, shift 1, adding: 0x400 -> 0x600 -> 0x900
+        channel.write_sweep(0b0001_0001);
+        channel.frequency = 0x400;
+        channel.trigger();
+        assert!(channel.is_running());
+
+        // the written back frequency fits, the second calculation overflows
+        channel.tick_sweep();
+        assert_eq!(channel.frequency, 0x600);
+        assert!(!channel.is_running());
+
+        // the check on trigger alone is enough to disable it
+        channel.frequency = 0x7F0;
+        channel.trigger();
+        assert!(!channel.is_running());
+
+        // leaving negate mode after a negated calculation disables it too
+        channel.write_sweep(0b0001_1001);
+        channel.frequency = 0x400;
+        channel.trigger();
+        assert!(channel.is_running());
+        channel.write_sweep(0b0001_0001);
+        assert!(!channel.is_running());
+    }
 }
@@ -1,4 +1,6 @@
 use sound::TimerDefaultPeriod;
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
 use std::ops::{Add, Sub};
 
 pub struct Sweep {
@@ -94,6 +96,27 @@ impl Sweep {
     }
 }
 
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.shift);
+        state.write_bool(self.negate);
+        state.write_bool(self.negate_mode_used);
+        self.timer.save_state(state);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.shift = state.read_u8()? & 0b111;
+        self.negate = state.read_bool()?;
+        self.negate_mode_used = state.read_bool()?;
+        self.timer.load_state(state)?;
+        self.shadow_frequency = state.read_u16()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for Sweep {
     fn default() -> Self {
         Sweep::new()
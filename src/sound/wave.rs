@@ -1,5 +1,7 @@
 use sound::length::{Length, MaxLength};
 use sound::{Sample, Timer, Voltage};
+use state::{SaveState, StateReader, StateWriter};
+use std::io;
 
 const WAVE_RAM_SAMPLES: u8 = 32;
 
@@ -58,13 +60,18 @@ impl From<u8> for Volume {
 }
 
 impl Volume {
+    // how much the 4 bit samples get shifted right. Muting shifts every bit out
+    pub fn shift(self) -> u8 {
+        match self {
+            Volume::Silent => 4,
+            Volume::Max => 0,
+            Volume::Half => 1,
+            Volume::Quarter => 2,
+        }
+    }
+
     fn apply_to(self, sample: Sample) -> Sample {
-        Sample(match self {
-            Volume::Silent => 0,
-            Volume::Max => sample.0,
-            Volume::Half => sample.0 / 2,
-            Volume::Quarter => sample.0 / 4,
-        })
+        Sample(sample.0 >> self.shift())
     }
 }
 
@@ -272,6 +279,10 @@ impl WaveChannel {
         0b1001_1111 | (self.volume as u8) << 5
     }
 
+    pub fn volume_shift(&self) -> u8 {
+        self.volume.shift()
+    }
+
     pub fn write_register_4(&mut self, byte: u8) {
         // set frequency most significative bits
         self.set_frequency_msb(byte);
@@ -298,6 +309,35 @@ impl WaveChannel {
     }
 }
 
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.dac_power);
+        state.write_u16(self.frequency);
+        self.length.save_state(state);
+        self.timer.save_state(state);
+        state.write_bool(self.wave_ram_accessible);
+        state.write_u8(self.buffer);
+        state.write_u8(self.position);
+        state.write_bytes(&self.samples);
+        state.write_u8(self.volume as u8);
+        state.write_bool(self.running);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.dac_power = state.read_bool()?;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.length.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.wave_ram_accessible = state.read_bool()?;
+        self.buffer = state.read_u8()?;
+        self.position = state.read_u8()? % WAVE_RAM_SAMPLES;
+        state.read_bytes(&mut self.samples)?;
+        self.volume = Volume::from_u8(state.read_u8()?);
+        self.running = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for WaveChannel {
     fn default() -> Self {
         WaveChannel::new()
@@ -351,6 +391,51 @@ mod tests {
         assert_eq!(channel.read_volume(), 0b1011_1111);
     }
 
+    #[test]
+    fn test_wave_volume_output() {
+        let mut channel: WaveChannel = WaveChannel::new();
+        channel.write_register_0(0b1000_0000);
+        channel.write_register_4(0b1000_0000);
+
+        // the first sample played is 12
+        channel.buffer = 0xC0;
+
+        // volume code, shift, sample after the shift
+        for &(code, shift, sample) in [(0, 4, 0), (1, 0, 12), (2, 1, 6), (3, 2, 3)].iter() {
+            channel.write_volume(code << 5);
+            assert_eq!(channel.volume_shift(), shift);
+            assert_eq!(channel.output().0, Sample(sample).to_voltage().0);
+        }
+
+        // muted, it's the same as a 0 sample
+        channel.write_volume(0);
+        assert_eq!(channel.output().0, 15);
+    }
+
+    #[test]
+    fn test_trigger_restarts_position() {
+        let mut channel: WaveChannel = WaveChannel::new();
+        channel.write_register_0(0b1000_0000);
+        channel.set_frequency_lsb(0xF0);
+        channel.write_register_4(0b1000_0111);
+
+        // the first sample is read 38 ticks after the trigger, then one every 32
+        for _ in 0..112 {
+            channel.tick();
+        }
+        assert_eq!(channel.position, 3);
+
+        // retrigger away from a wave ram read, so that the ram isn't corrupted
+        channel.write_register_4(0b1000_0111);
+        assert_eq!(channel.position, 0);
+        assert_eq!(channel.timer.curr, 38);
+
+        for _ in 0..38 {
+            channel.tick();
+        }
+        assert_eq!(channel.position, 1);
+    }
+
     #[test]
     fn test_wave_register_4() {
         let mut channel: WaveChannel = WaveChannel::new();
@@ -0,0 +1,194 @@
+/// Save states
+///
+/// Every component writes its fields to a flat byte buffer, in a fixed order,
+/// and reads them back in the same order. Numbers are little endian.
+use std::io;
+
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // usizes are always stored on 64 bits, so states move between platforms
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    // bytes of a known length, eg: a memory array
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // bytes of a variable length, prefixed by it
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    // the next length bytes, failing if the state ends before
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.position < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "save state is truncated",
+            ));
+        }
+
+        let taken = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.read_bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_usize(&mut self) -> io::Result<usize> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    // fills bytes entirely
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        let length = bytes.len();
+        bytes.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.read_usize()?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    // true if the whole state has been read
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+// the error for a state holding a value the component can't have
+pub fn invalid_state(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_usize(42);
+        writer.write_f32(-1.5);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_vec(&[4, 5]);
+
+        let data = writer.into_bytes();
+        let mut reader = StateReader::new(&data);
+
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789A_BCDE);
+        assert_eq!(reader.read_usize().unwrap(), 42);
+        assert_eq!(reader.read_f32().unwrap(), -1.5);
+        let mut bytes = [0; 3];
+        reader.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.read_vec().unwrap(), vec![4, 5]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut reader = StateReader::new(&[1]);
+
+        assert_eq!(
+            reader.read_u16().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
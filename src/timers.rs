@@ -1,3 +1,7 @@
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use std::io;
+
 #[derive(Clone, Copy)]
 #[repr(u8)]
 enum TimerSpeed {
@@ -34,87 +38,100 @@ impl From<u8> for TimerSpeed {
 }
 
 pub struct Timers {
-    main: u8,
-    sub: u8,
-    div: u8,
+    // the internal 16 bit counter, going up every cycle. DIV is its upper byte
+    system_counter: u16,
 
     speed: TimerSpeed,
     running: bool, // true if enabled
 
     // registers
-    divider: u8,
     counter: u8,
     modulo: u8,
+
+    // the counter overflowed: it reads 0 for a machine cycle, then gets reloaded with the modulo
+    reload_pending: bool,
 }
 
 impl Timers {
     pub fn new() -> Self {
         Timers {
-            main: 0,
-            sub: 0,
-            div: 0,
+            system_counter: 0,
 
-            divider: 0,
             counter: 0,
             modulo: 0,
             speed: TimerSpeed::Speed0,
             running: false,
+            reload_pending: false,
         }
     }
 
-    // send the timers forward; returns true if timer interrupt should be triggered
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        let m = cycles / 4;
-        self.sub = self.sub.wrapping_add(m);
+    // the counter goes up when this signal falls from high to low, it's the bit
+    // of the system counter selected by the speed, masked by the enable
+    fn counter_signal(&self) -> bool {
+        let bit = match self.speed {
+            TimerSpeed::Speed0 => 9,
+            TimerSpeed::Speed1 => 3,
+            TimerSpeed::Speed2 => 5,
+            TimerSpeed::Speed3 => 7,
+        };
 
-        if self.sub >= 4 {
-            self.main = self.main.wrapping_add(1);
-            self.sub = self.sub.wrapping_sub(4);
+        self.running && self.system_counter & (1 << bit) != 0
+    }
 
-            self.div = self.div.wrapping_add(1);
-            if self.div == 16 {
-                self.divider = self.divider.wrapping_add(1);
-                self.div = 0;
-            }
-        }
+    fn increment_counter(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
 
-        // check if enabled
-        if !self.running {
-            return false;
+        // overflow, the counter stays at 0 until the next tick
+        if self.counter == 0 {
+            self.reload_pending = true;
         }
+    }
 
-        let threshold = match self.speed {
-            TimerSpeed::Speed0 => 64,
-            TimerSpeed::Speed1 => 1,
-            TimerSpeed::Speed2 => 4,
-            TimerSpeed::Speed3 => 16,
-        };
-
-        // no need to send timer forward
-        if self.main < threshold {
-            return false;
+    // send the timers forward; returns true if timer interrupt should be triggered
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        // the counter overflowed on the previous tick, reload and request the interrupt now.
+        // Memory accesses happen at the end of an instruction, at least a machine cycle
+        // after the previous tick, so any tick is late enough
+        let mut raise_interrupt = false;
+        if self.reload_pending {
+            self.reload_pending = false;
+            self.counter = self.modulo;
+            raise_interrupt = true;
         }
 
-        self.main = 0;
-        self.counter = self.counter.wrapping_add(1);
+        for _ in 0..cycles / 4 {
+            let signal = self.counter_signal();
+            self.system_counter = self.system_counter.wrapping_add(4);
 
-        // overflow
-        if self.counter == 0 {
-            self.counter = self.modulo;
-            return true;
+            if signal && !self.counter_signal() {
+                self.increment_counter();
+            }
         }
 
-        false
+        raise_interrupt
+    }
+
+    // sets the whole system counter, without edges: for starting past the boot rom
+    pub fn set_system_counter(&mut self, value: u16) {
+        self.system_counter = value;
     }
 
     // when writing to 0xFF04
     pub fn change_divider(&mut self, _byte: u8) {
-        // always resets
-        self.divider = 0;
+        // always resets, the whole system counter. If the selected bit was high,
+        // that's a falling edge
+        let signal = self.counter_signal();
+        self.system_counter = 0;
+
+        if signal {
+            self.increment_counter();
+        }
     }
 
     // when writing to 0xFF05
     pub fn change_counter(&mut self, byte: u8) {
+        // writing during the reload delay cancels both reload and interrupt
+        self.reload_pending = false;
         self.counter = byte;
     }
 
@@ -125,13 +142,20 @@ impl Timers {
 
     // when writing to 0xFF07
     pub fn change_control(&mut self, byte: u8) {
+        let signal = self.counter_signal();
+
         self.speed = TimerSpeed::from_u8(byte & 0b0000_0011);
         self.running = ((byte & 0b0000_0100) >> 2) == 1;
+
+        // disabling the timer or selecting a low bit can make the signal fall
+        if signal && !self.counter_signal() {
+            self.increment_counter();
+        }
     }
 
     // when reading from 0xFF04
     pub fn read_divider(&self) -> u8 {
-        self.divider
+        (self.system_counter >> 8) as u8
     }
 
     // when writing to 0xFF05
@@ -150,6 +174,27 @@ impl Timers {
     }
 }
 
+impl SaveState for Timers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.system_counter);
+        state.write_u8(self.speed as u8);
+        state.write_bool(self.running);
+        state.write_u8(self.counter);
+        state.write_u8(self.modulo);
+        state.write_bool(self.reload_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.system_counter = state.read_u16()?;
+        self.speed = TimerSpeed::from(state.read_u8()? & 0b11);
+        self.running = state.read_bool()?;
+        self.counter = state.read_u8()?;
+        self.modulo = state.read_u8()?;
+        self.reload_pending = state.read_bool()?;
+        Ok(())
+    }
+}
+
 impl Default for Timers {
     fn default() -> Self {
         Timers::new()
@@ -164,7 +209,7 @@ mod tests {
     fn test_timers_initialization() {
         let timers = Timers::new();
 
-        assert_eq!(timers.divider, 0);
+        assert_eq!(timers.read_divider(), 0);
         assert_eq!(timers.counter, 0);
         assert_eq!(timers.modulo, 0);
         assert_eq!(timers.speed as u8, 0);
@@ -210,4 +255,81 @@ mod tests {
 
         assert_eq!(timers.read_control(), 0b0000_0111);
     }
+
+    // runs the timer at its fastest speed until the counter overflows
+    fn tick_until_overflow(timers: &mut Timers) {
+        timers.change_control(0b0000_0101);
+        timers.change_counter(0xFF);
+
+        while timers.read_counter() == 0xFF {
+            assert!(!timers.tick(4));
+        }
+    }
+
+    #[test]
+    fn test_delayed_reload() {
+        let mut timers = Timers::new();
+        timers.change_modulo(0x42);
+
+        tick_until_overflow(&mut timers);
+
+        // reads 0 right after overflowing
+        assert_eq!(timers.read_counter(), 0);
+
+        // reloaded on the next machine cycle, along with the interrupt
+        assert!(timers.tick(4));
+        assert_eq!(timers.read_counter(), 0x42);
+    }
+
+    #[test]
+    fn test_write_cancels_reload() {
+        let mut timers = Timers::new();
+        timers.change_modulo(0x42);
+
+        tick_until_overflow(&mut timers);
+
+        timers.change_counter(0x10);
+
+        assert!(!timers.tick(4));
+        assert_eq!(timers.read_counter(), 0x10);
+    }
+
+    #[test]
+    fn test_divider_goes_up() {
+        let mut timers = Timers::new();
+
+        for _ in 0..64 {
+            timers.tick(4);
+        }
+
+        assert_eq!(timers.read_divider(), 1);
+    }
+
+    #[test]
+    fn test_divider_reset_falling_edge() {
+        let mut timers = Timers::new();
+        timers.change_control(0b0000_0101);
+
+        // bit 3 of the system counter is high
+        timers.tick(8);
+        assert_eq!(timers.read_counter(), 0);
+
+        timers.change_divider(0);
+        assert_eq!(timers.read_counter(), 1);
+
+        // with the bit low, nothing happens
+        timers.change_divider(0);
+        assert_eq!(timers.read_counter(), 1);
+    }
+
+    #[test]
+    fn test_control_change_falling_edge() {
+        let mut timers = Timers::new();
+        timers.change_control(0b0000_0101);
+        timers.tick(8);
+
+        // disabling the timer drops the signal
+        timers.change_control(0b0000_0001);
+        assert_eq!(timers.read_counter(), 1);
+    }
 }
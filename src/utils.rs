@@ -1,7 +1,10 @@
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
 use std::io::Read;
 use std::mem;
 
+#[cfg(feature = "std")]
 pub fn load_boot_rom() -> [u8; 0x0100] {
     // TODO: make a generic function for loading roms
     let mut boot_rom: [u8; 0x0100] = [0; 0x0100];
@@ -65,6 +68,8 @@ pub fn add_words(a: u16, b: u16, c: u16) -> (u16, bool, bool) {
     (res as u16, carry, halfcarry)
 }
 
+// adds the signed byte b to a. Like the cpu does for ADD SP,r8 and LD HL,SP+r8,
+// carry and half carry come from bits 7 and 3 of the low byte, as if it was an unsigned add
 pub fn add_word_with_signed(a: u16, b: u16, _: u16) -> (u16, bool, bool) {
     let a = a as i32;
     let b = b as u8 as i8 as i32;
@@ -2,68 +2,85 @@ extern crate gameman;
 
 use gameman::emu::Emulator;
 
+// the results can be read without a frontend, a frame at a time
+#[test]
+fn serial_output() {
+    let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
+
+    for _ in 0..600 {
+        emulator.run_cycles(70224);
+        if emulator.serial_output().contains("Passed") {
+            break;
+        }
+    }
+
+    let output = emulator.serial_output();
+    assert!(output.starts_with("01-special"), "{}", output);
+    assert!(output.contains("Passed"), "{}", output);
+}
+
 #[test]
 fn cpu_instrs_01() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/01-special.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_02() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/02-interrupts.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/02-interrupts.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_03() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/03-op sp,hl.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/03-op sp,hl.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_04() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/04-op r,imm.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/04-op r,imm.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_05() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/05-op rp.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/05-op rp.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_06() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/06-ld r,r.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/06-ld r,r.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_07() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/07-jr,jp,call,ret,rst.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/07-jr,jp,call,ret,rst.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_08() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/08-misc instrs.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/08-misc instrs.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_09() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/09-op r,r.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/09-op r,r.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_10() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/10-bit ops.gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/10-bit ops.gb", false);
     assert!(emulator.passes_test_rom());
 }
 
 #[test]
 fn cpu_instrs_11() {
-    let mut emulator = Emulator::new("tests/cpu_instrs/11-op a,(hl).gb");
+    let mut emulator = Emulator::new("tests/cpu_instrs/11-op a,(hl).gb", false);
     assert!(emulator.passes_test_rom());
 }
@@ -0,0 +1,65 @@
+extern crate gameman;
+
+use gameman::cartridge::load_rom;
+use gameman::cpu::CPU;
+use gameman::gpu::GPU;
+use gameman::mem::MMU;
+
+use std::env;
+use std::fs;
+
+const ROM: &str = "tests/cpu_instrs/01-special.gb";
+const REFERENCE: &str = "tests/golden/01-special.log";
+const INSTRUCTIONS: usize = 2000;
+
+// Runs the rom for INSTRUCTIONS instructions, collecting the cpu state before each one
+// in the Gameboy Doctor format. Only the cpu (and the timers through the mmu) is stepped,
+// so that the trace doesn't depend on the gpu or the sound timings.
+fn run_trace() -> Vec<String> {
+    let mut cpu = CPU::new(MMU::new(GPU::new(), load_rom(ROM).unwrap()));
+
+    cpu.set_registry_value("AF", 0x01B0);
+    cpu.set_registry_value("BC", 0x0013);
+    cpu.set_registry_value("DE", 0x00D8);
+    cpu.set_registry_value("HL", 0x014D);
+
+    let mut trace = Vec::with_capacity(INSTRUCTIONS);
+    for _ in 0..INSTRUCTIONS {
+        trace.push(cpu.trace_state());
+        cpu.step();
+    }
+    trace
+}
+
+// The reference was generated from a known-good run (all of blargg's cpu_instrs passing)
+// with the same runner:
+//
+//     GOLDEN_TRACE_BLESS=1 cargo test --test test_golden_trace
+//
+// Regenerate it only after making sure the cpu change is actually a fix.
+#[test]
+fn golden_trace() {
+    let trace = run_trace();
+
+    if env::var("GOLDEN_TRACE_BLESS").is_ok() {
+        fs::write(REFERENCE, trace.join("\n") + "\n").expect("couldnt write the reference trace");
+        return;
+    }
+
+    let reference = fs::read_to_string(REFERENCE).expect("couldnt read the reference trace");
+    let expected: Vec<&str> = reference.lines().collect();
+
+    assert_eq!(
+        expected.len(),
+        INSTRUCTIONS,
+        "reference trace has unexpected length"
+    );
+
+    for (index, (expected, actual)) in expected.iter().zip(trace.iter()).enumerate() {
+        assert_eq!(
+            expected, actual,
+            "trace diverged at instruction {}\nexpected: {}\n  actual: {}",
+            index, expected, actual
+        );
+    }
+}
@@ -4,6 +4,6 @@ use gameman::emu::Emulator;
 
 #[test]
 fn cpu_instrs_timing() {
-    let mut emulator = Emulator::new("tests/instr_timing.gb");
+    let mut emulator = Emulator::new("tests/instr_timing.gb", false);
     assert!(emulator.passes_test_rom());
 }
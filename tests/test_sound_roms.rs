@@ -4,6 +4,6 @@ extern crate gameman;
 fn sound_registers() {
     // this test rom is not writing to the link cable...
 
-    // let mut emulator = Emulator::new("tests/sound/dmg_sound.gb");
+    // let mut emulator = Emulator::new("tests/sound/dmg_sound.gb", false);
     // assert!(emulator.passes_test_rom());
 }